@@ -0,0 +1,173 @@
+//! Compares the throughput of the synchronization strategies used in the homework.
+//!
+//! Run with `cargo bench --bench sync_strategies`. The workloads are deterministic, so the results
+//! can be compared across runs.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Mutex, RwLock};
+use std::thread::scope;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use cs431_homework::hello_server::{Cache, ShardedCache};
+use cs431_homework::{ConcurrentSet, FineGrainedListSet};
+
+const THREADS: [usize; 3] = [1, 4, 16];
+const OPS_PER_THREAD: usize = 1 << 12;
+const NUM_KEYS: usize = 1 << 8;
+
+/// Common interface of the benchmarked caches.
+trait BenchCache: Default + Sync {
+    fn get_or_insert_with<F: FnOnce(usize) -> usize>(&self, key: usize, f: F) -> usize;
+}
+
+impl BenchCache for Cache<usize, usize> {
+    fn get_or_insert_with<F: FnOnce(usize) -> usize>(&self, key: usize, f: F) -> usize {
+        Cache::get_or_insert_with(self, key, f)
+    }
+}
+
+impl BenchCache for ShardedCache<usize, usize> {
+    fn get_or_insert_with<F: FnOnce(usize) -> usize>(&self, key: usize, f: F) -> usize {
+        ShardedCache::get_or_insert_with(self, key, f)
+    }
+}
+
+/// Baseline cache that computes values while holding a single global lock.
+#[derive(Default)]
+struct SimpleCache {
+    inner: Mutex<HashMap<usize, usize>>,
+}
+
+impl BenchCache for SimpleCache {
+    fn get_or_insert_with<F: FnOnce(usize) -> usize>(&self, key: usize, f: F) -> usize {
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| f(key))
+    }
+}
+
+/// Baseline set protected by a single global lock.
+#[derive(Default)]
+struct CoarseGrainedListSet<T> {
+    inner: RwLock<BTreeSet<T>>,
+}
+
+impl<T: Ord> ConcurrentSet<T> for CoarseGrainedListSet<T> {
+    fn contains(&self, value: &T) -> bool {
+        self.inner.read().unwrap().contains(value)
+    }
+
+    fn insert(&self, value: T) -> bool {
+        self.inner.write().unwrap().insert(value)
+    }
+
+    fn remove(&self, value: &T) -> bool {
+        self.inner.write().unwrap().remove(value)
+    }
+}
+
+/// Deterministic pseudo-random key sequence for the thread `tid`.
+fn keys(tid: usize) -> impl Iterator<Item = usize> {
+    let mut state = (tid as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    (0..OPS_PER_THREAD).map(move |_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as usize
+    })
+}
+
+/// Runs `op` on `threads` threads `iters` times and returns the total elapsed time.
+fn measure<S: Sync>(
+    iters: u64,
+    threads: usize,
+    init: impl Fn() -> S,
+    op: impl Fn(&S, usize) + Sync,
+) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..iters {
+        let target = init();
+        let start = Instant::now();
+        scope(|s| {
+            for tid in 0..threads {
+                let (target, op) = (&target, &op);
+                let _ = s.spawn(move || op(target, tid));
+            }
+        });
+        total += start.elapsed();
+    }
+    total
+}
+
+fn cache_workload<C: BenchCache>(cache: &C, tid: usize) {
+    for key in keys(tid) {
+        let _ = cache.get_or_insert_with(key % NUM_KEYS, |k| k.wrapping_mul(k));
+    }
+}
+
+fn bench_cache<C: BenchCache>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group("get_or_insert_with");
+    for threads in THREADS {
+        let _ = group.throughput(Throughput::Elements((threads * OPS_PER_THREAD) as u64));
+        let _ = group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| measure(iters, threads, C::default, cache_workload::<C>))
+        });
+    }
+    group.finish();
+}
+
+/// 80% lookups, 10% insertions, and 10% removals.
+fn set_workload<S: ConcurrentSet<usize>>(set: &S, tid: usize) {
+    for key in keys(tid) {
+        let value = (key >> 8) % NUM_KEYS;
+        match key % 10 {
+            0 => {
+                let _ = set.insert(value);
+            }
+            1 => {
+                let _ = set.remove(&value);
+            }
+            _ => {
+                let _ = set.contains(&value);
+            }
+        }
+    }
+}
+
+/// Creates a set half-filled with the keys used by the workload.
+fn prefilled<S: Default + ConcurrentSet<usize>>() -> S {
+    let set = S::default();
+    for key in (0..NUM_KEYS).step_by(2) {
+        let _ = set.insert(key);
+    }
+    set
+}
+
+fn bench_set<S: Default + Sync + ConcurrentSet<usize>>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group("set_mixed");
+    for threads in THREADS {
+        let _ = group.throughput(Throughput::Elements((threads * OPS_PER_THREAD) as u64));
+        let _ = group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| measure(iters, threads, prefilled::<S>, set_workload::<S>))
+        });
+    }
+    group.finish();
+}
+
+fn caches(c: &mut Criterion) {
+    bench_cache::<Cache<usize, usize>>(c, "Cache");
+    bench_cache::<ShardedCache<usize, usize>>(c, "ShardedCache");
+    bench_cache::<SimpleCache>(c, "SimpleCache");
+}
+
+fn sets(c: &mut Criterion) {
+    bench_set::<FineGrainedListSet<usize>>(c, "FineGrainedListSet");
+    bench_set::<CoarseGrainedListSet<usize>>(c, "CoarseGrainedListSet");
+}
+
+criterion_group!(benches, caches, sets);
+criterion_main!(benches);
@@ -1,10 +1,503 @@
 //! Thread-safe key/value cache.
 
 use std::collections::hash_map::{Entry, HashMap};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::fmt;
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::hash::Hash;
-use std::sync::{Arc, Mutex, RwLock};
+#[cfg(feature = "snapshot")]
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard, Weak};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
-type Inner<T> = Arc<Mutex<Option<T>>>;
+use crossbeam_channel::{bounded, Receiver};
+
+#[cfg(feature = "snapshot")]
+use super::snapshot::{self, Persist};
+use super::ThreadPool;
+use crate::PoisonPolicy;
+
+type Inner<T> = Arc<CacheEntry<T>>;
+
+/// Panic message of a computation that looks up its own key, which would wait for or recurse into
+/// itself.
+const REENTRANT: &str = "the computation of a cache entry requested the same key from the cache";
+
+/// A slot of the cache.
+///
+/// The metadata are atomics, and the state is behind a `RwLock`, so that hits on the same entry
+/// only take its shared lock and run concurrently.
+#[derive(Debug)]
+struct CacheEntry<T> {
+    state: RwLock<EntryState<T>>,
+    /// Held by the threads that wait for `computed`. Since `Condvar` needs a `Mutex`, the state of
+    /// the computation is checked under this lock, and the computing thread takes it before
+    /// notifying, so that no wakeup is lost.
+    waiters: Mutex<()>,
+    /// Notified when a computation of the value finishes or is abandoned.
+    computed: Condvar,
+    hits: AtomicUsize,
+    /// Generation of the cache when the entry was created. Entries of older generations are
+    /// treated as absent.
+    generation: u64,
+    /// Whether the entry is exempt from eviction.
+    pinned: AtomicBool,
+}
+
+/// The value of a cache entry and the status of its computation.
+#[derive(Debug)]
+struct EntryState<T> {
+    /// `None` while the value is not computed. The slot is shared with the callers of
+    /// `get_or_insert_arc_cell`, so it has its own lock, which the cache only takes while holding
+    /// the lock of the state.
+    value: Arc<RwLock<Option<T>>>,
+    /// When `value` was computed.
+    computed_at: Option<Instant>,
+    /// How long `value` stays valid, if its computation decided it. Otherwise the TTL of the cache
+    /// applies.
+    ttl: Option<Duration>,
+    /// The thread computing the value, if any. The lock is not held during the computation, so
+    /// that the waiters can give up.
+    computing: Option<ThreadId>,
+    /// The threads computing the value under `StampedeMode::AllowDuplicate`, which do not mark
+    /// the entry as `computing`, as there may be several of them.
+    racing: Vec<ThreadId>,
+    /// Whether a computation panicked. This is tracked by hand because the lock is not held
+    /// during the computation, so it is not poisoned by the panic.
+    poisoned: bool,
+    /// Bumped whenever the value is stored or taken, so that a computation that ran without the
+    /// lock can tell whether another one stored a value meanwhile.
+    version: u64,
+}
+
+impl<T> EntryState<T> {
+    /// Returns a clone of the value if it is computed and not expired. `ttl` is the TTL of the
+    /// cache.
+    fn fresh(&self, ttl: Option<Duration>, poison: PoisonPolicy) -> Option<T>
+    where
+        T: Clone,
+    {
+        if self.is_expired(ttl) {
+            return None;
+        }
+        self.value(poison)
+    }
+
+    /// Returns a clone of the value, regardless of its expiry.
+    fn value(&self, poison: PoisonPolicy) -> Option<T>
+    where
+        T: Clone,
+    {
+        poison.unwrap(self.value.read()).clone()
+    }
+
+    /// Returns whether the value is computed, regardless of its expiry.
+    fn has_value(&self, poison: PoisonPolicy) -> bool {
+        poison.unwrap(self.value.read()).is_some()
+    }
+
+    /// Removes the value, and returns it.
+    fn take(&mut self, poison: PoisonPolicy) -> Option<T> {
+        self.version += 1;
+        poison.unwrap(self.value.write()).take()
+    }
+
+    /// Returns whether the value was computed at least its own TTL ago, or `ttl` ago if it has
+    /// none.
+    fn is_expired(&self, ttl: Option<Duration>) -> bool {
+        match (self.ttl.or(ttl), self.computed_at) {
+            (Some(ttl), Some(computed_at)) => computed_at.elapsed() >= ttl,
+            _ => false,
+        }
+    }
+
+    /// Handles a panic of an earlier computation according to `poison`.
+    fn check_poisoned(&mut self, poison: PoisonPolicy) {
+        if self.poisoned {
+            match poison {
+                PoisonPolicy::Propagate => panic!("the computation of the cache entry panicked"),
+                PoisonPolicy::Recover => self.poisoned = false,
+            }
+        }
+    }
+
+    /// Stores a newly computed value under the TTL of the cache, and returns the previous one.
+    fn set(&mut self, value: T, poison: PoisonPolicy) -> Option<T> {
+        self.computed_at = Some(Instant::now());
+        self.ttl = None;
+        self.version += 1;
+        poison.unwrap(self.value.write()).replace(value)
+    }
+}
+
+impl<T> CacheEntry<T> {
+    fn new(generation: u64) -> Self {
+        Self {
+            state: RwLock::new(EntryState {
+                value: Arc::default(),
+                computed_at: None,
+                ttl: None,
+                computing: None,
+                racing: Vec::new(),
+                poisoned: false,
+                version: 0,
+            }),
+            waiters: Mutex::new(()),
+            computed: Condvar::new(),
+            hits: AtomicUsize::new(0),
+            generation,
+            pinned: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns whether the value was computed at least `ttl` ago and is not being recomputed.
+    fn is_expired(&self, ttl: Option<Duration>, poison: PoisonPolicy) -> bool {
+        let state = poison.unwrap(self.state.read());
+        state.computing.is_none() && state.is_expired(ttl)
+    }
+
+    /// Returns whether the value is computed and not being recomputed.
+    fn is_computed(&self, poison: PoisonPolicy) -> bool {
+        let state = poison.unwrap(self.state.read());
+        state.computing.is_none() && state.has_value(poison)
+    }
+
+    /// Returns a copy of the value if it is computed, younger than `ttl`, and not being
+    /// recomputed, only taking the shared lock.
+    fn read_fresh(&self, ttl: Option<Duration>, poison: PoisonPolicy) -> Option<T>
+    where
+        T: Clone,
+    {
+        let state = poison.unwrap(self.state.read());
+        if state.computing.is_some() || state.poisoned {
+            return None;
+        }
+        state.fresh(ttl, poison)
+    }
+
+    /// Locks the state exclusively once no thread is computing the value. Returns `None` if that
+    /// does not happen within `timeout`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current thread is computing the value, as it would wait for itself.
+    fn lock_idle(
+        &self,
+        poison: PoisonPolicy,
+        timeout: Option<Duration>,
+    ) -> Option<RwLockWriteGuard<'_, EntryState<T>>> {
+        // Checked before taking the locks, so that the panic does not poison them. Only the current
+        // thread can mark itself as computing, so this cannot change while it waits.
+        let computing = poison.unwrap(self.state.read()).computing;
+        assert!(computing != Some(thread::current().id()), "{REENTRANT}");
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut waiters = poison.unwrap(self.waiters.lock());
+        let mut state = loop {
+            let state = poison.unwrap(self.state.write());
+            if state.computing.is_none() {
+                break state;
+            }
+            drop(state);
+            waiters = match deadline {
+                None => poison.unwrap(self.computed.wait(waiters)),
+                Some(deadline) => {
+                    let timeout = deadline.checked_duration_since(Instant::now())?;
+                    poison
+                        .unwrap(self.computed.wait_timeout(waiters, timeout))
+                        .0
+                }
+            };
+        };
+        drop(waiters);
+        state.check_poisoned(poison);
+        Some(state)
+    }
+
+    /// Replaces the value with `f` applied to the current one, running `f` without holding the
+    /// lock. Concurrent callers of `lock_idle` wait until it finishes.
+    ///
+    /// If `f` panics, the entry is left not computed and marked as poisoned.
+    fn compute<F: FnOnce(Option<T>) -> T>(
+        &self,
+        state: RwLockWriteGuard<'_, EntryState<T>>,
+        poison: PoisonPolicy,
+        f: F,
+    ) -> T
+    where
+        T: Clone,
+    {
+        self.compute_with_ttl(state, poison, |prev| (f(prev), None))
+    }
+
+    /// Like `compute`, but `f` also returns the TTL of the value. `None` means the TTL of the
+    /// cache.
+    fn compute_with_ttl<F: FnOnce(Option<T>) -> (T, Option<Duration>)>(
+        &self,
+        state: RwLockWriteGuard<'_, EntryState<T>>,
+        poison: PoisonPolicy,
+        f: F,
+    ) -> T
+    where
+        T: Clone,
+    {
+        match self.try_compute_with_ttl(state, poison, |prev| Ok::<_, Infallible>(f(prev))) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Like `compute_with_ttl`, but `f` may fail. On an error, the entry is left not computed.
+    fn try_compute_with_ttl<E, F: FnOnce(Option<T>) -> Result<(T, Option<Duration>), E>>(
+        &self,
+        mut state: RwLockWriteGuard<'_, EntryState<T>>,
+        poison: PoisonPolicy,
+        f: F,
+    ) -> Result<T, E>
+    where
+        T: Clone,
+    {
+        let prev = state.take(poison);
+        state.computed_at = None;
+        state.computing = Some(thread::current().id());
+        let started = state.version;
+        drop(state);
+
+        let abandon = Abandon(self);
+        let result = f(prev);
+        mem::forget(abandon);
+
+        let mut state = poison.unwrap(self.state.write());
+        state.computing = None;
+        let result = result.map(|(value, ttl)| {
+            // A value stored meanwhile is kept, as it is newer than the one `f` started from.
+            if state.version == started {
+                let _ = state.set(value.clone(), poison);
+                state.ttl = ttl;
+            }
+            value
+        });
+        drop(state);
+        self.notify_computed(poison);
+        result
+    }
+
+    /// Wakes up the threads waiting in `lock_idle`.
+    fn notify_computed(&self, poison: PoisonPolicy) {
+        drop(poison.unwrap(self.waiters.lock()));
+        self.computed.notify_all();
+    }
+}
+
+/// Removes the current thread from the racing computations of an entry when dropped, including
+/// when the computation panics.
+struct Racing<'a, T>(&'a CacheEntry<T>);
+
+impl<T> Drop for Racing<'_, T> {
+    fn drop(&mut self) {
+        let current = thread::current().id();
+        let mut state = PoisonPolicy::Recover.unwrap(self.0.state.write());
+        state.racing.retain(|&thread| thread != current);
+    }
+}
+
+/// Marks the computation of an entry as finished when `f` panics, and wakes up the waiters.
+struct Abandon<'a, T>(&'a CacheEntry<T>);
+
+impl<T> Drop for Abandon<'_, T> {
+    fn drop(&mut self) {
+        let mut state = PoisonPolicy::Recover.unwrap(self.0.state.write());
+        state.computing = None;
+        state.poisoned = true;
+        drop(state);
+        self.0.notify_computed(PoisonPolicy::Recover);
+    }
+}
+
+/// Counting semaphore that bounds the number of concurrent computations of values.
+#[derive(Debug)]
+struct FillLimit {
+    /// The threads holding a slot.
+    running: Mutex<Vec<ThreadId>>,
+    max: usize,
+    released: Condvar,
+}
+
+impl FillLimit {
+    /// Runs `f` once fewer than `max` computations are running.
+    ///
+    /// If the current thread already holds a slot, i.e., `f` is nested in another computation of
+    /// the cache, `f` runs under that slot. Otherwise nested computations would wait for the slots
+    /// held by the computations that wait for them.
+    fn run<R>(&self, poison: PoisonPolicy, f: impl FnOnce() -> R) -> R {
+        let current = thread::current().id();
+        let running = poison.unwrap(self.running.lock());
+        if running.contains(&current) {
+            drop(running);
+            return f();
+        }
+        let mut running = poison.unwrap(
+            self.released
+                .wait_while(running, |running| running.len() >= self.max),
+        );
+        running.push(current);
+        drop(running);
+        let _permit = Permit(self, poison);
+        f()
+    }
+}
+
+/// Releases the slot of the current thread in a `FillLimit` when dropped, even if the computation
+/// panics.
+struct Permit<'a>(&'a FillLimit, PoisonPolicy);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let current = thread::current().id();
+        let mut running = self.1.unwrap(self.0.running.lock());
+        let index = running.iter().position(|&id| id == current).unwrap();
+        let _ = running.swap_remove(index);
+        drop(running);
+        self.0.released.notify_one();
+    }
+}
+
+/// Callback of [`Cache::on_evict`].
+///
+/// It also holds the map of the cache, to pass the remaining entries to the callback when the
+/// cache is dropped.
+struct EvictHook<K, V> {
+    f: Box<dyn Fn(K, V) + Send + Sync>,
+    inner: Arc<RwLock<HashMap<K, Inner<V>>>>,
+    generation: Arc<AtomicU64>,
+}
+
+impl<K, V> fmt::Debug for EvictHook<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EvictHook").finish_non_exhaustive()
+    }
+}
+
+impl<K, V> Drop for EvictHook<K, V> {
+    fn drop(&mut self) {
+        // The sweeper may still share the map, so it is emptied rather than consumed.
+        let map = mem::take(&mut *PoisonPolicy::Recover.unwrap(self.inner.write()));
+        let generation = self.generation.load(Ordering::SeqCst);
+        for (key, entry) in map {
+            if entry.generation != generation {
+                continue;
+            }
+            let value = PoisonPolicy::Recover
+                .unwrap(entry.state.write())
+                .take(PoisonPolicy::Recover);
+            if let Some(value) = value {
+                (self.f)(key, value);
+            }
+        }
+    }
+}
+
+/// How a [`Cache`] handles concurrent misses of the same key. See [`Cache::with_stampede`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StampedeMode {
+    /// Only one of the callers computes the value, and the others wait for it.
+    #[default]
+    Dedup,
+    /// Every caller computes the value without waiting, and the first one to finish stores it.
+    /// The others discard their value and return the stored one. This suits cheap computations
+    /// whose callers must not wait.
+    AllowDuplicate,
+}
+
+/// Which entry a full [`Cache`] evicts. See [`Cache::with_policy`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts an entry that was not hit recently. Like the CLOCK algorithm, this approximates the
+    /// least recently used entry: an entry hit since the last eviction round is spared once.
+    #[default]
+    Lru,
+    /// Evicts an entry with few hits. Each hit spares the entry for one more eviction round, so
+    /// this approximates the least frequently used entry. This suits a stable set of hot keys,
+    /// which a burst of one-off keys would push out under LRU.
+    Lfu,
+}
+
+/// Clock of a bounded cache, which picks the entry to evict without scanning all of them.
+///
+/// Each entry has a slot, and the hand visits the slots in a circle. An entry that was hit since
+/// the hand last passed it gets another round, so the hand stops at an entry that was not used
+/// recently. Under `EvictionPolicy::Lfu`, each hit buys another round, so frequently hit entries
+/// survive longer. Every round is paid for by a hit, so an eviction takes amortized constant time.
+#[derive(Debug)]
+struct EvictionClock<K, V> {
+    /// The slots in the order the hand visits them. The hand is at the front.
+    slots: VecDeque<ClockSlot<K, V>>,
+    /// Generation of the cache when the invalidated entries were last dropped.
+    generation: u64,
+}
+
+impl<K, V> Default for EvictionClock<K, V> {
+    fn default() -> Self {
+        Self {
+            slots: VecDeque::new(),
+            generation: 0,
+        }
+    }
+}
+
+/// Slot of an entry in the clock. The slots of removed entries are dropped when the hand passes.
+#[derive(Debug)]
+struct ClockSlot<K, V> {
+    key: K,
+    entry: Weak<CacheEntry<V>>,
+    /// Number of hits of the entry that were already accounted for when the hand passed it.
+    seen_hits: usize,
+}
+
+impl<K: Eq + Hash, V> ClockSlot<K, V> {
+    /// Returns the entry of the slot if it is still in `map`.
+    fn entry<'m>(&self, map: &'m HashMap<K, Inner<V>>) -> Option<&'m Inner<V>> {
+        map.get(&self.key)
+            .filter(|entry| Arc::as_ptr(entry) == self.entry.as_ptr())
+    }
+}
+
+/// Error returned by [`Cache::get_or_insert_with_timeout`] when another thread's computation of
+/// the value did not finish in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+/// Metadata of a computed cache entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// When the value was computed.
+    pub computed_at: Instant,
+    /// How many times the value has been read from the cache.
+    pub hits: usize,
+}
+
+impl EntryInfo {
+    /// Returns how long ago the value was computed.
+    pub fn age(&self) -> Duration {
+        self.computed_at.elapsed()
+    }
+}
+
+/// Source of the values of a read-through cache. See [`Cache::read_through`].
+pub trait Loader<K, V> {
+    /// Computes the value for `key`.
+    fn load(&self, key: &K) -> V;
+}
+
+impl<K, V, F: Fn(&K) -> V> Loader<K, V> for F {
+    fn load(&self, key: &K) -> V {
+        self(key)
+    }
+}
 
 /// Cache that remembers the result for each key.
 #[derive(Debug, Default)]
@@ -13,6 +506,29 @@ pub struct Cache<K, V> {
     // specification for `get_or_insert_with`.
     // inner: Mutex<HashMap<K, V>>,
     inner: Arc<RwLock<HashMap<K, Inner<V>>>>,
+    poison: PoisonPolicy,
+    /// How long a computed value stays valid. `None` means forever.
+    ttl: Option<Duration>,
+    /// Maximum number of entries. `None` means unbounded.
+    capacity: Option<usize>,
+    /// Which entry to evict when the cache is full.
+    policy: EvictionPolicy,
+    stampede: StampedeMode,
+    /// Picks the entries to evict when the cache is full. It is only locked while holding the
+    /// write lock of `inner`.
+    clock: Mutex<EvictionClock<K, V>>,
+    /// Bumped by `invalidate_all`. It is shared with the sweeper.
+    generation: Arc<AtomicU64>,
+    /// Number of lookups that took the exclusive lock of their entry.
+    exclusive_lookups: AtomicUsize,
+    /// Bound on the number of values computed at the same time. `None` means unbounded.
+    fill_limit: Option<FillLimit>,
+    /// Called with the entries evicted for capacity, and with the remaining ones on drop.
+    on_evict: Option<EvictHook<K, V>>,
+    /// Entries of `get_or_insert_with_async`. They are kept apart from `inner` because a blocking
+    /// caller cannot wait for an async computation and vice versa.
+    #[cfg(feature = "async")]
+    async_inner: RwLock<HashMap<K, Arc<tokio::sync::OnceCell<V>>>>,
 }
 
 // impl<K, V> Default for Cache<K, V> {
@@ -23,6 +539,164 @@ pub struct Cache<K, V> {
 //     }
 // }
 
+impl<K, V> Cache<K, V> {
+    /// Creates a new unbounded cache whose values never expire.
+    ///
+    /// The builder methods below configure it further, e.g.,
+    /// `Cache::new().with_capacity(100).with_ttl(ttl)`.
+    pub fn new() -> Self {
+        Self::with_poison_policy(PoisonPolicy::default())
+    }
+
+    /// Creates a new cache that handles poisoned locks according to `poison`.
+    ///
+    /// A panic in the computation of a value poisons its entry. Under `PoisonPolicy::Recover`, the
+    /// entry is treated as not computed yet, so the next caller recomputes it.
+    pub fn with_poison_policy(poison: PoisonPolicy) -> Self {
+        Self {
+            inner: Arc::default(),
+            poison,
+            ttl: None,
+            capacity: None,
+            policy: EvictionPolicy::Lru,
+            stampede: StampedeMode::Dedup,
+            clock: Mutex::default(),
+            generation: Arc::default(),
+            exclusive_lookups: AtomicUsize::new(0),
+            fill_limit: None,
+            on_evict: None,
+            #[cfg(feature = "async")]
+            async_inner: RwLock::default(),
+        }
+    }
+
+    /// Makes the values expire `ttl` after they are computed.
+    ///
+    /// An expired value is recomputed by the next `get_or_insert_with`. Until then it stays in
+    /// memory, unless a sweeper removes it (see [`start_sweeper`](Self::start_sweeper)).
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Bounds the cache to `capacity` entries.
+    ///
+    /// Inserting a new key into a full cache evicts the computed entry chosen by the policy of
+    /// [`with_policy`](Self::with_policy), by default one that was not hit recently. Entries that
+    /// are being computed or [pinned](Self::pin) are never evicted, so the cache may exceed
+    /// `capacity` while all of them are in flight or pinned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Makes a cache bounded by [`with_capacity`](Self::with_capacity) evict the computed entry
+    /// chosen by `policy` when it is full.
+    pub fn with_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Handles concurrent misses of the same key according to `mode`.
+    ///
+    /// Under [`StampedeMode::AllowDuplicate`], [`get_or_insert_with`](Self::get_or_insert_with)
+    /// never waits for another thread's computation, and
+    /// [`get_or_insert_with_status`](Self::get_or_insert_with_status) reports `true` only if the
+    /// value of this call was stored. The other operations, e.g., [`compute`](Self::compute), still
+    /// run one at a time per key.
+    pub fn with_stampede(mut self, mode: StampedeMode) -> Self {
+        self.stampede = mode;
+        self
+    }
+
+    /// Runs at most `n` computations of values at the same time, across all keys. Computations
+    /// beyond that wait until one finishes, so a burst of misses does not start unboundedly many
+    /// expensive computations.
+    ///
+    /// Callers waiting for a computation of the same key by another thread do not count. A
+    /// computation that computes other keys itself, e.g., in
+    /// [`get_or_insert_with_cache`](Self::get_or_insert_with_cache), counts once, so nested misses
+    /// do not wait for one another.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn with_max_concurrent_fills(mut self, n: usize) -> Self {
+        assert!(n > 0);
+        self.fill_limit = Some(FillLimit {
+            running: Mutex::new(Vec::with_capacity(n)),
+            max: n,
+            released: Condvar::new(),
+        });
+        self
+    }
+
+    /// Sets a callback that is called with the key and the value of each entry evicted because the
+    /// cache is full, e.g., to write back the values of a write-behind cache.
+    ///
+    /// When the cache is dropped, the callback is also called for every remaining computed entry
+    /// in an unspecified order, so that none of them is lost. Entries being computed and
+    /// invalidated ones are skipped. The callback is never called while a lock of the cache is
+    /// held, so it may use the cache.
+    pub fn on_evict<F: Fn(K, V) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_evict = Some(EvictHook {
+            f: Box::new(f),
+            inner: Arc::clone(&self.inner),
+            generation: Arc::clone(&self.generation),
+        });
+        self
+    }
+
+    /// Runs the computation `f` of a value, within the limit of concurrent computations.
+    fn fill<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.fill_limit {
+            Some(limit) => limit.run(self.poison, f),
+            None => f(),
+        }
+    }
+
+    /// Returns whether `entry` was created after the last `invalidate_all`.
+    fn is_current(&self, entry: &CacheEntry<V>) -> bool {
+        entry.generation == self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Invalidates all the entries at once, without walking them, so that the next lookup of each
+    /// key computes it again.
+    ///
+    /// The invalidated entries are dropped lazily: by the next lookup of their key, by the next
+    /// eviction of a full cache, which drops all of them first, or by the sweeper. Until then they
+    /// are counted by [`len`](Self::len). Like [`invalidate_matching`](Self::invalidate_matching),
+    /// running computations finish into the invalidated entries.
+    pub fn invalidate_all(&self) {
+        let _ = self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns the number of lookups that could not be served under the shared lock of their
+    /// entry, because its value had to be computed or waited for.
+    ///
+    /// Hits on a computed value only take the shared lock, so concurrent hits on the same key do
+    /// not serialize and are not counted here.
+    pub fn exclusive_lookups(&self) -> usize {
+        self.exclusive_lookups.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of entries, including expired ones, invalidated ones that are not
+    /// dropped yet, and ones being computed.
+    pub fn len(&self) -> usize {
+        self.poison.unwrap(self.inner.read()).len()
+    }
+
+    /// Returns `true` if the cache has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 impl<K: Eq + Hash + Clone, V: Clone> Cache<K, V> {
     /// Retrieve the value or insert a new one created by `f`.
     ///
@@ -37,29 +711,818 @@ impl<K: Eq + Hash + Clone, V: Clone> Cache<K, V> {
     ///
     /// Hint: the [`Entry`] API may be useful in implementing this function.
     ///
+    /// # Panics
+    ///
+    /// `f` may look up other keys in this cache, but panics if it looks up `key` itself, which
+    /// would otherwise wait for its own result forever.
+    ///
     /// [`Entry`]: https://doc.rust-lang.org/stable/std/collections/hash_map/struct.HashMap.html#method.entry
     pub fn get_or_insert_with<F: FnOnce(K) -> V>(&self, key: K, f: F) -> V {
-        let value = Arc::new(Mutex::new(None));
+        self.get_or_insert_with_status(key, f).0
+    }
 
-        let mut write_lock = self.inner.write().unwrap();
+    /// Like [`get_or_insert_with`](Self::get_or_insert_with), but passes the cache to `f`, e.g., to
+    /// derive the value from the values of other keys.
+    ///
+    /// `f` runs without holding any lock of the cache, so it may look up and compute other keys.
+    /// Under [`with_max_concurrent_fills`](Self::with_max_concurrent_fills), the nested
+    /// computations share the slot of `f` instead of waiting for another one. Looking up `key`
+    /// itself panics, as for `get_or_insert_with`.
+    pub fn get_or_insert_with_cache<F: FnOnce(&Self, K) -> V>(&self, key: K, f: F) -> V {
+        self.get_or_insert_with(key, |key| f(self, key))
+    }
 
-        if !write_lock.contains_key(&key) {
-            write_lock.insert(key.clone(), Arc::clone(&value));
+    /// Like [`get_or_insert_with`](Self::get_or_insert_with), but also returns whether `f` was
+    /// run by this call.
+    ///
+    /// The flag is `false` both on a hit and when this call waited for another thread's
+    /// computation of the same key.
+    pub fn get_or_insert_with_status<F: FnOnce(K) -> V>(&self, key: K, f: F) -> (V, bool) {
+        match self.lookup(key, None, |key| (f(key), None)) {
+            Ok(result) => result,
+            Err(Timeout) => unreachable!("waited without a timeout"),
         }
+    }
 
-        let stored_value = Arc::clone(write_lock.get(&key).unwrap());
+    /// Like [`get_or_insert_with`](Self::get_or_insert_with), but gives up if another thread is
+    /// computing the value and it does not finish within `timeout`.
+    ///
+    /// The other thread still finishes and caches the value. If no other thread is computing the
+    /// value, this computes it with `f` regardless of `timeout`.
+    pub fn get_or_insert_with_timeout<F: FnOnce(K) -> V>(
+        &self,
+        key: K,
+        timeout: Duration,
+        f: F,
+    ) -> Result<V, Timeout> {
+        self.lookup(key, Some(timeout), |key| (f(key), None))
+            .map(|(v, _)| v)
+    }
 
-        drop(write_lock);
+    /// Like [`get_or_insert_with`](Self::get_or_insert_with), but `f` also decides how long the
+    /// value stays valid, e.g., from the `max-age` of an HTTP response. The TTL of the value
+    /// overrides the one of the cache, and it applies until the value is replaced.
+    pub fn get_or_insert_with_computed_ttl<F: FnOnce(K) -> (V, Duration)>(
+        &self,
+        key: K,
+        f: F,
+    ) -> V {
+        let result = self.lookup(key, None, |key| {
+            let (value, ttl) = f(key);
+            (value, Some(ttl))
+        });
+        match result {
+            Ok((v, _)) => v,
+            Err(Timeout) => unreachable!("waited without a timeout"),
+        }
+    }
+
+    /// Returns the value for `key` and whether it was computed by this call, waiting for other
+    /// threads' computation of it for at most `timeout`. `f` returns the value and its TTL.
+    fn lookup<F: FnOnce(K) -> (V, Option<Duration>)>(
+        &self,
+        key: K,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> Result<(V, bool), Timeout> {
+        self.lookup_in(&self.entry(&key), key, timeout, f)
+    }
+
+    /// Like `lookup`, with the `entry` for `key`.
+    fn lookup_in<F: FnOnce(K) -> (V, Option<Duration>)>(
+        &self,
+        entry: &CacheEntry<V>,
+        key: K,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> Result<(V, bool), Timeout> {
+        let result = match entry.read_fresh(self.ttl, self.poison) {
+            Some(v) => {
+                let _ = entry.hits.fetch_add(1, Ordering::Relaxed);
+                Some((v, false))
+            }
+            None => match self.stampede {
+                StampedeMode::Dedup => self.lookup_exclusive(entry, &key, timeout, f),
+                StampedeMode::AllowDuplicate => Some(self.lookup_racing(entry, &key, f)),
+            },
+        };
+        // The key is deliberately not recorded. Requiring `K: Debug` only with the `tracing`
+        // feature would make enabling it break the caches of other crates.
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            op = "get_or_insert_with",
+            outcome = match result {
+                Some((_, true)) => "miss",
+                Some((_, false)) => "hit",
+                None => "timeout",
+            },
+        );
+        result.ok_or(Timeout)
+    }
 
+    /// The slow path of `lookup` under `StampedeMode::AllowDuplicate`. The value is computed
+    /// without marking the entry as being computed, and then stored unless another thread stored
+    /// a value meanwhile or is computing one, e.g., in `compute`, which would overwrite it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current thread is already computing the value, as it would recurse forever.
+    fn lookup_racing<F: FnOnce(K) -> (V, Option<Duration>)>(
+        &self,
+        entry: &CacheEntry<V>,
+        key: &K,
+        f: F,
+    ) -> (V, bool) {
+        let _ = self.exclusive_lookups.fetch_add(1, Ordering::Relaxed);
+        let current = thread::current().id();
+        let mut state = self.poison.unwrap(entry.state.write());
+        if state.racing.contains(&current) {
+            drop(state);
+            panic!("{REENTRANT}");
+        }
+        state.racing.push(current);
+        let started = state.version;
+        drop(state);
+        let racing = Racing(entry);
+        let (value, ttl) = self.fill(|| f(key.clone()));
+        drop(racing);
+        let mut state = self.poison.unwrap(entry.state.write());
+        state.check_poisoned(self.poison);
+        if let Some(v) = state.fresh(self.ttl, self.poison) {
+            let _ = entry.hits.fetch_add(1, Ordering::Relaxed);
+            return (v, false);
+        }
+        if state.version != started || state.computing.is_some() {
+            return (value, false);
+        }
+        let _ = state.set(value.clone(), self.poison);
+        state.ttl = ttl;
+        (value, true)
+    }
+
+    /// The slow path of `lookup`, under the exclusive lock of the entry.
+    fn lookup_exclusive<F: FnOnce(K) -> (V, Option<Duration>)>(
+        &self,
+        entry: &CacheEntry<V>,
+        key: &K,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> Option<(V, bool)> {
+        let _ = self.exclusive_lookups.fetch_add(1, Ordering::Relaxed);
+        entry.lock_idle(self.poison, timeout).map(|state| {
+            if let Some(v) = state.fresh(self.ttl, self.poison) {
+                let _ = entry.hits.fetch_add(1, Ordering::Relaxed);
+                (v, false)
+            } else {
+                (
+                    entry.compute_with_ttl(state, self.poison, |_| self.fill(|| f(key.clone()))),
+                    true,
+                )
+            }
+        })
+    }
+
+    /// Returns the value for `key`, computing it with `f` if needed. If `f` fails, nothing is
+    /// cached and the error is returned. The computation is deduplicated like under
+    /// `StampedeMode::Dedup`, whatever the mode of the cache.
+    fn try_lookup<E, F: FnOnce(K) -> Result<V, E>>(&self, key: K, f: F) -> Result<V, E> {
+        let entry = self.entry(&key);
+        let result = match entry.read_fresh(self.ttl, self.poison) {
+            Some(v) => {
+                let _ = entry.hits.fetch_add(1, Ordering::Relaxed);
+                Ok((v, false))
+            }
+            None => {
+                let _ = self.exclusive_lookups.fetch_add(1, Ordering::Relaxed);
+                let state = entry.lock_idle(self.poison, None).unwrap();
+                if let Some(v) = state.fresh(self.ttl, self.poison) {
+                    let _ = entry.hits.fetch_add(1, Ordering::Relaxed);
+                    Ok((v, false))
+                } else {
+                    entry
+                        .try_compute_with_ttl(state, self.poison, |_| {
+                            self.fill(|| f(key.clone())).map(|v| (v, None))
+                        })
+                        .map(|v| (v, true))
+                }
+            }
+        };
+        if result.is_err() {
+            self.remove_placeholder(&key, entry);
+        }
+        // No key, as in `lookup_in`.
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            op = "get_or_try_insert_with",
+            outcome = match result {
+                Ok((_, true)) => "miss",
+                Ok((_, false)) => "hit",
+                Err(_) => "error",
+            },
+        );
+        result.map(|(v, _)| v)
+    }
+
+    /// Removes `entry` for `key` if it is still empty and no other thread uses it, e.g., after its
+    /// computation failed. An entry that another thread waits on is kept, so that the value it
+    /// computes is cached.
+    fn remove_placeholder(&self, key: &K, entry: Inner<V>) {
+        let mut write_lock = self.poison.unwrap(self.inner.write());
+        // Other threads only get the entry from the map, which is locked, so the count is stable.
+        if !write_lock.get(key).is_some_and(|e| Arc::ptr_eq(e, &entry))
+            || Arc::strong_count(&entry) > 2
         {
-            let mut lock = stored_value.lock().unwrap();
-            if lock.is_none() {
-                let v = f(key.clone());
-                *lock = Some(v.clone());
-                v.clone()
+            return;
+        }
+        let state = self.poison.unwrap(entry.state.read());
+        if !state.has_value(self.poison) && state.computing.is_none() {
+            drop(state);
+            let _ = write_lock.remove(key);
+        }
+    }
+
+    /// Like [`get_or_insert_with`](Self::get_or_insert_with), but returns the slot of the entry
+    /// instead of a clone of the value, e.g., for code that already shares values as
+    /// `Arc<RwLock<Option<V>>>`. The slot is updated in place, so the caller observes later updates
+    /// of the value, e.g., by [`update`](Self::update), without looking up the key again.
+    ///
+    /// The slot holds `None` while the value is being recomputed. A value written into the slot is
+    /// served by the cache as if it was computed at the time of the last computation. If the entry
+    /// is removed from the cache, the slot is detached: it keeps the last value, and the key gets a
+    /// new slot.
+    ///
+    /// External code must not panic while holding the lock of the slot, which would poison it
+    /// and make the lookups of the key panic under `PoisonPolicy::Propagate`. It must not use
+    /// the cache while holding the lock either, since the cache takes it under its own locks.
+    pub fn get_or_insert_arc_cell<F: FnOnce(K) -> V>(
+        &self,
+        key: K,
+        f: F,
+    ) -> Arc<RwLock<Option<V>>> {
+        let entry = self.entry(&key);
+        match self.lookup_in(&entry, key, None, |key| (f(key), None)) {
+            Ok(_) => Arc::clone(&self.poison.unwrap(entry.state.read()).value),
+            Err(Timeout) => unreachable!("waited without a timeout"),
+        }
+    }
+
+    /// Retrieve the value or load it with `loader`, with the same guarantees as
+    /// [`get_or_insert_with`](Self::get_or_insert_with). In particular, `loader` is called only
+    /// once per key even for concurrent calls.
+    pub fn read_through<L: Loader<K, V> + ?Sized>(&self, key: K, loader: &L) -> V {
+        self.get_or_insert_with(key, |key| loader.load(&key))
+    }
+
+    /// Like [`read_through`](Self::read_through), but runs the load in `pool`, so that at most as
+    /// many loads run at the same time as the pool has workers, and returns a receiver of the
+    /// value right away. The calling thread is free during the load, e.g., to serve other
+    /// requests, and receives the value once it is ready.
+    ///
+    /// A hit is sent before this returns. Concurrent misses for the same key run a single load:
+    /// their jobs wait in the pool for the first one, whatever the [`StampedeMode`] of the cache.
+    /// If the load panics, the panic is reported by the pool, and the receiver is disconnected.
+    pub fn read_through_pooled<L>(&self, key: K, loader: &L, pool: &ThreadPool) -> Receiver<V>
+    where
+        K: Send + 'static,
+        V: Send + Sync + 'static,
+        L: Loader<K, V> + Clone + Send + 'static,
+    {
+        let (tx, rx) = bounded(1);
+        let entry = self.entry(&key);
+        if let Some(v) = entry.read_fresh(self.ttl, self.poison) {
+            let _ = entry.hits.fetch_add(1, Ordering::Relaxed);
+            let _ = tx.send(v);
+            return rx;
+        }
+        let _ = self.exclusive_lookups.fetch_add(1, Ordering::Relaxed);
+        let (ttl, poison, loader) = (self.ttl, self.poison, loader.clone());
+        pool.execute_to(tx, move || {
+            let state = entry.lock_idle(poison, None).unwrap();
+            if let Some(v) = state.fresh(ttl, poison) {
+                let _ = entry.hits.fetch_add(1, Ordering::Relaxed);
+                return v;
+            }
+            entry.compute(state, poison, |_| loader.load(&key))
+        });
+        rx
+    }
+
+    /// Sets the value for `key` and returns the previous one, if it was computed.
+    ///
+    /// If the value is being computed by another thread, waits for it to finish and then
+    /// overwrites it.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let entry = self.entry(&key);
+        let prev = entry
+            .lock_idle(self.poison, None)
+            .and_then(|mut state| state.set(value, self.poison));
+        prev
+    }
+
+    /// Replaces the value for `key` with `new` only if it is computed and equal to `expected`,
+    /// and returns whether it was replaced.
+    ///
+    /// The comparison and the replacement are done under the lock of the entry, so of concurrent
+    /// calls with the same `expected`, at most one succeeds. If the value is being computed by
+    /// another thread, waits for it to finish first.
+    pub fn compare_and_set(&self, key: &K, expected: &V, new: V) -> bool
+    where
+        V: PartialEq,
+    {
+        let Some(entry) = self
+            .poison
+            .unwrap(self.inner.read())
+            .get(key)
+            .filter(|entry| self.is_current(entry))
+            .cloned()
+        else {
+            return false;
+        };
+        let Some(mut state) = entry.lock_idle(self.poison, None) else {
+            return false;
+        };
+        if state.fresh(self.ttl, self.poison).as_ref() != Some(expected) {
+            return false;
+        }
+        let _ = state.set(new, self.poison);
+        drop(state);
+        true
+    }
+
+    /// Replaces the value for `key` with `f` applied to the current one (`None` if not computed),
+    /// and returns the new value.
+    ///
+    /// Concurrent calls for the same key wait while `f` runs, so they are serialized. Calls with
+    /// different keys do not block each other.
+    pub fn compute<F: FnOnce(Option<V>) -> V>(&self, key: K, f: F) -> V {
+        let entry = self.entry(&key);
+        let state = entry.lock_idle(self.poison, None).unwrap();
+        entry.compute(state, self.poison, |prev| self.fill(|| f(prev)))
+    }
+
+    /// Replaces the value for `key` with `f` applied to the current one, and returns the new
+    /// value. Returns `None` without calling `f` if the key has no fresh value.
+    ///
+    /// Like [`compute`](Self::compute), concurrent calls for the same key are serialized.
+    pub fn update<F: FnOnce(&V) -> V>(&self, key: &K, f: F) -> Option<V> {
+        let entry = self
+            .poison
+            .unwrap(self.inner.read())
+            .get(key)
+            .filter(|entry| self.is_current(entry))
+            .cloned()?;
+        let state = entry.lock_idle(self.poison, None)?;
+        let _ = state.fresh(self.ttl, self.poison)?;
+        let v = entry.compute(state, self.poison, |prev| {
+            self.fill(|| f(&prev.expect("checked to be computed")))
+        });
+        Some(v)
+    }
+
+    /// Returns the entry for `key`, inserting an empty one if there is none.
+    fn entry(&self, key: &K) -> Inner<V> {
+        if let Some(entry) = self.poison.unwrap(self.inner.read()).get(key) {
+            if self.is_current(entry) {
+                return Arc::clone(entry);
+            }
+        }
+        let mut write_lock = self.poison.unwrap(self.inner.write());
+        if let Some(entry) = write_lock.get(key) {
+            if self.is_current(entry) {
+                return Arc::clone(entry);
+            }
+            let _ = write_lock.remove(key);
+        }
+        let (entry, evicted) = self.insert_entry(&mut write_lock, key.clone());
+        drop(write_lock);
+        self.report_evicted(evicted);
+        entry
+    }
+
+    /// Inserts an empty entry for `key`, which must not be in `map`, evicting another one if the
+    /// cache is full. Returns the new entry and the evicted one, which should be passed to
+    /// `report_evicted` once `map` is unlocked.
+    fn insert_entry(
+        &self,
+        map: &mut HashMap<K, Inner<V>>,
+        key: K,
+    ) -> (Inner<V>, Option<(K, Inner<V>)>) {
+        let evicted = if self.capacity.is_some_and(|capacity| map.len() >= capacity) {
+            self.evict(map)
+        } else {
+            None
+        };
+        let entry = Arc::new(CacheEntry::new(self.generation.load(Ordering::SeqCst)));
+        if self.capacity.is_some() {
+            let mut clock = self.poison.unwrap(self.clock.lock());
+            clock.slots.push_back(ClockSlot {
+                key: key.clone(),
+                entry: Arc::downgrade(&entry),
+                seen_hits: 0,
+            });
+            // Entries removed by other means leave their slots behind, which are dropped from
+            // time to time so that the clock stays proportional to the map.
+            if clock.slots.len() > 2 * map.len() + 16 {
+                clock.slots.retain(|slot| slot.entry(map).is_some());
+            }
+        }
+        let _ = map.insert(key, Arc::clone(&entry));
+        (entry, evicted)
+    }
+
+    /// Passes an entry removed by `evict` to the `on_evict` callback, unless it was invalidated.
+    fn report_evicted(&self, evicted: Option<(K, Inner<V>)>) {
+        let (Some(hook), Some((key, entry))) = (&self.on_evict, evicted) else {
+            return;
+        };
+        if !self.is_current(&entry) {
+            return;
+        }
+        let value = self.poison.unwrap(entry.state.read()).value(self.poison);
+        if let Some(value) = value {
+            (hook.f)(key, value);
+        }
+    }
+
+    /// Makes room in the full `map`, and returns the removed entry to report, if any.
+    ///
+    /// After an `invalidate_all`, the invalidated entries are all dropped at once. Otherwise, the
+    /// hand of the clock removes the first computed entry that is neither pinned nor due another
+    /// round. If a whole round finds none, nothing is removed.
+    fn evict(&self, map: &mut HashMap<K, Inner<V>>) -> Option<(K, Inner<V>)> {
+        let mut clock = self.poison.unwrap(self.clock.lock());
+        let generation = self.generation.load(Ordering::SeqCst);
+        if clock.generation != generation {
+            clock.generation = generation;
+            let before = map.len();
+            map.retain(|_, entry| self.is_current(entry));
+            if map.len() < before {
+                clock.slots.retain(|slot| slot.entry(map).is_some());
+            }
+            if self.capacity.is_some_and(|capacity| map.len() < capacity) {
+                return None;
+            }
+        }
+
+        // Number of slots visited since the last one that used up a hit. The hand gives up after a
+        // round without progress, e.g., when all entries are pinned or being computed.
+        let mut idle = 0;
+        while idle < clock.slots.len() {
+            let mut slot = clock.slots.pop_front()?;
+            let Some(entry) = slot.entry(map) else {
+                continue;
+            };
+            if entry.pinned.load(Ordering::Relaxed) || !entry.is_computed(self.poison) {
+                idle += 1;
             } else {
-                lock.clone().unwrap()
+                let hits = entry.hits.load(Ordering::Relaxed);
+                if hits <= slot.seen_hits {
+                    return map.remove_entry(&slot.key);
+                }
+                slot.seen_hits = match self.policy {
+                    EvictionPolicy::Lru => hits,
+                    EvictionPolicy::Lfu => slot.seen_hits + 1,
+                };
+                idle = 0;
             }
+            clock.slots.push_back(slot);
+        }
+        None
+    }
+
+    /// Exempts the entry for `key` from eviction, e.g., for a value that must stay available in a
+    /// bounded cache. If `key` is not in the cache yet, its value is pinned once computed.
+    ///
+    /// The entry can still be removed by `invalidate_matching` and `invalidate_all`, which also
+    /// drop the pin.
+    pub fn pin(&self, key: &K) {
+        self.entry(key).pinned.store(true, Ordering::Relaxed);
+    }
+
+    /// Makes the entry for `key` evictable again after [`pin`](Self::pin).
+    pub fn unpin(&self, key: &K) {
+        if let Some(entry) = self.poison.unwrap(self.inner.read()).get(key) {
+            entry.pinned.store(false, Ordering::Relaxed);
         }
     }
+
+    /// Returns the metadata of the entry for `key`, or `None` if its value is not computed yet.
+    pub fn entry_info(&self, key: &K) -> Option<EntryInfo> {
+        let entry = Arc::clone(
+            self.poison
+                .unwrap(self.inner.read())
+                .get(key)
+                .filter(|entry| self.is_current(entry))?,
+        );
+        let computed_at = self.poison.unwrap(entry.state.read()).computed_at?;
+        Some(EntryInfo {
+            computed_at,
+            hits: entry.hits.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Returns the keys whose value is being computed, e.g., to find the hot spots of a stampede.
+    ///
+    /// This does not wait for the computations: the lock of an entry is not held while its value
+    /// is computed, only while its status is read or updated.
+    pub fn in_flight_keys(&self) -> Vec<K> {
+        self.poison
+            .unwrap(self.inner.read())
+            .iter()
+            .filter(|(_, entry)| {
+                self.is_current(entry) && self.poison.unwrap(entry.state.read()).computing.is_some()
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Removes every entry whose key matches `f`, and returns the number of removed entries.
+    ///
+    /// Entries whose value is still being computed are removed as well. The running computation
+    /// finishes into the removed entry, and a later call for the same key computes again.
+    pub fn invalidate_matching<F: FnMut(&K) -> bool>(&self, mut f: F) -> usize {
+        let mut write_lock = self.poison.unwrap(self.inner.write());
+        let before = write_lock.len();
+        write_lock.retain(|key, _| !f(key));
+        before - write_lock.len()
+    }
+
+    /// Replaces all the entries with the computed `entries`, e.g., to refresh the cache from its
+    /// source of truth.
+    ///
+    /// The new map is built without holding any lock, and then swapped in under a single brief
+    /// write lock, so every lookup sees either all the old entries or all the new ones. The old
+    /// entries are dropped after the swap, without calling [`on_evict`](Self::on_evict), and
+    /// running computations finish into them like for
+    /// [`invalidate_matching`](Self::invalidate_matching). If `entries` outnumber the capacity,
+    /// the cache exceeds it until entries are removed.
+    pub fn replace_all<I: IntoIterator<Item = (K, V)>>(&self, entries: I) {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let map = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let mut entry = CacheEntry::new(generation);
+                let _ = self
+                    .poison
+                    .unwrap(entry.state.get_mut())
+                    .set(value, self.poison);
+                (key, Arc::new(entry))
+            })
+            .collect::<HashMap<_, _>>();
+        let slots = match self.capacity {
+            Some(_) => map
+                .iter()
+                .map(|(key, entry)| ClockSlot {
+                    key: key.clone(),
+                    entry: Arc::downgrade(entry),
+                    seen_hits: 0,
+                })
+                .collect(),
+            None => VecDeque::new(),
+        };
+        let mut write_lock = self.poison.unwrap(self.inner.write());
+        let old = mem::replace(&mut *write_lock, map);
+        let old_slots = mem::replace(&mut self.poison.unwrap(self.clock.lock()).slots, slots);
+        drop(write_lock);
+        drop((old, old_slots));
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Cache<Arc<K>, V> {
+    /// Creates a new cache whose keys are stored as `Arc`s and looked up by reference with
+    /// [`get_or_insert_with_ref`](Self::get_or_insert_with_ref).
+    pub fn arc_keys() -> Self {
+        Self::with_poison_policy(PoisonPolicy::default())
+    }
+
+    /// Retrieve the value or insert a new one created by `f`, like
+    /// [`get_or_insert_with`](Self::get_or_insert_with), but looking up the key by reference.
+    ///
+    /// The keys are stored as `Arc`s, so `key` is cloned only when it is not in the cache, and the
+    /// cache shares that single clone afterwards. This makes expensive-to-clone keys cheap.
+    pub fn get_or_insert_with_ref<F: FnOnce(&K) -> V>(&self, key: &K, f: F) -> V {
+        self.get_or_insert_with(self.shared_key(key), |key| f(&key))
+    }
+
+    /// Returns the stored key equal to `key`, inserting a clone of it with an empty entry if there
+    /// is none.
+    fn shared_key(&self, key: &K) -> Arc<K> {
+        if let Some((shared, _)) = self.poison.unwrap(self.inner.read()).get_key_value(key) {
+            return Arc::clone(shared);
+        }
+        let mut write_lock = self.poison.unwrap(self.inner.write());
+        if let Some((shared, _)) = write_lock.get_key_value(key) {
+            return Arc::clone(shared);
+        }
+        let shared = Arc::new(key.clone());
+        let (_, evicted) = self.insert_entry(&mut write_lock, Arc::clone(&shared));
+        drop(write_lock);
+        self.report_evicted(evicted);
+        shared
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> Cache<K, Weak<V>> {
+    /// Creates a new cache that does not keep its values alive.
+    ///
+    /// The values are shared as `Arc`s and only `Weak` references are stored, so a value is freed
+    /// once the callers drop all of their `Arc`s. Use
+    /// [`get_or_insert_arc_with`](Self::get_or_insert_arc_with) to access it.
+    pub fn weak() -> Self {
+        Self::with_poison_policy(PoisonPolicy::default())
+    }
+
+    /// Retrieve the value or insert a new one created by `f`, like
+    /// [`get_or_insert_with`](Self::get_or_insert_with), but treating a value that was freed as a
+    /// miss.
+    ///
+    /// A hit on a live value only takes the shared lock of its entry, and counts as a hit in
+    /// [`entry_info`](Self::entry_info). Concurrent misses with the same key are serialized, so `f`
+    /// still runs at most once while the value is alive.
+    pub fn get_or_insert_arc_with<F: FnOnce(K) -> V>(&self, key: K, f: F) -> Arc<V> {
+        let entry = self.entry(&key);
+        let live = entry
+            .read_fresh(self.ttl, self.poison)
+            .and_then(|weak| weak.upgrade());
+        if let Some(arc) = live {
+            let _ = entry.hits.fetch_add(1, Ordering::Relaxed);
+            return arc;
+        }
+
+        let _ = self.exclusive_lookups.fetch_add(1, Ordering::Relaxed);
+        let state = entry.lock_idle(self.poison, None).unwrap();
+        match state
+            .fresh(self.ttl, self.poison)
+            .and_then(|weak| weak.upgrade())
+        {
+            Some(arc) => {
+                let _ = entry.hits.fetch_add(1, Ordering::Relaxed);
+                arc
+            }
+            None => {
+                let mut value = None;
+                let _ = entry.compute(state, self.poison, |_| {
+                    let arc = Arc::new(self.fill(|| f(key)));
+                    let weak = Arc::downgrade(&arc);
+                    value = Some(arc);
+                    weak
+                });
+                value.unwrap()
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> Cache<K, Arc<V>> {
+    /// Retrieve the value or insert a new one loaded by `f`, like
+    /// [`get_or_insert_with`](Self::get_or_insert_with), but `f` may fail, e.g., for a network
+    /// load of a large value.
+    ///
+    /// The value is stored as an `Arc`, so a hit only clones the `Arc`. If `f` returns an error,
+    /// nothing is cached: the error is returned, and the empty entry for `key` is removed unless
+    /// another caller is waiting on it, in which case that caller runs its own `f`. Concurrent
+    /// calls with the same key wait for each other whatever the [`StampedeMode`] of the cache.
+    pub fn get_or_try_insert_with_arc<E, F: FnOnce(K) -> Result<V, E>>(
+        &self,
+        key: K,
+        f: F,
+    ) -> Result<Arc<V>, E> {
+        self.try_lookup(key, |key| f(key).map(Arc::new))
+    }
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    /// Starts a background thread that removes the expired and invalidated entries every
+    /// `interval`, so that their memory is freed without waiting for an access. The thread stops
+    /// when the returned handle is dropped.
+    ///
+    /// Each sweep first collects the expired keys under the read lock, and then takes the write
+    /// lock only to remove them, so hits are blocked only briefly.
+    pub fn start_sweeper(&self, interval: Duration) -> SweeperHandle {
+        let inner = Arc::clone(&self.inner);
+        let generation = Arc::clone(&self.generation);
+        let ttl = self.ttl;
+        let poison = self.poison;
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let (stopped, condvar) = &*stop;
+                let mut stopped = stopped.lock().unwrap();
+                while !*stopped {
+                    stopped = condvar.wait_timeout(stopped, interval).unwrap().0;
+                    let generation = generation.load(Ordering::SeqCst);
+                    let is_stale = |entry: &CacheEntry<V>| {
+                        entry.generation != generation || entry.is_expired(ttl, poison)
+                    };
+                    let expired = poison
+                        .unwrap(inner.read())
+                        .iter()
+                        .filter(|(_, entry)| is_stale(entry))
+                        .map(|(key, _)| key.clone())
+                        .collect::<Vec<_>>();
+                    if expired.is_empty() {
+                        continue;
+                    }
+                    let mut write_lock = poison.unwrap(inner.write());
+                    for key in expired {
+                        // The value may have been recomputed in the meantime.
+                        if let Entry::Occupied(entry) = write_lock.entry(key) {
+                            if is_stale(entry.get()) {
+                                let _ = entry.remove();
+                            }
+                        }
+                    }
+                }
+            })
+        };
+        SweeperHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Handle of the sweeper thread of a cache. Dropping it stops the thread.
+#[derive(Debug)]
+pub struct SweeperHandle {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for SweeperHandle {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl<K: Eq + Hash + Clone + Persist, V: Clone + Persist> Cache<K, V> {
+    /// Writes the computed entries to `w`, so that they can be restored with
+    /// [`load_from`](Self::load_from), e.g., to warm up the cache after a restart.
+    ///
+    /// Expired and invalidated entries and entries that are being computed are skipped. The
+    /// entries are encoded under the read lock of the cache, and written after it is released.
+    pub fn snapshot_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut entries = Vec::new();
+        let mut count = 0;
+        for (key, entry) in self.poison.unwrap(self.inner.read()).iter() {
+            let state = self.poison.unwrap(entry.state.read());
+            if state.computing.is_some() || !self.is_current(entry) {
+                continue;
+            }
+            if let Some(value) = state.fresh(self.ttl, self.poison) {
+                snapshot::write_item(&mut entries, key)?;
+                snapshot::write_item(&mut entries, &value)?;
+                count += 1;
+            }
+        }
+        snapshot::write_len(&mut w, count)?;
+        w.write_all(&entries)?;
+        w.flush()
+    }
+
+    /// Inserts the entries of a snapshot written by [`snapshot_to`](Self::snapshot_to), and
+    /// returns the number of entries.
+    ///
+    /// The restored values count as computed now for the TTL. If the data is malformed, an error
+    /// of kind `InvalidData` or `UnexpectedEof` is returned, and the entries read so far stay
+    /// inserted.
+    pub fn load_from<R: Read>(&self, mut r: R) -> io::Result<usize> {
+        let count = snapshot::read_len(&mut r)?;
+        for _ in 0..count {
+            let key = snapshot::read_item(&mut r)?;
+            let value = snapshot::read_item(&mut r)?;
+            let _ = self.insert(key, value);
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<K: Eq + Hash + Clone, V: Clone> Cache<K, V> {
+    /// Async version of `get_or_insert_with`.
+    ///
+    /// Concurrent calls with the same key await a single run of `f`, and calls with different keys
+    /// do not block each other. The values are cached separately from those of the blocking API.
+    pub async fn get_or_insert_with_async<Fut, F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce(K) -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cell = {
+            let mut write_lock = self.poison.unwrap(self.async_inner.write());
+            Arc::clone(write_lock.entry(key.clone()).or_default())
+        };
+        cell.get_or_init(|| f(key)).await.clone()
+    }
 }
@@ -2,12 +2,23 @@
 
 mod cache;
 mod handler;
+mod sharded_cache;
+#[cfg(feature = "snapshot")]
+mod snapshot;
 mod statistics;
 mod tcp;
 mod thread_pool;
+mod tiered_cache;
 
-pub use cache::Cache;
+pub use cache::{Cache, EntryInfo, EvictionPolicy, Loader, StampedeMode, SweeperHandle, Timeout};
 pub use handler::Handler;
+pub use sharded_cache::ShardedCache;
+#[cfg(feature = "snapshot")]
+pub use snapshot::Persist;
 pub use statistics::{Report, Statistics};
 pub use tcp::CancellableTcpListener;
-pub use thread_pool::ThreadPool;
+pub use thread_pool::{
+    BlockingPool, CancelToken, JobToken, LabelStats, NoSuchWorker, PoolEvent, PoolMetrics,
+    ThreadPool, ThreadPoolBuilder, TryScope,
+};
+pub use tiered_cache::L1L2Cache;
@@ -0,0 +1,95 @@
+//! Key/value cache split into independently locked shards.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+
+use super::Cache;
+use crate::PoisonPolicy;
+
+/// Cache that distributes the keys over several [`Cache`]s by their hash.
+///
+/// Operations on keys of different shards never contend on the same lock, and each shard evicts
+/// independently of the others.
+#[derive(Debug)]
+pub struct ShardedCache<K, V> {
+    shards: Vec<Cache<K, V>>,
+    hasher: RandomState,
+}
+
+impl<K, V> ShardedCache<K, V> {
+    /// Number of shards of `ShardedCache::default()`.
+    pub const DEFAULT_SHARDS: usize = 16;
+
+    /// Creates a new cache with `shards` unbounded shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is 0.
+    pub fn new(shards: usize) -> Self {
+        Self::from_shards(
+            shards,
+            || Cache::with_poison_policy(PoisonPolicy::default()),
+        )
+    }
+
+    /// Creates a new cache with `shards` shards that hold at most `per_shard_cap` entries each, for
+    /// a total capacity of `shards * per_shard_cap`.
+    ///
+    /// Each shard evicts an entry that was not hit recently when it is full, so there is no global
+    /// eviction lock. The price is a mild imbalance: a hot shard evicts sooner than a cold one, even if
+    /// the cache as a whole is not full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` or `per_shard_cap` is 0.
+    pub fn with_capacity(shards: usize, per_shard_cap: usize) -> Self {
+        Self::from_shards(shards, || Cache::new().with_capacity(per_shard_cap))
+    }
+
+    fn from_shards(shards: usize, shard: impl Fn() -> Cache<K, V>) -> Self {
+        assert!(shards > 0);
+        Self {
+            shards: (0..shards).map(|_| shard()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Returns the number of shards.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the number of entries of the shard `index`.
+    pub fn shard_len(&self, index: usize) -> usize {
+        self.shards[index].len()
+    }
+
+    /// Returns the total number of entries.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(Cache::len).sum()
+    }
+
+    /// Returns `true` if the cache has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(Cache::is_empty)
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> ShardedCache<K, V> {
+    /// Returns the index of the shard that holds `key`.
+    pub fn shard_of(&self, key: &K) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    /// Retrieve the value or insert a new one created by `f`, with the same guarantees as
+    /// [`Cache::get_or_insert_with`].
+    pub fn get_or_insert_with<F: FnOnce(K) -> V>(&self, key: K, f: F) -> V {
+        self.shards[self.shard_of(&key)].get_or_insert_with(key, f)
+    }
+}
+
+impl<K, V> Default for ShardedCache<K, V> {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_SHARDS)
+    }
+}
@@ -0,0 +1,81 @@
+//! Binary format of cache snapshots. See [`Cache::snapshot_to`](super::Cache::snapshot_to).
+//!
+//! A snapshot is the number of entries followed by the entries, each of which is its key and then
+//! its value. Numbers are little-endian `u64`s, and keys and values are length-prefixed byte
+//! strings produced by [`Persist`].
+
+use std::io::{self, Read, Write};
+
+/// Types that can be stored in a cache snapshot.
+pub trait Persist: Sized {
+    /// Encodes `self` to bytes.
+    fn encode(&self) -> Vec<u8>;
+
+    /// Decodes a value encoded by `encode`. Returns `None` if `bytes` is not a valid encoding.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl Persist for Vec<u8> {
+    fn encode(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl Persist for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+macro_rules! impl_persist_int {
+    ($($t:ty),*) => {
+        $(
+            impl Persist for $t {
+                fn encode(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn decode(bytes: &[u8]) -> Option<Self> {
+                    Some(Self::from_le_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_persist_int!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+pub(super) fn write_len<W: Write>(w: &mut W, len: usize) -> io::Result<()> {
+    w.write_all(&(len as u64).to_le_bytes())
+}
+
+pub(super) fn read_len<R: Read>(r: &mut R) -> io::Result<usize> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    usize::try_from(u64::from_le_bytes(bytes))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "length overflows usize"))
+}
+
+pub(super) fn write_item<W: Write, T: Persist>(w: &mut W, item: &T) -> io::Result<()> {
+    let bytes = item.encode();
+    write_len(w, bytes.len())?;
+    w.write_all(&bytes)
+}
+
+pub(super) fn read_item<R: Read, T: Persist>(r: &mut R) -> io::Result<T> {
+    let len = read_len(r)?;
+    let mut bytes = Vec::new();
+    let read = r.take(len as u64).read_to_end(&mut bytes)?;
+    if read < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    T::decode(&bytes).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid item"))
+}
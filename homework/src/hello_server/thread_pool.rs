@@ -1,16 +1,136 @@
 //! Thread pool that joins all thread when dropped.
 
-// NOTE: Crossbeam channels are MPMC, which means that you don't need to wrap the receiver in
-// Arc<Mutex<..>>. Just clone the receiver and give it to each worker thread.
-use crossbeam_channel::{unbounded, Sender};
-use std::sync::{Arc, Condvar, Mutex};
+// NOTE: Each worker has its own local queue, and jobs that do not fit into the local queues overflow
+// to a global queue. An idle worker first drains its local queue, then steals from the global queue,
+// and finally steals from its siblings. This avoids contention on a single shared channel.
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::iter;
+use std::mem;
+use std::ops::RangeInclusive;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{unbounded, Sender};
+use crossbeam_epoch::{pin, Atomic, Owned};
+
+/// Soft limit on the length of each local queue. Jobs overflow to the global queue beyond it.
+const LOCAL_QUEUE_CAPACITY: usize = 16;
+
+/// Number of the latest panic messages kept for `ThreadPool::take_panics`.
+const PANIC_HISTORY: usize = 64;
+
+struct Job {
+    run: Box<dyn FnOnce() + Send + 'static>,
+    /// Label of `execute_labeled`, under which the job is counted in `label_metrics`.
+    label: Option<&'static str>,
+}
+
+impl Job {
+    fn new<F: FnOnce() + Send + 'static>(f: F) -> Self {
+        Self {
+            run: Box::new(f),
+            label: None,
+        }
+    }
+}
+
+impl fmt::Debug for Job {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Job")
+            .field("label", &self.label)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Something that happened in a thread pool, reported to its logger.
+///
+/// The enum is non-exhaustive, as some variants only exist with some features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PoolEvent {
+    /// A job was submitted. `job_count` is the number of unfinished jobs including it.
+    JobSubmitted {
+        /// Number of unfinished jobs.
+        job_count: usize,
+    },
+    /// A worker started a job.
+    JobStarted {
+        /// Id of the worker, see [`ThreadPool::worker_ids`].
+        worker: usize,
+    },
+    /// A worker finished a job.
+    JobFinished {
+        /// Id of the worker, see [`ThreadPool::worker_ids`].
+        worker: usize,
+    },
+    /// A worker left its loop because it was retired or the pool is dropped.
+    WorkerTerminating {
+        /// Id of the worker, see [`ThreadPool::worker_ids`].
+        worker: usize,
+    },
+    /// A worker thread was joined, by the dropped pool or by a resize after the worker retired.
+    WorkerJoined {
+        /// Id of the worker, see [`ThreadPool::worker_ids`].
+        worker: usize,
+    },
+    /// A worker could not be pinned to its core of [`ThreadPoolBuilder::pin_to_cores`], because
+    /// the core is not available or the platform does not support pinning. It runs unpinned.
+    #[cfg(feature = "affinity")]
+    PinningFailed {
+        /// Id of the worker, see [`ThreadPool::worker_ids`].
+        worker: usize,
+        /// The core that it should have been pinned to.
+        core: usize,
+    },
+}
+
+/// Callback that receives the events of a thread pool.
+struct Logger(Box<dyn Fn(PoolEvent) + Send + Sync>);
+
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Logger").finish_non_exhaustive()
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self(Box::new(|_| {}))
+    }
+}
+
+type PanicFn = dyn Fn(&(dyn Any + Send)) + Send + Sync;
+
+/// Callback that receives the payloads of panicking jobs.
+struct PanicHandler(Box<PanicFn>);
+
+impl fmt::Debug for PanicHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PanicHandler").finish_non_exhaustive()
+    }
+}
+
+/// Callback that is called whenever the pool becomes idle.
+struct IdleHook(Box<dyn Fn() + Send + Sync>);
 
-struct Job(Box<dyn FnOnce() + Send + 'static>);
+impl fmt::Debug for IdleHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdleHook").finish_non_exhaustive()
+    }
+}
 
 #[derive(Debug)]
 struct Worker {
     _id: usize,
+    local: Arc<LocalQueue>,
+    /// Whether the worker is executing a job.
+    busy: Arc<AtomicBool>,
     thread: Option<thread::JoinHandle<()>>,
 }
 
@@ -20,44 +140,409 @@ impl Drop for Worker {
     ///
     /// NOTE: The thread is detached if not `join`ed explicitly.
     fn drop(&mut self) {
-        // println!("[worker {}] is terminating", self._id);
         if let Some(thread) = self.thread.take() {
             thread.join().unwrap();
-            // println!("[worker {}] joined", self._id)
         }
     }
 }
 
+/// Local job queue of a worker.
+#[derive(Debug, Default)]
+struct LocalQueue {
+    /// Id of the worker.
+    id: usize,
+    jobs: Mutex<VecDeque<Job>>,
+    /// Length of `jobs`, readable without taking the lock.
+    len: AtomicUsize,
+    /// Jobs submitted to this worker by `ThreadPool::execute_on`. Siblings do not steal them, and
+    /// they are not counted in `Queues::queued`.
+    pinned: Mutex<VecDeque<Job>>,
+    /// Whether the worker is asked to exit because the pool shrank.
+    retired: AtomicBool,
+}
+
+impl LocalQueue {
+    fn push_back(&self, job: Job) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.push_back(job);
+        self.len.store(jobs.len(), Ordering::Relaxed);
+    }
+
+    fn pop_front(&self) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.pop_front();
+        self.len.store(jobs.len(), Ordering::Relaxed);
+        job
+    }
+
+    fn pop_back(&self) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.pop_back();
+        self.len.store(jobs.len(), Ordering::Relaxed);
+        job
+    }
+}
+
+/// Job queues shared by the pool and the workers.
+#[derive(Debug)]
+struct Queues {
+    global: Mutex<VecDeque<Job>>,
+    /// Local queues of the current workers, in increasing order of their ids. Pushers hold the read
+    /// lock, so that no job is pushed to the queue of a retired worker.
+    locals: RwLock<Vec<Arc<LocalQueue>>>,
+    /// Number of jobs in all queues. It is incremented before a job is pushed, so it never
+    /// underestimates the number of jobs to be popped.
+    queued: AtomicUsize,
+    /// Whether the pool is shutting down. Idle workers wait for `wakeup` while holding this lock.
+    shutdown: Mutex<bool>,
+    /// Whether the workers are asked not to pop jobs. It is set and cleared under the `shutdown`
+    /// lock.
+    paused: AtomicBool,
+    wakeup: Condvar,
+    global_steals: AtomicUsize,
+    sibling_steals: AtomicUsize,
+}
+
+impl Queues {
+    fn new() -> Self {
+        Self {
+            global: Mutex::new(VecDeque::new()),
+            locals: RwLock::new(Vec::new()),
+            queued: AtomicUsize::new(0),
+            shutdown: Mutex::new(false),
+            paused: AtomicBool::new(false),
+            wakeup: Condvar::new(),
+            global_steals: AtomicUsize::new(0),
+            sibling_steals: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pushes the job to the least loaded local queue, or to the global queue if all local queues
+    /// are full. Then wakes up an idle worker. Returns the number of queued jobs including it.
+    fn push(&self, job: Job) -> usize {
+        let depth = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        {
+            let locals = self.locals.read().unwrap();
+            let local = locals
+                .iter()
+                .min_by_key(|local| local.len.load(Ordering::Relaxed))
+                .filter(|local| local.len.load(Ordering::Relaxed) < LOCAL_QUEUE_CAPACITY);
+            match local {
+                Some(local) => local.push_back(job),
+                None => self.global.lock().unwrap().push_back(job),
+            }
+        }
+        drop(self.shutdown.lock().unwrap());
+        self.wakeup.notify_one();
+        depth
+    }
+
+    /// Pops a job for the worker owning `local`: from its pinned jobs, then from its local queue,
+    /// then from the global queue, then from the siblings' local queues.
+    ///
+    /// Returns `None` if the pool is paused once the job is taken, as the job may have been
+    /// submitted after `pause` returned. The job is put back in that case.
+    fn pop(&self, local: &LocalQueue) -> Option<Job> {
+        {
+            let mut pinned = local.pinned.lock().unwrap();
+            if let Some(job) = pinned.pop_front() {
+                if self.paused.load(Ordering::SeqCst) {
+                    pinned.push_front(job);
+                    return None;
+                }
+                return Some(job);
+            }
+        }
+        let job = local
+            .pop_front()
+            .or_else(|| {
+                let job = self.global.lock().unwrap().pop_front()?;
+                let _ = self.global_steals.fetch_add(1, Ordering::Relaxed);
+                Some(job)
+            })
+            .or_else(|| {
+                let locals = self.locals.read().unwrap();
+                let job = locals
+                    .iter()
+                    .filter(|sibling| !ptr::eq(&***sibling, local))
+                    .find_map(|sibling| sibling.pop_back())?;
+                let _ = self.sibling_steals.fetch_add(1, Ordering::Relaxed);
+                Some(job)
+            })?;
+        if self.paused.load(Ordering::SeqCst) {
+            self.global.lock().unwrap().push_front(job);
+            return None;
+        }
+        let _ = self.queued.fetch_sub(1, Ordering::SeqCst);
+        Some(job)
+    }
+
+    /// Blocks until a job is available for the worker owning `local` and the pool is not paused.
+    /// Returns `None` if the worker is retired, or if the pool is shutting down and there are no
+    /// more jobs.
+    fn wait_pop(&self, local: &LocalQueue) -> Option<Job> {
+        loop {
+            if local.retired.load(Ordering::SeqCst) {
+                return None;
+            }
+            if !self.paused.load(Ordering::SeqCst) {
+                if let Some(job) = self.pop(local) {
+                    return Some(job);
+                }
+            }
+            let mut shutdown = self.shutdown.lock().unwrap();
+            while (self.paused.load(Ordering::SeqCst)
+                || (self.queued.load(Ordering::SeqCst) == 0
+                    && local.pinned.lock().unwrap().is_empty()))
+                && !local.retired.load(Ordering::SeqCst)
+            {
+                if *shutdown {
+                    return None;
+                }
+                shutdown = self.wakeup.wait(shutdown).unwrap();
+            }
+        }
+    }
+
+    /// Retires the worker owning the last local queue. Its pending jobs, including the pinned ones,
+    /// are moved to the global queue.
+    fn retire_last(&self) {
+        let local = self.locals.write().unwrap().pop().unwrap();
+        local.retired.store(true, Ordering::SeqCst);
+        let mut global = self.global.lock().unwrap();
+        global.extend(local.jobs.lock().unwrap().drain(..));
+        let mut pinned = local.pinned.lock().unwrap();
+        let _ = self.queued.fetch_add(pinned.len(), Ordering::SeqCst);
+        global.extend(pinned.drain(..));
+        drop((pinned, global));
+        drop(self.shutdown.lock().unwrap());
+        self.wakeup.notify_all();
+    }
+
+    /// Stops the workers from popping jobs. Once this returns, no job submitted afterwards is popped
+    /// until `resume`.
+    fn pause(&self) {
+        let shutdown = self.shutdown.lock().unwrap();
+        self.paused.store(true, Ordering::SeqCst);
+        drop(shutdown);
+    }
+
+    /// Lets the workers pop jobs again.
+    fn resume(&self) {
+        let shutdown = self.shutdown.lock().unwrap();
+        self.paused.store(false, Ordering::SeqCst);
+        drop(shutdown);
+        self.wakeup.notify_all();
+    }
+
+    /// Lets the workers exit once all queues are empty. A paused pool is resumed, so that the
+    /// pending jobs are executed.
+    fn shutdown(&self) {
+        let mut shutdown = self.shutdown.lock().unwrap();
+        *shutdown = true;
+        self.paused.store(false, Ordering::SeqCst);
+        drop(shutdown);
+        self.wakeup.notify_all();
+    }
+}
+
 /// Internal data structure for tracking the current job status. This is shared by worker closures
 /// via `Arc` so that the workers can report to the pool that it started/finished a job.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct ThreadPoolInner {
     job_count: Mutex<usize>,
     empty_condvar: Condvar,
+    queues: Queues,
+    /// Workers of the pool, including retired ones that have not been joined yet.
+    workers: Mutex<Vec<Worker>>,
+    /// Id of the next spawned worker. Ids are not reused.
+    next_worker_id: AtomicUsize,
+    /// Number of workers that are executing a job.
+    active_count: AtomicUsize,
+    /// Number of jobs executed since the last reset, including the ones that panicked.
+    completed_jobs: AtomicU64,
+    /// The callbacks are replaced at runtime, and read without taking a lock. A replaced one is
+    /// freed once no thread may be calling it.
+    logger: Atomic<Logger>,
+    /// If not null, panics of jobs are caught and reported to it instead of killing the worker.
+    panic_handler: Atomic<PanicHandler>,
+    /// Called when the job count drops to 0.
+    on_idle: Option<IdleHook>,
+    /// Notified once, and then dropped, when the job count next drops to 0.
+    idle_senders: Mutex<Vec<Sender<()>>>,
+    /// Messages of the latest caught panics, oldest first.
+    panics: Mutex<VecDeque<String>>,
+    /// Counters of the labels of `execute_labeled`.
+    labels: Mutex<HashMap<&'static str, LabelStats>>,
+    /// Cores of `ThreadPoolBuilder::pin_to_cores`, where the worker with id `i` is pinned to
+    /// `cores[i % cores.len()]`. Empty if the workers are not pinned.
+    #[cfg(feature = "affinity")]
+    cores: Vec<usize>,
 }
 
 impl ThreadPoolInner {
-    fn new() -> Self {
+    fn new(logger: Logger, panic_handler: Option<PanicHandler>, on_idle: Option<IdleHook>) -> Self {
         Self {
             job_count: Mutex::new(0),
             empty_condvar: Condvar::new(),
+            queues: Queues::new(),
+            workers: Mutex::new(Vec::new()),
+            next_worker_id: AtomicUsize::new(0),
+            active_count: AtomicUsize::new(0),
+            completed_jobs: AtomicU64::new(0),
+            logger: Atomic::new(logger),
+            panic_handler: panic_handler.map_or_else(Atomic::null, Atomic::new),
+            on_idle,
+            idle_senders: Mutex::new(Vec::new()),
+            panics: Mutex::new(VecDeque::new()),
+            labels: Mutex::new(HashMap::new()),
+            #[cfg(feature = "affinity")]
+            cores: Vec::new(),
+        }
+    }
+
+    /// Pins the current thread, which is the worker with id `worker`, to its core, if any.
+    #[cfg(feature = "affinity")]
+    fn pin_worker(&self, worker: usize) {
+        if self.cores.is_empty() {
+            return;
+        }
+        let core = self.cores[worker % self.cores.len()];
+        // Setting an unavailable core may panic instead of failing, so it is checked first.
+        let available = core_affinity::get_core_ids()
+            .is_some_and(|cores| cores.iter().any(|available| available.id == core));
+        if !available || !core_affinity::set_for_current(core_affinity::CoreId { id: core }) {
+            self.log(PoolEvent::PinningFailed { worker, core });
         }
     }
+
+    /// Reports `event` to the logger.
+    fn log(&self, event: PoolEvent) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(event = ?event);
+        let guard = pin();
+        let logger = self.logger.load(Ordering::Acquire, &guard);
+        (unsafe { logger.deref() }.0)(event);
+    }
+
+    /// Replaces the callback in `callback` with `value`.
+    fn replace_callback<T>(callback: &Atomic<T>, value: T) {
+        let guard = pin();
+        let old = callback.swap(Owned::new(value), Ordering::AcqRel, &guard);
+        if !old.is_null() {
+            // Other threads may still be calling the old one.
+            unsafe { guard.defer_destroy(old) };
+        }
+    }
+
+    /// Runs `run`, and reports its panic, if any, to the panic handler. If there is no panic
+    /// handler, the panic is not caught.
+    fn run_job(&self, run: Box<dyn FnOnce() + Send>) {
+        if self.panic_handler.load(Ordering::Acquire, &pin()).is_null() {
+            return run();
+        }
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(run)) {
+            self.record_panic(&*payload);
+            // The panic handler is never unset, but it may have been replaced.
+            let guard = pin();
+            let handler = self.panic_handler.load(Ordering::Acquire, &guard);
+            (unsafe { handler.deref() }.0)(&*payload);
+        }
+    }
+
+    /// Records the message of a caught panic, forgetting the oldest one if there are too many.
+    fn record_panic(&self, payload: &(dyn Any + Send)) {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            (*message).to_owned()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Box<dyn Any>".to_owned()
+        };
+        let mut panics = self.panics.lock().unwrap();
+        if panics.len() == PANIC_HISTORY {
+            let _ = panics.pop_front();
+        }
+        panics.push_back(message);
+    }
+
+    /// Count, report, and queue a job. Returns the number of queued jobs including it.
+    fn submit(&self, job: Job) -> usize {
+        self.start_job();
+        self.queues.push(job)
+    }
+
     /// Increment the job count.
     fn start_job(&self) {
+        let job_count = self.count_job();
+        self.log(PoolEvent::JobSubmitted { job_count });
+    }
+
+    /// Increment the job count without reporting it, and return the new count. The caller reports
+    /// the submission once it holds no lock, so that the logger may use the pool.
+    fn count_job(&self) -> usize {
         let mut cnt = self.job_count.lock().unwrap();
         *cnt += 1;
-        // println!("[tpool] add (job count: {})", *cnt);
+        *cnt
     }
 
-    /// Decrement the job count.
+    /// Decrement the job count, and call the idle hook if it drops to 0.
     fn finish_job(&self) {
         let mut cnt = self.job_count.lock().unwrap();
         *cnt -= 1;
-        if *cnt == 0 {
-            self.empty_condvar.notify_all(); // Notify all waiting threads that job count is 0
+        if *cnt != 0 {
+            return;
         }
-        // println!("[tpool] finish (job count: {})", *cnt);
+        self.empty_condvar.notify_all(); // Notify all waiting threads that job count is 0
+        let idle_senders = mem::take(&mut *self.idle_senders.lock().unwrap());
+
+        // The hook may submit jobs, which takes the lock.
+        drop(cnt);
+        for tx in idle_senders {
+            let _ = tx.send(());
+        }
+        if let Some(on_idle) = &self.on_idle {
+            (on_idle.0)();
+        }
+    }
+
+    /// Submits `job` to the pinned jobs of the worker with id `worker`.
+    fn submit_pinned(&self, worker: usize, job: Job) -> Result<(), NoSuchWorker> {
+        let job_count = self.push_pinned(worker, job)?;
+        self.log(PoolEvent::JobSubmitted { job_count });
+        Ok(())
+    }
+
+    /// Like `submit_pinned`, but returns the job count instead of reporting the submission.
+    fn push_pinned(&self, worker: usize, job: Job) -> Result<usize, NoSuchWorker> {
+        let job_count = {
+            // The read lock keeps the worker from being retired until the job is pushed.
+            let locals = self.queues.locals.read().unwrap();
+            let local = locals
+                .iter()
+                .find(|local| local.id == worker)
+                .ok_or(NoSuchWorker)?;
+            let job_count = self.count_job();
+            local.pinned.lock().unwrap().push_back(job);
+            job_count
+        };
+        drop(self.queues.shutdown.lock().unwrap());
+        // The idle workers share the condvar, so wake all of them to make sure the target is.
+        self.queues.wakeup.notify_all();
+        Ok(job_count)
+    }
+
+    /// Submits a job that runs `f` and resubmits itself as long as `f` returns `true`.
+    ///
+    /// The resubmitted job is counted before the current one finishes, so the job count does not
+    /// drop to 0 between two runs.
+    fn submit_recurring(self: &Arc<Self>, mut f: Box<dyn FnMut() -> bool + Send>) {
+        let pool_inner = Arc::clone(self);
+        let _ = self.submit(Job::new(move || {
+            if f() {
+                pool_inner.submit_recurring(f);
+            }
+        }));
     }
 
     /// Wait until the job count becomes 0.
@@ -70,14 +555,629 @@ impl ThreadPoolInner {
             cnt = self.empty_condvar.wait(cnt).unwrap();
         }
     }
+
+    /// Wait until the job count becomes 0 or `timeout` elapses, and return the job count.
+    fn wait_empty_timeout(&self, timeout: Duration) -> usize {
+        let cnt = self.job_count.lock().unwrap();
+        let (cnt, _) = self
+            .empty_condvar
+            .wait_timeout_while(cnt, timeout, |cnt| *cnt > 0)
+            .unwrap();
+        *cnt
+    }
+
+    fn size(&self) -> usize {
+        self.queues.locals.read().unwrap().len()
+    }
+
+    fn current_load(&self) -> f64 {
+        self.active_count.load(Ordering::Relaxed) as f64 / self.size() as f64
+    }
+
+    /// Spawns or retires workers until there are `size` of them. The retired workers that have
+    /// exited are joined, so that they do not pile up while the pool is resized repeatedly.
+    fn set_size(self: &Arc<Self>, size: usize) {
+        assert!(size > 0);
+        let mut workers = self.workers.lock().unwrap();
+        let (exited, running): (Vec<_>, Vec<_>) =
+            mem::take(&mut *workers).into_iter().partition(|worker| {
+                worker.local.retired.load(Ordering::SeqCst)
+                    && worker.thread.as_ref().is_some_and(|t| t.is_finished())
+            });
+        *workers = running;
+        while self.size() < size {
+            let id = self.next_worker_id.fetch_add(1, Ordering::Relaxed);
+            let local = Arc::new(LocalQueue {
+                id,
+                ..LocalQueue::default()
+            });
+            self.queues.locals.write().unwrap().push(Arc::clone(&local));
+            let worker_local = Arc::clone(&local);
+            let pool_inner = Arc::clone(self);
+            let busy = Arc::new(AtomicBool::new(false));
+            let worker_busy = Arc::clone(&busy);
+            let handle = thread::spawn(move || {
+                #[cfg(feature = "affinity")]
+                pool_inner.pin_worker(id);
+                // `None` will happen if the worker is retired, or if the pool is dropped and all
+                // queues are drained.
+                while let Some(Job { run, label }) = pool_inner.queues.wait_pop(&local) {
+                    pool_inner.log(PoolEvent::JobStarted { worker: id });
+                    busy.store(true, Ordering::Relaxed);
+                    let _ = pool_inner.active_count.fetch_add(1, Ordering::Relaxed);
+                    if let Some(label) = label {
+                        pool_inner
+                            .labels
+                            .lock()
+                            .unwrap()
+                            .entry(label)
+                            .or_default()
+                            .active += 1;
+                    }
+                    pool_inner.run_job(run);
+                    let _ = pool_inner.active_count.fetch_sub(1, Ordering::Relaxed);
+                    busy.store(false, Ordering::Relaxed);
+                    let _ = pool_inner.completed_jobs.fetch_add(1, Ordering::Relaxed);
+                    if let Some(label) = label {
+                        let mut labels = pool_inner.labels.lock().unwrap();
+                        let stats = labels.get_mut(label).unwrap();
+                        stats.active -= 1;
+                        stats.completed += 1;
+                    }
+                    pool_inner.log(PoolEvent::JobFinished { worker: id });
+                    pool_inner.finish_job();
+                }
+                pool_inner.log(PoolEvent::WorkerTerminating { worker: id });
+            });
+            workers.push(Worker {
+                _id: id,
+                local: worker_local,
+                busy: worker_busy,
+                thread: Some(handle),
+            });
+        }
+        while self.size() > size {
+            self.queues.retire_last();
+        }
+        drop(workers);
+        for worker in exited {
+            let id = worker._id;
+            drop(worker);
+            self.log(PoolEvent::WorkerJoined { worker: id });
+        }
+    }
+}
+
+impl Drop for ThreadPoolInner {
+    fn drop(&mut self) {
+        // No thread can call the callbacks anymore.
+        drop(unsafe { mem::take(&mut self.logger).try_into_owned() });
+        drop(unsafe { mem::take(&mut self.panic_handler).try_into_owned() });
+    }
+}
+
+/// Background thread that resizes a pool to keep its load within a target band.
+#[derive(Debug)]
+struct Autoscaler {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Autoscaler {
+    /// How often the load is sampled.
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    fn start(
+        pool_inner: Arc<ThreadPoolInner>,
+        min: usize,
+        max: usize,
+        target: RangeInclusive<f64>,
+    ) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let (stopped, condvar) = &*stop;
+                let mut stopped = stopped.lock().unwrap();
+                while !*stopped {
+                    let size = pool_inner.size();
+                    let load = pool_inner.current_load();
+                    if size < min || (load > *target.end() && size < max) {
+                        pool_inner.set_size(size + 1);
+                    } else if size > max || (load < *target.start() && size > min) {
+                        pool_inner.set_size(size - 1);
+                    }
+                    stopped = condvar.wait_timeout(stopped, Self::INTERVAL).unwrap().0;
+                }
+            })
+        };
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Autoscaler {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Snapshot of the counters of a thread pool.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Number of jobs taken from the global overflow queue.
+    pub global_steals: usize,
+    /// Number of jobs taken from another worker's local queue.
+    pub sibling_steals: usize,
+}
+
+impl PoolMetrics {
+    /// Total number of jobs that were not taken from the worker's own local queue.
+    pub fn steals(&self) -> usize {
+        self.global_steals + self.sibling_steals
+    }
+}
+
+/// Counters of the jobs of a label of [`ThreadPool::execute_labeled`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LabelStats {
+    /// Number of jobs that are executing.
+    pub active: usize,
+    /// Number of jobs that finished since the label was first used or the counters were reset.
+    pub completed: u64,
+}
+
+/// Error returned by [`ThreadPool::execute_on`] when no current worker of the pool has the given
+/// id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoSuchWorker;
+
+/// Completion signal shared by a `join_any` call and the jobs it waits for. It holds the index of
+/// the first job that finished.
+type AnySignal = (Mutex<Option<usize>>, Condvar);
+
+/// Completion status of a tracked job.
+#[derive(Debug, Default)]
+struct JobState {
+    finished: bool,
+    /// Signals of the `join_any` calls waiting for the job, with the index of the job in each call.
+    watchers: Vec<(usize, Arc<AnySignal>)>,
+}
+
+/// Handle of a job submitted by [`ThreadPool::execute_tracked`].
+#[derive(Debug, Clone)]
+pub struct JobToken {
+    done: Arc<(Mutex<JobState>, Condvar)>,
+}
+
+impl JobToken {
+    /// Returns `true` if the job has been executed.
+    pub fn is_done(&self) -> bool {
+        self.done.0.lock().unwrap().finished
+    }
+
+    /// Block the current thread until the job has been executed.
+    pub fn wait(&self) {
+        let (state, condvar) = &*self.done;
+        let mut state = state.lock().unwrap();
+        while !state.finished {
+            state = condvar.wait(state).unwrap();
+        }
+    }
+
+    /// Marks the job as executed, and wakes up its waiters.
+    fn finish(&self) {
+        let (state, condvar) = &*self.done;
+        let mut state = state.lock().unwrap();
+        state.finished = true;
+        let watchers = mem::take(&mut state.watchers);
+        drop(state);
+        condvar.notify_all();
+        for (index, signal) in watchers {
+            let _ = signal.0.lock().unwrap().get_or_insert(index);
+            signal.1.notify_all();
+        }
+    }
+}
+
+/// Marks the job of a token as executed when dropped, so that its waiters are woken up even if
+/// the job panics.
+struct FinishOnDrop(JobToken);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+/// Flag that tells the tasks of a [`ThreadPool::try_scope`] to stop early because a sibling failed.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Returns `true` if the tasks are cancelled. Long-running tasks should check it periodically
+    /// and return early.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Results of the tasks of a `TryScope`.
+#[derive(Debug)]
+struct ScopeResults<T, E> {
+    /// Values of the tasks in the order they were spawned. `None` until the task succeeds.
+    values: Vec<Option<T>>,
+    /// The first error returned by a task.
+    error: Option<E>,
+    /// Payload of the first panic of a task.
+    panic: Option<Box<dyn Any + Send>>,
+    /// Number of tasks that did not finish yet.
+    pending: usize,
+}
+
+/// Handle to spawn the tasks of a [`ThreadPool::try_scope`].
+pub struct TryScope<'p, T, E> {
+    pool: &'p ThreadPool,
+    token: CancelToken,
+    results: Arc<(Mutex<ScopeResults<T, E>>, Condvar)>,
+}
+
+impl<T, E> fmt::Debug for TryScope<'_, T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TryScope")
+            .field("token", &self.token)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Send + 'static, E: Send + 'static> TryScope<'_, T, E> {
+    /// Spawns a task in the pool. The task receives the cancellation token of the scope.
+    ///
+    /// A task that has not started when the scope is cancelled is skipped.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: FnOnce(&CancelToken) -> Result<T, E> + Send + 'static,
+    {
+        let index = {
+            let mut results = self.results.0.lock().unwrap();
+            results.values.push(None);
+            results.pending += 1;
+            results.values.len() - 1
+        };
+        let token = self.token.clone();
+        let shared = Arc::clone(&self.results);
+        self.pool.execute(move || {
+            let result = (!token.is_cancelled())
+                .then(|| panic::catch_unwind(AssertUnwindSafe(|| task(&token))));
+            let (results, condvar) = &*shared;
+            let mut results = results.lock().unwrap();
+            match result {
+                Some(Ok(Ok(value))) => results.values[index] = Some(value),
+                Some(Ok(Err(error))) => {
+                    let _ = results.error.get_or_insert(error);
+                    token.cancel();
+                }
+                Some(Err(payload)) => {
+                    let _ = results.panic.get_or_insert(payload);
+                    token.cancel();
+                }
+                None => {}
+            }
+            results.pending -= 1;
+            if results.pending == 0 {
+                condvar.notify_all();
+            }
+        });
+    }
+}
+
+/// Builder for a [`ThreadPool`] with non-default settings.
+#[derive(Debug)]
+pub struct ThreadPoolBuilder {
+    size: usize,
+    max_blocking: usize,
+    blocking_keep_alive: Duration,
+    logger: Logger,
+    panic_handler: Option<PanicHandler>,
+    on_idle: Option<IdleHook>,
+    #[cfg(feature = "affinity")]
+    cores: Vec<usize>,
+}
+
+impl ThreadPoolBuilder {
+    /// Default value of [`blocking_threads`](Self::blocking_threads).
+    pub const DEFAULT_BLOCKING_THREADS: usize = 64;
+
+    /// Default value of [`blocking_keep_alive`](Self::blocking_keep_alive).
+    pub const DEFAULT_BLOCKING_KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+    /// Starts building a pool with `size` threads.
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            max_blocking: Self::DEFAULT_BLOCKING_THREADS,
+            blocking_keep_alive: Self::DEFAULT_BLOCKING_KEEP_ALIVE,
+            logger: Logger::default(),
+            panic_handler: None,
+            on_idle: None,
+            #[cfg(feature = "affinity")]
+            cores: Vec::new(),
+        }
+    }
+
+    /// Bounds the number of threads of the pool for [`ThreadPool::blocking`] jobs to `max`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn blocking_threads(mut self, max: usize) -> Self {
+        assert!(max > 0);
+        self.max_blocking = max;
+        self
+    }
+
+    /// Lets a thread of the pool for [`ThreadPool::blocking`] jobs exit once it has been idle for
+    /// `keep_alive`.
+    pub fn blocking_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.blocking_keep_alive = keep_alive;
+        self
+    }
+
+    /// Reports the events of the pool to `logger`, which is called on the thread where each
+    /// event happens. By default, events are discarded.
+    pub fn logger(mut self, logger: Box<dyn Fn(PoolEvent) + Send + Sync>) -> Self {
+        self.logger = Logger(logger);
+        self
+    }
+
+    /// Catches the panics of jobs and reports their payloads to `handler`, so that the worker keeps
+    /// running. By default, a panicking job kills its worker, and the panic is propagated when the
+    /// pool is dropped.
+    pub fn panic_handler(mut self, handler: Box<PanicFn>) -> Self {
+        self.panic_handler = Some(PanicHandler(handler));
+        self
+    }
+
+    /// Calls `on_idle` each time the pool becomes idle, i.e., when the last unfinished job
+    /// finishes, e.g., to flush the output of a batch of jobs.
+    ///
+    /// It is called once per transition, on the worker that finished the last job, after the
+    /// threads waiting in `join` are woken up. It may submit jobs, which starts a new batch.
+    pub fn on_idle(mut self, on_idle: Box<dyn Fn() + Send + Sync>) -> Self {
+        self.on_idle = Some(IdleHook(on_idle));
+        self
+    }
+
+    /// Pins the workers to `cores` when they start, which reduces the cache misses of steady
+    /// pipelines that would otherwise move between cores. The worker with id `i` is pinned to
+    /// `cores[i % cores.len()]`, including the workers added later by resizing.
+    ///
+    /// If a core is not available, e.g., it is out of range or the platform does not support
+    /// pinning, the worker runs unpinned, and [`PoolEvent::PinningFailed`] is reported.
+    #[cfg(feature = "affinity")]
+    pub fn pin_to_cores(mut self, cores: Vec<usize>) -> Self {
+        self.cores = cores;
+        self
+    }
+
+    /// Creates the pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size is 0.
+    pub fn build(self) -> ThreadPool {
+        assert!(self.size > 0);
+        #[cfg_attr(not(feature = "affinity"), allow(unused_mut))]
+        let mut pool_inner = ThreadPoolInner::new(self.logger, self.panic_handler, self.on_idle);
+        #[cfg(feature = "affinity")]
+        {
+            pool_inner.cores = self.cores;
+        }
+        let pool_inner = Arc::new(pool_inner);
+        pool_inner.set_size(self.size);
+        ThreadPool {
+            pool_inner,
+            autoscaler: Mutex::new(None),
+            blocking: BlockingPool::new(self.max_blocking, self.blocking_keep_alive),
+            broadcast: Mutex::new(()),
+        }
+    }
+}
+
+/// Pool of the threads that run [`ThreadPool::blocking`] jobs.
+///
+/// It starts without threads. A thread is spawned when a job is submitted while no thread is idle,
+/// up to [`ThreadPoolBuilder::blocking_threads`], and the jobs beyond that are queued. A thread
+/// exits once it has been idle for [`ThreadPoolBuilder::blocking_keep_alive`].
+///
+/// A panic of a job does not stop its thread. The first one is propagated when the pool is dropped.
+#[derive(Debug)]
+pub struct BlockingPool {
+    inner: Arc<BlockingInner>,
+    /// Threads that may still be running. Exited ones are removed when a thread is spawned.
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+struct BlockingInner {
+    max: usize,
+    keep_alive: Duration,
+    state: Mutex<BlockingState>,
+    /// Notified when a job is queued or the pool is dropped. Idle threads wait for it.
+    wakeup: Condvar,
+    /// Notified when the number of unfinished jobs drops to 0.
+    empty: Condvar,
+    active: AtomicUsize,
+    completed_jobs: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct BlockingState {
+    queue: VecDeque<Job>,
+    /// Number of live threads.
+    size: usize,
+    /// Number of threads waiting for `wakeup`.
+    idle: usize,
+    /// Number of queued or running jobs.
+    unfinished: usize,
+    shutdown: bool,
+    /// Payload of the first panic of a job.
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl BlockingPool {
+    fn new(max: usize, keep_alive: Duration) -> Self {
+        Self {
+            inner: Arc::new(BlockingInner {
+                max,
+                keep_alive,
+                state: Mutex::new(BlockingState::default()),
+                wakeup: Condvar::new(),
+                empty: Condvar::new(),
+                active: AtomicUsize::new(0),
+                completed_jobs: AtomicU64::new(0),
+            }),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Queues `job`, and spawns a thread for it if every thread is busy.
+    fn execute(&self, job: Job) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.queue.push_back(job);
+        state.unfinished += 1;
+        // An idle thread that was woken up but has not taken a job yet is still counted as idle,
+        // so each queued job is matched with a distinct idle thread.
+        if state.queue.len() <= state.idle {
+            drop(state);
+            self.inner.wakeup.notify_one();
+            return;
+        }
+        if state.size == self.inner.max {
+            return;
+        }
+        state.size += 1;
+        drop(state);
+
+        let inner = Arc::clone(&self.inner);
+        let handle = thread::spawn(move || inner.run());
+        let mut threads = self.threads.lock().unwrap();
+        threads.retain(|thread| !thread.is_finished());
+        threads.push(handle);
+    }
+
+    /// Returns the number of threads.
+    pub fn size(&self) -> usize {
+        self.inner.state.lock().unwrap().size
+    }
+
+    /// Returns the number of threads that are executing a job.
+    pub fn active_count(&self) -> usize {
+        self.inner.active.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of jobs that finished, including the ones that panicked.
+    pub fn completed_jobs(&self) -> u64 {
+        self.inner.completed_jobs.load(Ordering::Relaxed)
+    }
+
+    /// Blocks the current thread until all jobs have been executed.
+    fn join(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        while state.unfinished > 0 {
+            state = self.inner.empty.wait(state).unwrap();
+        }
+    }
+
+    /// Like `join`, but gives up after `timeout`, and returns the number of unfinished jobs.
+    fn join_timeout(&self, timeout: Duration) -> usize {
+        let state = self.inner.state.lock().unwrap();
+        let (state, _) = self
+            .inner
+            .empty
+            .wait_timeout_while(state, timeout, |state| state.unfinished > 0)
+            .unwrap();
+        state.unfinished
+    }
+}
+
+impl BlockingInner {
+    /// Loop of a thread, which exits when it has been idle for `keep_alive`, or when the pool is
+    /// dropped and the queue is empty.
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(Job { run, .. }) = state.queue.pop_front() {
+                drop(state);
+                let _ = self.active.fetch_add(1, Ordering::Relaxed);
+                let result = panic::catch_unwind(AssertUnwindSafe(run));
+                let _ = self.active.fetch_sub(1, Ordering::Relaxed);
+                let _ = self.completed_jobs.fetch_add(1, Ordering::Relaxed);
+                state = self.state.lock().unwrap();
+                if let Err(payload) = result {
+                    let _ = state.panic.get_or_insert(payload);
+                }
+                state.unfinished -= 1;
+                if state.unfinished == 0 {
+                    self.empty.notify_all();
+                }
+                continue;
+            }
+            if state.shutdown {
+                break;
+            }
+            state.idle += 1;
+            let (next, timeout) = self.wakeup.wait_timeout(state, self.keep_alive).unwrap();
+            state = next;
+            state.idle -= 1;
+            if timeout.timed_out() && state.queue.is_empty() {
+                break;
+            }
+        }
+        state.size -= 1;
+    }
+}
+
+impl Drop for BlockingPool {
+    /// Lets the threads exit once the queued jobs are executed, and joins them. If a job panicked,
+    /// then this function panics too.
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().shutdown = true;
+        self.inner.wakeup.notify_all();
+        for thread in mem::take(&mut *self.threads.lock().unwrap()) {
+            thread.join().unwrap();
+        }
+        let panic = self.inner.state.lock().unwrap().panic.take();
+        if let Some(payload) = panic {
+            if !thread::panicking() {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
 }
 
 /// Thread pool.
 #[derive(Debug)]
 pub struct ThreadPool {
-    _workers: Vec<Worker>,
-    job_sender: Option<Sender<Job>>,
     pool_inner: Arc<ThreadPoolInner>,
+    autoscaler: Mutex<Option<Autoscaler>>,
+    /// Pool of `blocking` jobs.
+    blocking: BlockingPool,
+    /// Held by `broadcast`, so that concurrent calls run one after the other.
+    broadcast: Mutex<()>,
 }
 
 impl ThreadPool {
@@ -87,60 +1187,541 @@ impl ThreadPool {
     ///
     /// Panics if `size` is 0.
     pub fn new(size: usize) -> Self {
-        assert!(size > 0);
-        let (job_sender, job_receiver) = unbounded::<Job>();
-        let pool_inner = Arc::new(ThreadPoolInner::new());
-        let mut _workers = vec![];
-        for i in 0..size {
-            let job_receiver = job_receiver.clone();
-            let pool_inner = Arc::clone(&pool_inner);
-            let handle = thread::spawn(move || loop {
-                let job = job_receiver.recv();
-                match job {
-                    Ok(job) => {
-                        // pool_inner.start_job();
-                        // println!("[worker {}] starts a job", i);
-                        job.0();
-                        pool_inner.finish_job();
-                        // println!("[worker {}] finishes a job", i);
-                    }
-                    Err(crossbeam_channel::RecvError) => {
-                        // This will happen if all `ThreadPool` clones are dropped.
-                        // pool_inner_clone.wait_empty();
-                        break;
-                    }
-                }
-            });
-            _workers.push(Worker {
-                _id: i,
-                thread: Some(handle),
-            });
-        }
-        Self {
-            _workers,
-            pool_inner,
-            job_sender: Some(job_sender),
-        }
+        ThreadPoolBuilder::new(size).build()
     }
 
     /// Execute a new job in the thread pool.
     pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let _ = self.execute_queued(f);
+    }
+
+    /// Like [`execute`](Self::execute), but returns the number of jobs waiting to start right
+    /// after the job was queued, including it, so that a producer can slow down when it grows.
+    ///
+    /// The number is approximate, as the workers pop jobs concurrently. Jobs of
+    /// [`execute_on`](Self::execute_on) are not counted.
+    pub fn execute_queued<F>(&self, f: F) -> usize
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool_inner.submit(Job::new(f))
+    }
+
+    /// Execute a job that spends most of its time waiting, e.g., for I/O, in a separate pool.
+    ///
+    /// A flood of such jobs does not hold up the workers of this pool, so CPU-bound jobs submitted
+    /// with `execute` still make progress. See [`BlockingPool`] for how the separate pool grows and
+    /// shrinks. Its jobs are not reported to the logger or the panic handler of this pool.
+    pub fn blocking<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.blocking.execute(Job::new(f));
+    }
+
+    /// Returns the pool that runs the [`blocking`](Self::blocking) jobs, e.g., to read its size and
+    /// counters apart from the ones of this pool.
+    pub fn blocking_pool(&self) -> &BlockingPool {
+        &self.blocking
+    }
+
+    /// Execute a new job that is counted under `label` in [`label_metrics`](Self::label_metrics),
+    /// e.g., to see how many jobs of each kind a server is running.
+    pub fn execute_labeled<F>(&self, label: &'static str, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
         self.pool_inner.start_job();
-        if let Some(sender) = &self.job_sender {
-            sender
-                .send(Job(Box::new(f)))
-                .expect("Failed to send job to worker")
+        self.pool_inner.queues.push(Job {
+            run: Box::new(f),
+            label: Some(label),
+        });
+    }
+
+    /// Execute a new job on the worker with id `worker`, e.g. to reuse its thread-local state. See
+    /// [`worker_ids`](Self::worker_ids) for the ids.
+    ///
+    /// The job is not stolen by other workers. If the pool shrinks before the job starts and the
+    /// worker is retired, the job runs on another worker.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NoSuchWorker` if no current worker has the id `worker`, e.g., because it was
+    /// retired.
+    pub fn execute_on<F>(&self, worker: usize, f: F) -> Result<(), NoSuchWorker>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool_inner.submit_pinned(worker, Job::new(f))
+    }
+
+    /// Execute `f` in the thread pool repeatedly until it returns `false`. Each run is a separate
+    /// job, so other jobs can run in between, and `join` waits until `f` stops.
+    ///
+    /// The closure is boxed once and moved from one run to the next.
+    pub fn execute_recurring<F>(&self, f: F)
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        self.pool_inner.submit_recurring(Box::new(f));
+    }
+
+    /// Execute `f` in the thread pool, and then `g` with its result as a separate job.
+    ///
+    /// `g` is submitted like a job of [`execute`](Self::execute) once `f` returns, before the job
+    /// of `f` finishes, so `join` also waits for `g`. If `f` panics, `g` is not submitted.
+    pub fn execute_then<F, G, T>(&self, f: F, g: G)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        G: FnOnce(T) + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool_inner = Arc::clone(&self.pool_inner);
+        self.execute(move || {
+            let result = f();
+            let _ = pool_inner.submit(Job::new(move || g(result)));
+        });
+    }
+
+    /// Execute a new job in the thread pool, and return a token to wait for it.
+    ///
+    /// A job that panics also counts as executed, so that its waiters do not block forever.
+    pub fn execute_tracked<F>(&self, f: F) -> JobToken
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let token = JobToken {
+            done: Arc::default(),
+        };
+        let finish = FinishOnDrop(token.clone());
+        self.execute(move || {
+            let _finish = finish;
+            f();
+        });
+        token
+    }
+
+    /// Execute `f` in the thread pool and send its result to `tx`, so that the results of many jobs
+    /// can be received from one channel as they complete.
+    ///
+    /// The send is part of the job, so `join` also waits for it. If the receiver is dropped, the
+    /// result is discarded.
+    pub fn execute_to<F, T>(&self, tx: Sender<T>, f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.execute(move || {
+            let _ = tx.send(f());
+        });
+    }
+
+    /// Execute `f` on each of `inputs` in the thread pool, and return an iterator over the results
+    /// in the order of `inputs`.
+    ///
+    /// The iterator blocks until the next result in order is ready, so the results can be consumed
+    /// while later ones are still computing. Results that complete early are buffered.
+    ///
+    /// # Panics
+    ///
+    /// The iterator panics when it reaches the result of a job that panicked.
+    pub fn execute_ordered<I, F, T>(&self, inputs: I, f: F) -> impl Iterator<Item = T>
+    where
+        I: IntoIterator,
+        I::Item: Send + 'static,
+        F: Fn(I::Item) -> T + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let f = Arc::new(f);
+        let (tx, rx) = unbounded();
+        let mut count = 0;
+        for input in inputs {
+            let f = f.clone();
+            let index = count;
+            self.execute_to(tx.clone(), move || (index, f(input)));
+            count += 1;
+        }
+        // Lets `recv` fail instead of blocking forever if a job panics.
+        drop(tx);
+
+        let mut early = HashMap::new();
+        let mut next = 0;
+        iter::from_fn(move || {
+            if next == count {
+                return None;
+            }
+            let result = loop {
+                if let Some(result) = early.remove(&next) {
+                    break result;
+                }
+                let (index, result) = rx.recv().expect("a job of `execute_ordered` panicked");
+                if index == next {
+                    break result;
+                }
+                let _ = early.insert(index, result);
+            };
+            next += 1;
+            Some(result)
+        })
+    }
+
+    /// Maps `items` with `map` and folds the mapped values into the accumulator `identity` with
+    /// `reduce` in the thread pool, and returns the result, e.g., to sum a large collection.
+    ///
+    /// The items are split into one chunk per worker, and each job folds its chunk from `identity`.
+    /// The partial results are then converted back into mapped values and folded in the order of
+    /// the chunks, so unlike collecting the mapped values first, only one value per chunk is kept.
+    /// For the result to match the sequential fold, `reduce` must be associative, i.e., folding
+    /// values onto an accumulator must be the same as folding them from `identity` and reducing
+    /// the accumulator with the converted result, and `identity` must be its identity element.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `map` or `reduce` panics in a job.
+    pub fn map_reduce<I, M, R, F, G>(&self, items: I, map: F, identity: R, reduce: G) -> R
+    where
+        I: IntoIterator,
+        I::Item: Send + 'static,
+        R: Into<M> + Clone + Send + Sync + 'static,
+        F: Fn(I::Item) -> M + Send + Sync + 'static,
+        G: Fn(R, M) -> R + Send + Sync + 'static,
+    {
+        let mut items = items.into_iter().collect::<Vec<_>>().into_iter();
+        let chunk_size = items.len().div_ceil(self.size()).max(1);
+        let chunks = iter::from_fn(move || {
+            let chunk = items.by_ref().take(chunk_size).collect::<Vec<_>>();
+            (!chunk.is_empty()).then_some(chunk)
+        });
+
+        let ops = Arc::new((map, reduce, identity));
+        let partials = self.execute_ordered(chunks, {
+            let ops = ops.clone();
+            move |chunk| {
+                let (map, reduce, identity) = &*ops;
+                chunk
+                    .into_iter()
+                    .fold(identity.clone(), |acc, item| reduce(acc, map(item)))
+            }
+        });
+        let (_, reduce, identity) = &*ops;
+        partials.fold(identity.clone(), |acc, partial| reduce(acc, partial.into()))
+    }
+
+    /// Block the current thread until the jobs of `tokens` have been executed. Unlike `join`, other
+    /// jobs may still be pending when this returns.
+    pub fn wait_all(&self, tokens: &[JobToken]) {
+        for token in tokens {
+            token.wait();
+        }
+    }
+
+    /// Block the current thread until any of the jobs of `tokens` has been executed, and return
+    /// its index in `tokens`. If several of them are already done, the first one is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tokens` is empty.
+    pub fn join_any(&self, tokens: &[JobToken]) -> usize {
+        assert!(!tokens.is_empty(), "no jobs to wait for");
+        let signal = Arc::new(AnySignal::default());
+        for (index, token) in tokens.iter().enumerate() {
+            let mut state = token.done.0.lock().unwrap();
+            if state.finished {
+                return index;
+            }
+            state.watchers.push((index, Arc::clone(&signal)));
+        }
+        let (first, condvar) = &*signal;
+        let first = condvar
+            .wait_while(first.lock().unwrap(), |first| first.is_none())
+            .unwrap();
+        first.unwrap()
+    }
+
+    /// Run `f` once on every worker, and block the current thread until all of them have.
+    ///
+    /// A copy of the job is submitted to each worker like with [`execute_on`](Self::execute_on),
+    /// so it runs after the jobs already submitted to that worker, and concurrent calls run one
+    /// after the other. If `f` panics on a worker, the panic is propagated to the caller once all
+    /// workers are done. While the pool is paused, this blocks until it is resumed. The pool is not
+    /// resized while the copies are submitted, but if it shrinks before a copy starts, the copy
+    /// runs on another worker.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from a worker of the pool, which would wait for its own copy forever.
+    pub fn broadcast<F: Fn() + Send + Sync + 'static>(&self, f: F) {
+        let current = thread::current().id();
+        let on_worker = self
+            .pool_inner
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|worker| {
+                worker
+                    .thread
+                    .as_ref()
+                    .is_some_and(|thread| thread.thread().id() == current)
+            });
+        assert!(
+            !on_worker,
+            "`broadcast` was called from a worker of the pool"
+        );
+
+        let serialized = self.broadcast.lock().unwrap();
+        let f = Arc::new(f);
+        let (tx, rx) = unbounded();
+        let job_counts = {
+            // The lock keeps the workers from being spawned or retired meanwhile.
+            let _workers = self.pool_inner.workers.lock().unwrap();
+            self.worker_ids()
+                .into_iter()
+                .map(|worker| {
+                    let f = Arc::clone(&f);
+                    let tx = tx.clone();
+                    let job = Job::new(move || {
+                        let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(&*f)).err());
+                    });
+                    self.pool_inner.push_pinned(worker, job).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        for job_count in job_counts {
+            self.pool_inner.log(PoolEvent::JobSubmitted { job_count });
+        }
+        // The channel is disconnected once every copy has run.
+        drop(tx);
+        let mut payload = None;
+        for panicked in rx {
+            payload = payload.or(panicked);
+        }
+        drop(serialized);
+        if let Some(payload) = payload {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// Run `f` to spawn fallible tasks in the pool, and block the current thread until all of them
+    /// finish. Returns the values of the tasks in the order they were spawned, or the first error.
+    ///
+    /// The first error cancels the other tasks: the ones that have not started are skipped, and
+    /// the running ones observe it through their [`CancelToken`]. If a task panics, the others are
+    /// cancelled too, and the panic is propagated to the caller once all tasks are done.
+    pub fn try_scope<T, E, F>(&self, f: F) -> Result<Vec<T>, E>
+    where
+        F: FnOnce(&TryScope<'_, T, E>),
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let scope = TryScope {
+            pool: self,
+            token: CancelToken::default(),
+            results: Arc::new((
+                Mutex::new(ScopeResults {
+                    values: Vec::new(),
+                    error: None,
+                    panic: None,
+                    pending: 0,
+                }),
+                Condvar::new(),
+            )),
+        };
+        f(&scope);
+        let (results, condvar) = &*scope.results;
+        let mut results = condvar
+            .wait_while(results.lock().unwrap(), |results| results.pending > 0)
+            .unwrap();
+        if let Some(payload) = results.panic.take() {
+            drop(results);
+            panic::resume_unwind(payload);
+        }
+        if let Some(error) = results.error.take() {
+            return Err(error);
         }
+        Ok(results.values.drain(..).map(Option::unwrap).collect())
     }
 
-    /// Block the current thread until all jobs in the pool have been executed.
+    /// Sends `()` to `tx` when the pool next becomes idle, i.e., when the last unfinished job
+    /// finishes, so that the completion can be received alongside other events with `select!`
+    /// instead of blocking in [`join`](Self::join).
+    ///
+    /// The notification is sent once, on the next transition after this call, even if the pool is
+    /// already idle. Every registered sender is notified. Unlike `join`, this does not wait for the
+    /// [`blocking`](Self::blocking) jobs.
+    pub fn notify_when_idle(&self, tx: Sender<()>) {
+        self.pool_inner.idle_senders.lock().unwrap().push(tx);
+    }
+
+    /// Block the current thread until all jobs in the pool have been executed, including the
+    /// [`blocking`](Self::blocking) ones.
     ///
     /// NOTE: This method has nothing to do with `JoinHandle::join`.
     pub fn join(&self) {
-        self.pool_inner.wait_empty()
+        self.pool_inner.wait_empty();
+        self.blocking.join();
+    }
+
+    /// Like [`join`](Self::join), but gives up after `timeout`. Returns the number of unfinished
+    /// jobs, which is 0 iff the pool was drained.
+    ///
+    /// Unlike dropping the pool, this does not stop it: the unfinished jobs keep running.
+    pub fn join_timeout(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let unfinished = self.pool_inner.wait_empty_timeout(timeout);
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        unfinished + self.blocking.join_timeout(remaining)
+    }
+
+    /// Stops the workers from starting new jobs, e.g., to quiesce the pool during maintenance. The
+    /// running jobs finish, and the submitted jobs are queued until [`resume`](Self::resume).
+    ///
+    /// While the pool is paused, `join` blocks until it is resumed if any job is pending. Dropping
+    /// the pool resumes it.
+    pub fn pause(&self) {
+        self.pool_inner.queues.pause();
+    }
+
+    /// Lets the workers start jobs again after [`pause`](Self::pause).
+    pub fn resume(&self) {
+        self.pool_inner.queues.resume();
+    }
+
+    /// Returns the number of jobs executed since the pool was created or the counters were reset.
+    /// Jobs that panicked are counted once the panic handler returns.
+    ///
+    /// Unlike the pending job count that `join` waits for, this only increases between resets.
+    pub fn completed_jobs(&self) -> u64 {
+        self.pool_inner.completed_jobs.load(Ordering::Relaxed)
+    }
+
+    /// Returns the messages of the jobs that panicked since the last call, oldest first. Only the
+    /// latest 64 are kept.
+    ///
+    /// Panics are caught only if the pool has a panic handler, so this is always empty otherwise.
+    /// The message is the payload if it is a string, as for `panic!` with a message.
+    pub fn take_panics(&self) -> Vec<String> {
+        self.pool_inner.panics.lock().unwrap().drain(..).collect()
+    }
+
+    /// Resets `completed_jobs`, the counters of `metrics`, and the completed counts of
+    /// `label_metrics` to 0.
+    pub fn reset_counters(&self) {
+        let queues = &self.pool_inner.queues;
+        self.pool_inner.completed_jobs.store(0, Ordering::Relaxed);
+        queues.global_steals.store(0, Ordering::Relaxed);
+        queues.sibling_steals.store(0, Ordering::Relaxed);
+        for stats in self.pool_inner.labels.lock().unwrap().values_mut() {
+            stats.completed = 0;
+        }
+    }
+
+    /// Returns a snapshot of the pool's counters.
+    pub fn metrics(&self) -> PoolMetrics {
+        let queues = &self.pool_inner.queues;
+        PoolMetrics {
+            global_steals: queues.global_steals.load(Ordering::Relaxed),
+            sibling_steals: queues.sibling_steals.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the counters of each label of [`execute_labeled`](Self::execute_labeled) that has
+    /// started a job. A job is counted as active from when a worker starts it until it finishes.
+    pub fn label_metrics(&self) -> HashMap<&'static str, LabelStats> {
+        self.pool_inner.labels.lock().unwrap().clone()
+    }
+
+    /// Returns the number of workers.
+    pub fn size(&self) -> usize {
+        self.pool_inner.size()
+    }
+
+    /// Returns the ids of the current workers, in increasing order.
+    ///
+    /// The workers of a new pool have the ids `0..size`. The workers added by
+    /// [`set_size`](Self::set_size) get ids that were never used by the pool, so an id keeps
+    /// referring to the same worker, and stops being valid once the worker is retired.
+    pub fn worker_ids(&self) -> Vec<usize> {
+        self.pool_inner
+            .queues
+            .locals
+            .read()
+            .unwrap()
+            .iter()
+            .map(|local| local.id)
+            .collect()
+    }
+
+    /// Resizes the pool to `size` workers.
+    ///
+    /// When shrinking, the retired workers finish their current job and exit, and their pending
+    /// jobs are handed over to the remaining workers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn set_size(&self, size: usize) {
+        self.pool_inner.set_size(size)
+    }
+
+    /// Replaces the logger set by [`ThreadPoolBuilder::logger`], e.g., to change the verbosity of
+    /// a long-running pool. The events that happen after this returns go to `logger`.
+    ///
+    /// The logger is read without taking a lock, so the workers pick up the new one without
+    /// contending with this or with each other.
+    pub fn set_logger(&self, logger: Box<dyn Fn(PoolEvent) + Send + Sync>) {
+        ThreadPoolInner::replace_callback(&self.pool_inner.logger, Logger(logger));
+    }
+
+    /// Replaces the panic handler set by [`ThreadPoolBuilder::panic_handler`], or sets one if
+    /// there is none. The jobs that start after this returns have their panics caught, and they are
+    /// reported to `handler`.
+    ///
+    /// Like the logger, the panic handler is read without taking a lock.
+    pub fn set_panic_handler(&self, handler: Box<PanicFn>) {
+        ThreadPoolInner::replace_callback(&self.pool_inner.panic_handler, PanicHandler(handler));
+    }
+
+    /// Returns the number of workers that are executing a job.
+    pub fn active_count(&self) -> usize {
+        self.pool_inner.active_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the ids of the workers that are executing a job, in increasing order, e.g., to find
+    /// the workers stuck in a job. The ids are the same as in [`worker_ids`](Self::worker_ids) and
+    /// [`PoolEvent`]s.
+    ///
+    /// Retired workers that are finishing their last job are included.
+    pub fn active_worker_ids(&self) -> Vec<usize> {
+        self.pool_inner
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|worker| worker.busy.load(Ordering::Relaxed))
+            .map(|worker| worker._id)
+            .collect()
+    }
+
+    /// Returns the ratio of the workers that are executing a job, between 0 and 1.
+    pub fn current_load(&self) -> f64 {
+        self.pool_inner.current_load()
+    }
+
+    /// Starts a background thread that periodically samples the load and resizes the pool by one
+    /// worker at a time to keep the load within `target`, with the size bounded by `[min, max]`.
+    /// Replaces the previously started autoscaler, if any.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is 0 or greater than `max`.
+    pub fn autoscale(&self, min: usize, max: usize, target: RangeInclusive<f64>) {
+        assert!(0 < min && min <= max);
+        let autoscaler = Autoscaler::start(Arc::clone(&self.pool_inner), min, max, target);
+        drop(self.autoscaler.lock().unwrap().replace(autoscaler));
     }
 }
 
@@ -148,17 +1729,13 @@ impl Drop for ThreadPool {
     /// When dropped, all worker threads' `JoinHandle` must be `join`ed. If the thread panicked,
     /// then this function should panic too.
     fn drop(&mut self) {
-        // self.job_sender.take();
-        // for worker in &mut self._workers {
-        //     if let Some(thread) = worker.thread.take() {
-        //         thread.join().unwrap();
-        //     }
-        // }
-        if let Some(job_sender) = self.job_sender.take() {
-            let _ = job_sender;
-        }
-        while let Some(worker) = self._workers.pop() {
-            let _ = worker;
+        drop(self.autoscaler.lock().unwrap().take());
+        self.pool_inner.queues.shutdown();
+        let workers = mem::take(&mut *self.pool_inner.workers.lock().unwrap());
+        for worker in workers {
+            let id = worker._id;
+            drop(worker);
+            self.pool_inner.log(PoolEvent::WorkerJoined { worker: id });
         }
     }
 }
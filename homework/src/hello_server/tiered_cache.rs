@@ -0,0 +1,146 @@
+//! Key/value cache with a thread-local tier in front of a shared one.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::Cache;
+use crate::PoisonPolicy;
+
+/// Source of the ids that tell apart the caches in the L1 tiers.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// L1 tier of each `L1L2Cache` used by the current thread, keyed by the id of the cache. The
+    /// values are `L1<K, V>`s.
+    static L1S: RefCell<HashMap<u64, Box<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
+/// Values of an L1 tier, tagged with the generation of the cache when they were read from L2.
+type L1<K, V> = HashMap<K, (V, u64)>;
+
+/// Cache where each thread keeps a small private copy (L1) of the values it has read from a shared
+/// [`Cache`] (L2).
+///
+/// A read that hits the L1 of the calling thread takes no lock at all, which pays off for hot keys
+/// that the same thread reads over and over. A miss falls back to L2, and copies the value into
+/// L1. When an L1 is full, an arbitrary entry of it is evicted.
+///
+/// # Consistency
+///
+/// Every write through this cache bumps a shared generation counter, and an L1 entry is only used
+/// if it was read from L2 in the current generation. Hence a read that starts after a write
+/// returned never sees a value from before the write, in any thread. The invalidation is coarse,
+/// as a write to any key invalidates all the L1 entries, so the cache suits read-mostly workloads.
+///
+/// The L1 entries of a dropped cache are freed when their thread exits, except for those of the
+/// thread that drops it.
+#[derive(Debug)]
+pub struct L1L2Cache<K, V> {
+    l2: Cache<K, V>,
+    id: u64,
+    l1_capacity: usize,
+    generation: AtomicU64,
+    /// Number of operations that went to `l2`.
+    l2_lookups: AtomicUsize,
+}
+
+impl<K, V> L1L2Cache<K, V> {
+    /// Creates a new cache whose L1 tiers hold at most `l1_capacity` entries each.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `l1_capacity` is 0.
+    pub fn new(l1_capacity: usize) -> Self {
+        assert!(l1_capacity > 0);
+        Self {
+            l2: Cache::with_poison_policy(PoisonPolicy::default()),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            l1_capacity,
+            generation: AtomicU64::new(0),
+            l2_lookups: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of entries of L2.
+    pub fn len(&self) -> usize {
+        self.l2.len()
+    }
+
+    /// Returns `true` if L2 has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.l2.is_empty()
+    }
+
+    /// Returns the number of operations that missed L1 and went to L2, i.e., took its lock.
+    pub fn l2_lookups(&self) -> usize {
+        self.l2_lookups.load(Ordering::Relaxed)
+    }
+}
+
+impl<K: Eq + Hash + Clone + 'static, V: Clone + 'static> L1L2Cache<K, V> {
+    /// Runs `f` on the L1 tier of the current thread.
+    fn with_l1<R>(&self, f: impl FnOnce(&mut L1<K, V>) -> R) -> R {
+        L1S.with(|l1s| {
+            let mut l1s = l1s.borrow_mut();
+            let l1 = l1s
+                .entry(self.id)
+                .or_insert_with(|| Box::<L1<K, V>>::default());
+            f(l1.downcast_mut().unwrap())
+        })
+    }
+
+    /// Retrieve the value of `key` from L1, or from L2 with the same guarantees as
+    /// [`Cache::get_or_insert_with`].
+    pub fn get_or_insert_with<F: FnOnce(K) -> V>(&self, key: K, f: F) -> V {
+        // Read the generation before L2, so that a write racing with the L2 read invalidates the
+        // copy.
+        let generation = self.generation.load(Ordering::SeqCst);
+        let hit = self.with_l1(|l1| match l1.get(&key) {
+            Some((value, g)) if *g == generation => Some(value.clone()),
+            _ => None,
+        });
+        if let Some(value) = hit {
+            return value;
+        }
+
+        // `f` may use this cache too, so L1 is not borrowed meanwhile.
+        let _ = self.l2_lookups.fetch_add(1, Ordering::Relaxed);
+        let value = self.l2.get_or_insert_with(key.clone(), f);
+        self.with_l1(|l1| {
+            if l1.len() >= self.l1_capacity && !l1.contains_key(&key) {
+                let victim = l1.keys().next().unwrap().clone();
+                let _ = l1.remove(&victim);
+            }
+            let _ = l1.insert(key, (value.clone(), generation));
+        });
+        value
+    }
+
+    /// Sets the value for `key` in L2 and returns the previous one, like [`Cache::insert`]. All
+    /// the L1 entries are invalidated.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let _ = self.l2_lookups.fetch_add(1, Ordering::Relaxed);
+        let prev = self.l2.insert(key, value);
+        let _ = self.generation.fetch_add(1, Ordering::SeqCst);
+        prev
+    }
+
+    /// Removes every entry whose key matches `f`, like [`Cache::invalidate_matching`]. All the L1
+    /// entries are invalidated.
+    pub fn invalidate_matching<F: FnMut(&K) -> bool>(&self, f: F) -> usize {
+        let _ = self.l2_lookups.fetch_add(1, Ordering::Relaxed);
+        let removed = self.l2.invalidate_matching(f);
+        let _ = self.generation.fetch_add(1, Ordering::SeqCst);
+        removed
+    }
+}
+
+impl<K, V> Drop for L1L2Cache<K, V> {
+    fn drop(&mut self) {
+        // The thread-local storage may be gone if the cache is dropped while the thread exits.
+        let _ = L1S.try_with(|l1s| l1s.borrow_mut().remove(&self.id));
+    }
+}
@@ -15,6 +15,7 @@ pub mod hazard_pointer;
 pub mod hello_server;
 mod linked_list;
 mod list_set;
+mod poison;
 
 pub mod test;
 
@@ -24,4 +25,8 @@ pub use boc::CownPtr;
 pub use elim_stack::ElimStack;
 pub use hash_table::{GrowableArray, SplitOrderedList};
 pub use linked_list::LinkedList;
-pub use list_set::{FineGrainedListSet, OptimisticFineGrainedListSet};
+pub use list_set::{
+    ConcurrentQueue, DedupWindow, FineGrainedListMultiset, FineGrainedListSet,
+    OptimisticFineGrainedListSet, TryInsertError,
+};
+pub use poison::PoisonPolicy;
@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use super::fine_grained::Node;
+use super::{FineGrainedListSet, TryInsertError};
+use crate::ConcurrentSet;
+
+/// Set of the most recently inserted values, e.g., to deduplicate a stream of recent ids.
+///
+/// It holds at most `capacity` values: inserting a new value into a full window evicts the oldest
+/// inserted one. Lookups only take the locks of the underlying [`FineGrainedListSet`], while the
+/// insertion order is tracked in a FIFO queue of its nodes behind a separate lock, which also
+/// serializes the insertions.
+#[derive(Debug)]
+pub struct DedupWindow<T> {
+    /// Bounded to the capacity of the window.
+    set: FineGrainedListSet<T>,
+    /// Nodes of `set` from the oldest inserted one. Nodes are only inserted and removed while
+    /// holding this lock, so they are valid.
+    order: Mutex<VecDeque<*mut Node<T>>>,
+}
+
+// The nodes in `order` belong to `set`.
+unsafe impl<T: Send> Send for DedupWindow<T> {}
+unsafe impl<T: Send + Sync> Sync for DedupWindow<T> {}
+
+impl<T> DedupWindow<T> {
+    /// Creates a new window that holds at most `capacity` values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            set: FineGrainedListSet::new().with_capacity(capacity),
+            order: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the number of values. It may be outdated if other threads modify the window.
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    /// Returns `true` iff the window has no values.
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+}
+
+impl<T: Ord> DedupWindow<T> {
+    /// Returns `true` if `key` is among the recently inserted values.
+    pub fn contains(&self, key: &T) -> bool {
+        self.set.contains(key)
+    }
+
+    /// Inserts `key` and returns `true` if it is not in the window, evicting the oldest inserted
+    /// value if the window is full. Returns `false` for a duplicate, which does not refresh its
+    /// position in the window.
+    pub fn insert(&self, key: T) -> bool {
+        let mut order = self.order.lock().unwrap();
+        let mut key = key;
+        loop {
+            key = match self.set.insert_node(key) {
+                Ok(node_ptr) => {
+                    order.push_back(node_ptr);
+                    return true;
+                }
+                Err((TryInsertError::Present, _)) => return false,
+                Err((TryInsertError::Full, key)) => key,
+            };
+            // `set` holds the nodes of `order`, so it is only full if `order` is not empty.
+            let Some(oldest) = order.pop_front() else {
+                return false;
+            };
+            drop(unsafe { self.set.remove_node(oldest) });
+        }
+    }
+}
@@ -1,24 +1,86 @@
-use std::cmp::Ordering::*;
-use std::mem;
+use std::borrow::Borrow;
+use std::cmp::{self, Ordering::*};
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::{self, MaybeUninit};
+use std::ops::{Bound, RangeBounds};
 use std::ptr;
-use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use crate::ConcurrentSet;
+use crate::{ConcurrentSet, PoisonPolicy};
 
 #[derive(Debug)]
-struct Node<T> {
+pub(super) struct Node<T> {
     data: T,
     next: Mutex<*mut Node<T>>,
 }
 
 /// Concurrent sorted singly linked list using fine-grained lock-coupling.
+///
+/// The set is `Send` if `T: Send`, and `Sync` only if also `T: Sync`, because threads sharing the
+/// set get shared references to the elements:
+///
+/// ```compile_fail
+/// # use std::cell::Cell;
+/// # use cs431_homework::FineGrainedListSet;
+/// fn assert_sync<S: Sync>() {}
+/// assert_sync::<FineGrainedListSet<Cell<i32>>>();
+/// ```
+///
+/// ```compile_fail
+/// # use std::rc::Rc;
+/// # use cs431_homework::FineGrainedListSet;
+/// fn assert_send<S: Send>() {}
+/// assert_send::<FineGrainedListSet<Rc<i32>>>();
+/// ```
 #[derive(Debug)]
 pub struct FineGrainedListSet<T> {
     head: Mutex<*mut Node<T>>,
+    poison: PoisonPolicy,
+    /// Number of elements. A slot is reserved before a node is linked, so it never exceeds
+    /// `capacity`.
+    len: AtomicUsize,
+    capacity: usize,
+    /// Custom ordering of the elements. `None` means `T::cmp`.
+    cmp: Option<Comparator<T>>,
+    /// Allocations of removed nodes, reused by later insertions. See `with_node_cache`.
+    free_nodes: Mutex<Vec<Box<MaybeUninit<Node<T>>>>>,
+    /// Maximum length of `free_nodes`.
+    node_cache: usize,
+}
+
+type CmpFn<T> = dyn Fn(&T, &T) -> cmp::Ordering + Send + Sync;
+
+/// Comparison function of a list with a custom ordering. It is shared with the sets derived from
+/// the list, e.g., by `symmetric_difference`.
+struct Comparator<T>(Arc<CmpFn<T>>);
+
+impl<T> Clone for Comparator<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
 }
 
+impl<T> fmt::Debug for Comparator<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Comparator").finish_non_exhaustive()
+    }
+}
+
+/// Error returned by [`FineGrainedListSet::try_insert`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryInsertError {
+    /// The value is already in the set.
+    Present,
+    /// The set has reached its capacity.
+    Full,
+}
+
+// Sharing the set moves elements between threads (e.g., `remove`) and also shares references to
+// them (e.g., `iter` and the comparisons of concurrent lookups), so `Sync` needs `T: Send + Sync`.
 unsafe impl<T: Send> Send for FineGrainedListSet<T> {}
-unsafe impl<T: Send> Sync for FineGrainedListSet<T> {}
+unsafe impl<T: Send + Sync> Sync for FineGrainedListSet<T> {}
 
 /// Reference to the `next` field of previous node which points to the current node.
 ///
@@ -33,6 +95,45 @@ unsafe impl<T: Send> Sync for FineGrainedListSet<T> {}
 /// 2.
 struct Cursor<'l, T>(MutexGuard<'l, *mut Node<T>>);
 
+/// Nodes detached from a list, linked through their `next` in order.
+struct Chain<T> {
+    head: *mut Node<T>,
+    tail: *mut Node<T>,
+    len: usize,
+}
+
+impl<T> Chain<T> {
+    fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            tail: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    /// Appends a detached node whose `next` is null.
+    fn push_back(&mut self, node_ptr: *mut Node<T>, set: &FineGrainedListSet<T>) {
+        match unsafe { self.tail.as_ref() } {
+            Some(tail) => *set.lock(&tail.next) = node_ptr,
+            None => self.head = node_ptr,
+        }
+        self.tail = node_ptr;
+        self.len += 1;
+    }
+
+    /// Removes the first node, and returns it with a null `next`.
+    fn pop_front(&mut self, set: &FineGrainedListSet<T>) -> Option<*mut Node<T>> {
+        let head = unsafe { self.head.as_ref() }?;
+        let node_ptr = self.head;
+        self.head = mem::replace(&mut *set.lock(&head.next), ptr::null_mut());
+        if self.head.is_null() {
+            self.tail = ptr::null_mut();
+        }
+        self.len -= 1;
+        Some(node_ptr)
+    }
+}
+
 impl<T> Node<T> {
     fn new(data: T, next: *mut Self) -> *mut Self {
         Box::into_raw(Box::new(Self {
@@ -45,96 +146,1139 @@ impl<T> Node<T> {
 impl<T: Ord> Cursor<'_, T> {
     /// Moves the cursor to the position of key in the sorted list.
     /// Returns whether the value was found.
-    fn find(&mut self, key: &T) -> bool {
+    fn find(&mut self, key: &T, set: &FineGrainedListSet<T>) -> bool {
+        self.find_ranked(key, set).0
+    }
+
+    /// Like `find`, but also returns the number of nodes that the cursor passed.
+    fn find_ranked(&mut self, key: &T, set: &FineGrainedListSet<T>) -> (bool, usize) {
+        self.find_by(|data| set.compare(data, key), set)
+    }
+}
+
+impl<T> Cursor<'_, T> {
+    /// Like `find_ranked`, but for a key that is compared to the elements by `cmp`, which must be
+    /// consistent with the order of the list.
+    fn find_by<F: FnMut(&T) -> cmp::Ordering>(
+        &mut self,
+        mut cmp: F,
+        set: &FineGrainedListSet<T>,
+    ) -> (bool, usize) {
+        let mut rank = 0;
         while let Some(node) = unsafe { self.0.as_ref() } {
-            match node.data.cmp(key) {
+            match cmp(&node.data) {
                 Equal => {
-                    return true;
+                    return (true, rank);
                 }
                 Greater => {
-                    return false;
+                    return (false, rank);
                 }
                 Less => {
-                    *self = Cursor(node.next.lock().unwrap());
+                    *self = Cursor(set.lock(&node.next));
+                    rank += 1;
                 }
             }
         }
-        false
+        (false, rank)
     }
 }
 
 impl<T> FineGrainedListSet<T> {
     /// Creates a new list.
     pub fn new() -> Self {
+        Self::with_poison_policy(PoisonPolicy::default())
+    }
+
+    /// Creates a new list that handles poisoned locks according to `poison`.
+    ///
+    /// The list is consistent whenever a panic can occur while holding its locks (e.g., in the
+    /// comparison or the destructor of an element), so `PoisonPolicy::Recover` is safe to use.
+    pub fn with_poison_policy(poison: PoisonPolicy) -> Self {
         Self {
             head: Mutex::new(ptr::null_mut()),
+            poison,
+            len: AtomicUsize::new(0),
+            capacity: usize::MAX,
+            cmp: None,
+            free_nodes: Mutex::new(Vec::new()),
+            node_cache: 0,
+        }
+    }
+
+    /// Orders the list by `cmp` instead of `T::cmp`, e.g., `FineGrainedListSet::new().with_cmp(f)`.
+    ///
+    /// `cmp` must be a total order. Elements that compare `Equal` are considered the same element.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the list is not empty, as its elements may not be ordered by `cmp`.
+    pub fn with_cmp<F>(mut self, cmp: F) -> Self
+    where
+        F: Fn(&T, &T) -> cmp::Ordering + Send + Sync + 'static,
+    {
+        assert!(
+            self.is_empty(),
+            "the ordering of a non-empty list was changed"
+        );
+        self.cmp = Some(Comparator(Arc::new(cmp)));
+        self
+    }
+
+    /// Bounds the list to `capacity` elements. Insertions into a full list are rejected.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Keeps the allocations of up to `cap` removed nodes and reuses them for later insertions, to
+    /// avoid churning the allocator in insert-heavy workloads. The space for the cache is reserved
+    /// upfront.
+    pub fn with_node_cache(mut self, cap: usize) -> Self {
+        self.free_nodes = Mutex::new(Vec::with_capacity(cap));
+        self.node_cache = cap;
+        self
+    }
+
+    /// Frees the allocations kept by the node cache, e.g., after clearing a large set. The space
+    /// reserved for the cache itself is kept, so the cache refills without reallocating it.
+    pub fn shrink_to_fit(&self) {
+        self.lock(&self.free_nodes).clear();
+    }
+
+    /// Returns the number of elements. It may be outdated if other threads modify the set.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns `true` iff the set has no elements. It may be outdated if other threads modify the
+    /// set.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock<'a, U>(&self, mutex: &'a Mutex<U>) -> MutexGuard<'a, U> {
+        self.poison.unwrap(mutex.lock())
+    }
+
+    /// Reserves a slot for a new element. Returns `false` if the set is full.
+    fn reserve(&self) -> bool {
+        self.len
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                (len < self.capacity).then_some(len + 1)
+            })
+            .is_ok()
+    }
+
+    /// Allocates a node, reusing a cached allocation if there is one.
+    fn alloc_node(&self, data: T, next: *mut Node<T>) -> *mut Node<T> {
+        if self.node_cache == 0 {
+            return Node::new(data, next);
         }
+        let Some(slot) = self.lock(&self.free_nodes).pop() else {
+            return Node::new(data, next);
+        };
+        let node_ptr = Box::into_raw(slot).cast::<Node<T>>();
+        unsafe {
+            node_ptr.write(Node {
+                data,
+                next: Mutex::new(next),
+            })
+        };
+        node_ptr
+    }
+
+    /// Frees a node that is no longer reachable, caching its allocation if the cache is not full,
+    /// and returns its element.
+    ///
+    /// # Safety
+    ///
+    /// `node_ptr` must be a node allocated by this list that no other thread can access.
+    unsafe fn free_node(&self, node_ptr: *mut Node<T>) -> T {
+        if self.node_cache == 0 {
+            return unsafe { Box::from_raw(node_ptr) }.data;
+        }
+        let Node { data, next: _ } = unsafe { node_ptr.read() };
+        // Dropping the slot only deallocates it, since `MaybeUninit` does not drop its contents.
+        let slot = unsafe { Box::from_raw(node_ptr.cast::<MaybeUninit<Node<T>>>()) };
+        let mut free_nodes = self.lock(&self.free_nodes);
+        if free_nodes.len() < self.node_cache {
+            free_nodes.push(slot);
+        }
+        data
+    }
+
+    /// Unlinks the node that the cursor points to, moves the cursor to the next node, and returns
+    /// the element of the unlinked node.
+    ///
+    /// # Safety
+    ///
+    /// The cursor must point to a node of this list.
+    unsafe fn unlink(&self, cursor: &mut Cursor<'_, T>) -> T {
+        let node_ptr = *cursor.0;
+        let next_guard = self.lock(unsafe { &(*node_ptr).next });
+        *cursor.0 = *next_guard;
+        drop(next_guard);
+        let _ = self.len.fetch_sub(1, Ordering::Relaxed);
+        // The node was reachable only through `cursor`, whose lock is held.
+        unsafe { self.free_node(node_ptr) }
+    }
+
+    /// Removes the node, which is found by its address instead of by comparing elements, and
+    /// returns its element.
+    ///
+    /// # Safety
+    ///
+    /// `node_ptr` must be a node of this list, e.g., one returned by `insert_node` that no thread
+    /// removed since.
+    pub(super) unsafe fn remove_node(&self, node_ptr: *mut Node<T>) -> T {
+        let mut cursor = Cursor(self.lock(&self.head));
+        while *cursor.0 != node_ptr {
+            // The list is not exhausted before `node_ptr` is reached.
+            let node = unsafe { &**cursor.0 };
+            cursor = Cursor(self.lock(&node.next));
+        }
+        unsafe { self.unlink(&mut cursor) }
     }
 }
 
 impl<T: Ord> FineGrainedListSet<T> {
+    /// Compares two elements in the order of the list.
+    fn compare(&self, a: &T, b: &T) -> cmp::Ordering {
+        match &self.cmp {
+            Some(cmp) => (cmp.0)(a, b),
+            None => a.cmp(b),
+        }
+    }
+
+    /// Returns whether `value` is ordered before the start of `range`.
+    fn is_below<R: RangeBounds<T>>(&self, value: &T, range: &R) -> bool {
+        match range.start_bound() {
+            Bound::Included(start) => self.compare(value, start) == Less,
+            Bound::Excluded(start) => self.compare(value, start) != Greater,
+            Bound::Unbounded => false,
+        }
+    }
+
+    /// Returns whether `value` is ordered after the end of `range`.
+    fn is_above<R: RangeBounds<T>>(&self, value: &T, range: &R) -> bool {
+        match range.end_bound() {
+            Bound::Included(end) => self.compare(value, end) == Greater,
+            Bound::Excluded(end) => self.compare(value, end) != Less,
+            Bound::Unbounded => false,
+        }
+    }
+
+    /// Moves a cursor from the head to the position of `key`.
+    ///
+    /// The cursor keeps the predecessor's `next` locked, so the node it points to stays reachable
+    /// until the cursor is dropped, and lookups need no validation. A reader that released that
+    /// lock before inspecting the node could report a node that was removed meanwhile, even if an
+    /// equal key was reinserted elsewhere; the optimistic set must validate against this.
     fn find(&self, key: &T) -> (bool, Cursor<'_, T>) {
-        let mut cursor = Cursor(self.head.lock().unwrap());
-        let found = cursor.find(key);
+        let mut cursor = Cursor(self.lock(&self.head));
+        let found = cursor.find(key, self);
         (found, cursor)
     }
-}
 
-impl<T: Ord> ConcurrentSet<T> for FineGrainedListSet<T> {
-    fn contains(&self, key: &T) -> bool {
-        self.find(key).0
+    /// Adds the value to the set, telling apart why it was not inserted.
+    pub fn try_insert(&self, key: T) -> Result<(), TryInsertError> {
+        let result = self.insert_node(key);
+        // The key is deliberately not recorded: that would need `T: Debug` whenever the `tracing`
+        // feature is enabled, which breaks the sets of other crates whose keys are not `Debug`.
+        #[cfg(feature = "tracing")]
+        tracing::trace!(op = "insert", outcome = result.is_ok());
+        result.map(drop).map_err(|(err, _)| err)
     }
 
-    fn insert(&self, key: T) -> bool {
-        let (found, mut cursor) = self.find(&key);
+    /// Like `try_insert`, but returns the new node, or gives back the value if it was not inserted.
+    pub(super) fn insert_node(&self, key: T) -> Result<*mut Node<T>, (TryInsertError, T)> {
+        let (found, cursor) = self.find(&key);
         if found {
-            return false;
+            return Err((TryInsertError::Present, key));
+        }
+        if !self.reserve() {
+            return Err((TryInsertError::Full, key));
         }
         let mut lock = cursor.0;
         let next = *lock;
-        let new_node = Node::new(key, next);
+        let new_node = self.alloc_node(key, next);
         *lock = new_node;
-        true
+        Ok(new_node)
+    }
+
+    /// Adds the value to the set and returns its 0-based position in sorted order, or the position
+    /// of the equal element as an error if it was already present.
+    ///
+    /// The rank is counted during the traversal, so it is only accurate at the moment of the
+    /// insertion. Concurrent operations before the position may change it right after. If the set
+    /// is full, the value is not inserted and the rank it would have is returned as an error.
+    pub fn insert_ranked(&self, key: T) -> Result<usize, usize> {
+        let mut cursor = Cursor(self.lock(&self.head));
+        let (found, rank) = cursor.find_ranked(&key, self);
+        if found || !self.reserve() {
+            return Err(rank);
+        }
+        let mut lock = cursor.0;
+        let next = *lock;
+        *lock = self.alloc_node(key, next);
+        Ok(rank)
+    }
+
+    /// Inserts every value of `iter`, and returns the number of inserted values and the number of
+    /// duplicates, either of elements already in the set or of earlier values of `iter`.
+    ///
+    /// Each value is inserted like [`try_insert`](Self::try_insert). Values that do not fit in a
+    /// full set are counted in neither.
+    pub fn extend_reporting<I: IntoIterator<Item = T>>(&self, iter: I) -> (usize, usize) {
+        let (mut inserted, mut duplicates) = (0, 0);
+        for key in iter {
+            match self.try_insert(key) {
+                Ok(()) => inserted += 1,
+                Err(TryInsertError::Present) => duplicates += 1,
+                Err(TryInsertError::Full) => {}
+            }
+        }
+        (inserted, duplicates)
+    }
+
+    /// Inserts the values of `sorted`, which should be in the order of the set, and returns the
+    /// number of inserted ones, e.g., to merge a large sorted file into the set.
+    ///
+    /// A single cursor moves forward through the list, so merging `m` values into `n` elements
+    /// takes O(n + m) comparisons, whereas inserting them one by one restarts from the head each
+    /// time. If a value is ordered before the previous one, the cursor restarts from the head, so
+    /// an unsorted stream is still merged correctly, only slower. The merge stops at the first
+    /// value that does not fit in a full set.
+    ///
+    /// Like other lock-coupled traversals, the cursor only holds the lock of its position, so
+    /// operations ahead of it keep running, and the ones behind it wait until it moves on.
+    pub fn merge_sorted<I: IntoIterator<Item = T>>(&self, sorted: I) -> usize {
+        let mut inserted = 0;
+        let mut cursor = Cursor(self.lock(&self.head));
+        for key in sorted {
+            // The cursor is at the previous value, so every element before it is smaller than
+            // `key` unless `key` is out of order.
+            if unsafe { cursor.0.as_ref() }
+                .is_some_and(|node| self.compare(&key, &node.data) == Less)
+            {
+                // The head is locked only after the cursor is dropped, in list order.
+                drop(cursor);
+                cursor = Cursor(self.lock(&self.head));
+            }
+            if cursor.find(&key, self) {
+                continue;
+            }
+            if !self.reserve() {
+                break;
+            }
+            let next = *cursor.0;
+            *cursor.0 = self.alloc_node(key, next);
+            inserted += 1;
+        }
+        inserted
+    }
+}
+
+/// Position of a key in a [`FineGrainedListSet`], returned by
+/// [`FineGrainedListSet::entry`].
+///
+/// The guard holds the lock that protects the position, so no other thread can insert or remove an
+/// element that is equal to the key, or that is ordered after it, until the guard is dropped.
+pub struct EntryGuard<'l, T> {
+    set: &'l FineGrainedListSet<T>,
+    cursor: Cursor<'l, T>,
+    /// Whether the cursor points to an element equal to the key.
+    found: bool,
+}
+
+impl<T> fmt::Debug for EntryGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EntryGuard")
+            .field("exists", &self.found)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Ord> FineGrainedListSet<T> {
+    /// Locks the position of `key`, whether it is in the set or not, so that compound operations
+    /// on it can be done atomically without walking the list again.
+    ///
+    /// Drop the guard as soon as possible: it blocks every operation that walks past the key.
+    pub fn entry(&self, key: &T) -> EntryGuard<'_, T> {
+        let (found, cursor) = self.find(key);
+        EntryGuard {
+            set: self,
+            cursor,
+            found,
+        }
+    }
+}
+
+impl<T: Ord> EntryGuard<'_, T> {
+    /// Returns `true` if the set contains the key.
+    pub fn exists(&self) -> bool {
+        self.found
+    }
+
+    /// Returns the stored element that is equal to the key.
+    pub fn get(&self) -> Option<&T> {
+        if !self.found {
+            return None;
+        }
+        unsafe { self.cursor.0.as_ref() }.map(|node| &node.data)
+    }
+
+    /// Inserts `value` at the position, telling apart why it was not inserted.
+    ///
+    /// `value` must be equal to the key that the guard was created with, or the list becomes
+    /// unsorted.
+    pub fn insert_here(&mut self, value: T) -> Result<(), TryInsertError> {
+        if self.found {
+            return Err(TryInsertError::Present);
+        }
+        debug_assert!(unsafe { self.cursor.0.as_ref() }
+            .map_or(true, |next| self.set.compare(&value, &next.data) == Less));
+        if !self.set.reserve() {
+            return Err(TryInsertError::Full);
+        }
+        *self.cursor.0 = self.set.alloc_node(value, *self.cursor.0);
+        self.found = true;
+        Ok(())
+    }
+
+    /// Removes the element equal to the key, and returns it.
+    pub fn remove_here(&mut self) -> Option<T> {
+        if !self.found {
+            return None;
+        }
+        self.found = false;
+        Some(unsafe { self.set.unlink(&mut self.cursor) })
+    }
+}
+
+impl<T: Ord> ConcurrentSet<T> for FineGrainedListSet<T> {
+    fn contains(&self, key: &T) -> bool {
+        let found = self.find(key).0;
+        // No key, as in `try_insert`.
+        #[cfg(feature = "tracing")]
+        tracing::trace!(op = "contains", outcome = found);
+        found
+    }
+
+    fn insert(&self, key: T) -> bool {
+        self.try_insert(key).is_ok()
     }
 
     fn remove(&self, key: &T) -> bool {
+        let removed = self.remove_value(key);
+        // No key, as in `try_insert`.
+        #[cfg(feature = "tracing")]
+        tracing::trace!(op = "remove", outcome = removed.is_some());
+        removed.is_some()
+    }
+}
+
+impl<T: Ord> FineGrainedListSet<T> {
+    /// Moves a cursor to the position of a borrowed form of an element.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the set has a custom ordering, which cannot compare `Q`.
+    fn find_q<Q: Ord + ?Sized>(&self, key: &Q) -> (bool, Cursor<'_, T>)
+    where
+        T: Borrow<Q>,
+    {
+        assert!(
+            self.cmp.is_none(),
+            "borrowed lookups need the natural ordering of the elements"
+        );
+        let mut cursor = Cursor(self.lock(&self.head));
+        let found = cursor.find_by(|data| data.borrow().cmp(key), self).0;
+        (found, cursor)
+    }
+
+    /// Returns `true` if the set contains `key`, given in any borrowed form of the elements, e.g.,
+    /// `&str` for a set of `String`s.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the set is ordered by [`with_cmp`](Self::with_cmp).
+    pub fn contains_q<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+    where
+        T: Borrow<Q>,
+    {
+        self.find_q(key).0
+    }
+
+    /// Removes `key`, given in any borrowed form of the elements. Returns `true` if it was in the
+    /// set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the set is ordered by [`with_cmp`](Self::with_cmp).
+    pub fn remove_q<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+    where
+        T: Borrow<Q>,
+    {
+        let (found, mut cursor) = self.find_q(key);
+        if found {
+            drop(unsafe { self.unlink(&mut cursor) });
+        }
+        found
+    }
+
+    /// Removes the element equal to `key` and returns it, e.g., to get back the payload of an
+    /// element whose ordering only depends on a part of it.
+    pub fn remove_value(&self, key: &T) -> Option<T> {
         let (found, mut cursor) = self.find(key);
+        found.then(|| unsafe { self.unlink(&mut cursor) })
+    }
+
+    /// Returns `true` if the set contains any of `keys`.
+    ///
+    /// The keys are sorted and looked up in a single lock-coupled walk that stops at the first
+    /// match, instead of walking the list once per key.
+    pub fn contains_any(&self, keys: &[T]) -> bool {
+        let mut keys = keys.iter().collect::<Vec<_>>();
+        keys.sort_unstable_by(|a, b| self.compare(a, b));
+        let mut cursor = Cursor(self.lock(&self.head));
+        keys.into_iter().any(|key| cursor.find(key, self))
+    }
+
+    /// Returns whether the set contains each of `keys`, in the order of `keys`.
+    ///
+    /// Like [`contains_any`](Self::contains_any), the keys are sorted and looked up in a single
+    /// lock-coupled walk, instead of walking the list once per key.
+    pub fn batch_contains(&self, keys: &[T]) -> Vec<bool> {
+        let mut order = (0..keys.len()).collect::<Vec<_>>();
+        order.sort_unstable_by(|&a, &b| self.compare(&keys[a], &keys[b]));
+        let mut found = vec![false; keys.len()];
+        let mut cursor = Cursor(self.lock(&self.head));
+        for i in order {
+            found[i] = cursor.find(&keys[i], self);
+        }
+        found
+    }
+
+    /// Returns a clone of the stored element that is equal to `key`.
+    ///
+    /// Unlike `contains`, this tells apart elements that are equal in order but carry other data.
+    pub fn get(&self, key: &T) -> Option<T>
+    where
+        T: Clone,
+    {
+        let (found, cursor) = self.find(key);
         if !found {
-            return false;
+            return None;
+        }
+        unsafe { cursor.0.as_ref() }.map(|node| node.data.clone())
+    }
+
+    /// Returns a clone of the stored element that is equal to `key`, or inserts `key` and returns
+    /// a clone of it if there is none.
+    ///
+    /// The lookup and the insertion happen in a single traversal without releasing the lock at the
+    /// position, so concurrent callers with equal keys all get the same element. If the set is
+    /// full, `key` is returned without being inserted.
+    pub fn get_or_insert(&self, key: T) -> T
+    where
+        T: Clone,
+    {
+        let (found, cursor) = self.find(&key);
+        if found {
+            return unsafe { &**cursor.0 }.data.clone();
+        }
+        if !self.reserve() {
+            return key;
         }
         let mut lock = cursor.0;
-        unsafe {
-            let mut node_ptr = *lock;
-            let mut next_guard = (*node_ptr).next.lock().unwrap();
-            *lock = *next_guard;
-            drop(next_guard);
-            let _ = Box::from_raw(node_ptr);
+        let next = *lock;
+        let value = key.clone();
+        *lock = self.alloc_node(key, next);
+        value
+    }
+
+    /// Looks up `key` and calls `f` with whether it is in the set, while still holding the lock
+    /// of its position, and returns the result of `f`.
+    ///
+    /// No other thread can insert or remove `key` until `f` returns, so unlike a
+    /// [`contains`](ConcurrentSet::contains) followed by an action, the action cannot race with a
+    /// change of the membership. Other operations that reach the position wait for `f` too, so
+    /// `f` should be short, and it must not operate on this set, or it may deadlock.
+    pub fn with_contains<R, F: FnOnce(bool) -> R>(&self, key: &T, f: F) -> R {
+        let (found, cursor) = self.find(key);
+        let result = f(found);
+        drop(cursor);
+        result
+    }
+}
+
+impl<T: Ord> FineGrainedListSet<T> {
+    /// Removes all values within `range` in a single lock-coupled pass. Returns the number of
+    /// removed values.
+    ///
+    /// The lock of the predecessor is held while the nodes in the range are unlinked one by one, so
+    /// that no other thread can observe a partially spliced list.
+    pub fn remove_range<R: RangeBounds<T>>(&self, range: R) -> usize {
+        let mut cursor = Cursor(self.lock(&self.head));
+
+        // Moves the cursor to the first node that is not below the range.
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            if !self.is_below(&node.data, &range) {
+                break;
+            }
+            cursor = Cursor(self.lock(&node.next));
+        }
+
+        // Unlinks the nodes until the cursor leaves the range.
+        let mut count = 0;
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            if self.is_above(&node.data, &range) {
+                break;
+            }
+            drop(unsafe { self.unlink(&mut cursor) });
+            count += 1;
+        }
+        count
+    }
+
+    /// Moves all values within `range` to `dst`, e.g., to rebalance two sets, and returns the
+    /// number of moved values. The nodes are relinked into `dst` in its order, so their
+    /// allocations are reused. A value equal to an element of `dst` stays in `self`.
+    ///
+    /// The values are unlinked from `self` in a single lock-coupled pass like
+    /// [`remove_range`](Self::remove_range), and then linked into `dst` in a single pass like
+    /// [`merge_sorted`](Self::merge_sorted). The values that were already in `dst` are then linked
+    /// back into `self` in the same way. The locks of both sets are never held together, so
+    /// concurrent moves in opposite directions cannot deadlock, but other threads may observe the
+    /// moved values in neither set meanwhile. If `dst` is full, the values that do not fit are
+    /// left in `self`.
+    pub fn move_range_to<R: RangeBounds<T>>(&self, range: R, dst: &Self) -> usize {
+        let mut cursor = Cursor(self.lock(&self.head));
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            if !self.is_below(&node.data, &range) {
+                break;
+            }
+            cursor = Cursor(self.lock(&node.next));
+        }
+
+        // Detaches the nodes in the range, reserving a slot of `dst` for each of them. They keep
+        // their slots of `self` until `dst` takes them, so that the rejected ones can come back.
+        let mut moved = Chain::new();
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            if self.is_above(&node.data, &range) || !dst.reserve() {
+                break;
+            }
+            let node_ptr = *cursor.0;
+            *cursor.0 = mem::replace(&mut *self.lock(&node.next), ptr::null_mut());
+            moved.push_back(node_ptr, self);
+        }
+        drop(cursor);
+
+        let detached = moved.len;
+        let rejected = dst.link_chain(moved);
+        let count = detached - rejected.len;
+        let _ = dst.len.fetch_sub(rejected.len, Ordering::Relaxed);
+        let _ = self.len.fetch_sub(count, Ordering::Relaxed);
+
+        // An equal value may have been inserted into `self` meanwhile, which replaces the
+        // rejected one.
+        let mut dropped = self.link_chain(rejected);
+        while let Some(node_ptr) = dropped.pop_front(self) {
+            let _ = self.len.fetch_sub(1, Ordering::Relaxed);
+            drop(unsafe { self.free_node(node_ptr) });
+        }
+        count
+    }
+
+    /// Links the nodes of `chain`, which are in ascending order, into the list in a single pass
+    /// like `merge_sorted`, and returns the ones equal to an element of the list. The caller
+    /// accounts for `len`.
+    fn link_chain(&self, mut chain: Chain<T>) -> Chain<T> {
+        let mut rejected = Chain::new();
+        let mut cursor = Cursor(self.lock(&self.head));
+        while let Some(node_ptr) = chain.pop_front(self) {
+            // The detached nodes are only reachable through `chain`.
+            let node = unsafe { &*node_ptr };
+            // Like in `merge_sorted`, the cursor restarts if the list is ordered differently.
+            if unsafe { cursor.0.as_ref() }
+                .is_some_and(|prev| self.compare(&node.data, &prev.data) == Less)
+            {
+                drop(cursor);
+                cursor = Cursor(self.lock(&self.head));
+            }
+            if cursor.find(&node.data, self) {
+                rejected.push_back(node_ptr, self);
+                continue;
+            }
+            *self.lock(&node.next) = *cursor.0;
+            *cursor.0 = node_ptr;
+        }
+        rejected
+    }
+
+    /// Returns the number of values within `range`, counted in a single lock-coupled walk from the
+    /// start of the range to its end without cloning them.
+    pub fn range_len<R: RangeBounds<T>>(&self, range: R) -> usize {
+        let mut cursor = Cursor(self.lock(&self.head));
+        let mut count = 0;
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            if self.is_above(&node.data, &range) {
+                break;
+            }
+            if !self.is_below(&node.data, &range) {
+                count += 1;
+            }
+            cursor = Cursor(self.lock(&node.next));
+        }
+        count
+    }
+}
+
+impl<T: Ord> FineGrainedListSet<T> {
+    /// Returns the number of elements that are less than `key`.
+    ///
+    /// Like [`insert_ranked`](Self::insert_ranked), this walks the list up to `key`, and the rank
+    /// is only accurate at the moment of the walk.
+    pub fn rank(&self, key: &T) -> usize {
+        let mut cursor = Cursor(self.lock(&self.head));
+        cursor.find_ranked(key, self).1
+    }
+
+    /// Returns a clone of the `k`-th smallest element, counting from 0, or `None` if the set has at
+    /// most `k` elements. This walks the first `k` nodes of the list.
+    pub fn select(&self, k: usize) -> Option<T>
+    where
+        T: Clone,
+    {
+        let mut cursor = Cursor(self.lock(&self.head));
+        for _ in 0..k {
+            let node = unsafe { cursor.0.as_ref() }?;
+            cursor = Cursor(self.lock(&node.next));
+        }
+        unsafe { cursor.0.as_ref() }.map(|node| node.data.clone())
+    }
+}
+
+impl<T: Ord> FineGrainedListSet<T> {
+    /// Returns a new set with the poison policy and the ordering of `self`, whose elements are
+    /// `values`. They must be sorted in that ordering, without duplicates.
+    fn new_sorted(&self, values: Vec<T>) -> Self {
+        let mut result = Self::with_poison_policy(self.poison);
+        result.cmp.clone_from(&self.cmp);
+        *result.len.get_mut() = values.len();
+        *result.head.get_mut().unwrap() = values
+            .into_iter()
+            .rev()
+            .fold(ptr::null_mut(), |next, value| Node::new(value, next));
+        result
+    }
+
+    /// Returns a new set of the elements that are in exactly one of `self` and `other`, with the
+    /// same ordering as `self`.
+    ///
+    /// Both lists are walked once in lockstep with lock-coupling, so `other` must have the same
+    /// ordering as `self`. Since locks of both sets are held at the same time, this must not run
+    /// concurrently with another operation that walks the two sets in the opposite roles, or they
+    /// may deadlock.
+    pub fn symmetric_difference(&self, other: &Self) -> Self
+    where
+        T: Clone,
+    {
+        let mut values = Vec::new();
+        if ptr::eq(self, other) {
+            return self.new_sorted(values);
+        }
+        let mut mine = self.iter().peekable();
+        let mut theirs = other.iter().peekable();
+        loop {
+            let value = match (mine.peek(), theirs.peek()) {
+                (Some(a), Some(b)) => match self.compare(a, b) {
+                    Less => mine.next(),
+                    Greater => theirs.next(),
+                    Equal => {
+                        let _ = mine.next();
+                        let _ = theirs.next();
+                        continue;
+                    }
+                },
+                (Some(_), None) => mine.next(),
+                (None, Some(_)) => theirs.next(),
+                (None, None) => break,
+            };
+            values.extend(value.cloned());
+        }
+        drop((mine, theirs));
+        self.new_sorted(values)
+    }
+
+    /// Returns two new sets of clones of the elements for which `f` returns `true` and `false`,
+    /// respectively, with the same ordering as `self`.
+    ///
+    /// Like [`fold`](Self::fold), the list is traversed once with lock-coupling.
+    pub fn partition<F: FnMut(&T) -> bool>(&self, mut f: F) -> (Self, Self)
+    where
+        T: Clone,
+    {
+        let (matching, rest) = self.fold((Vec::new(), Vec::new()), |(mut yes, mut no), data| {
+            if f(data) {
+                yes.push(data.clone());
+            } else {
+                no.push(data.clone());
+            }
+            (yes, no)
+        });
+        (self.new_sorted(matching), self.new_sorted(rest))
+    }
+
+    /// Replaces the elements of `dst` with clones of the elements of `self`, e.g., to take repeated
+    /// snapshots into the same set. `dst` also takes the ordering of `self`.
+    ///
+    /// The nodes of `dst` go to its node cache, if it has one, and are reused for the clones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `self` has more elements than the capacity of `dst`.
+    pub fn clone_into(&self, dst: &mut Self)
+    where
+        T: Clone,
+    {
+        let mut values = Vec::with_capacity(self.len());
+        values.extend(self.iter().cloned());
+        assert!(
+            values.len() <= dst.capacity,
+            "the destination set is too small"
+        );
+        dst.clear();
+        dst.cmp.clone_from(&self.cmp);
+        *dst.len.get_mut() = values.len();
+        let mut head = ptr::null_mut();
+        for value in values.into_iter().rev() {
+            head = dst.alloc_node(value, head);
+        }
+        *dst.poison.unwrap(dst.head.get_mut()) = head;
+    }
+
+    /// Returns the number of elements that are in both `self` and `other`, in a single merge walk
+    /// of both lists without building a new set.
+    ///
+    /// The count is a best-effort snapshot: an element inserted or removed concurrently is counted
+    /// depending on whether the walk has already passed its position. The same restrictions as
+    /// [`symmetric_difference`](Self::symmetric_difference) apply.
+    pub fn intersection_count(&self, other: &Self) -> usize {
+        if ptr::eq(self, other) {
+            return self.fold(0, |count, _| count + 1);
+        }
+        let mut count = 0;
+        let mut mine = self.iter().peekable();
+        let mut theirs = other.iter().peekable();
+        while let (Some(a), Some(b)) = (mine.peek(), theirs.peek()) {
+            match self.compare(a, b) {
+                Less => drop(mine.next()),
+                Greater => drop(theirs.next()),
+                Equal => {
+                    count += 1;
+                    let _ = mine.next();
+                    let _ = theirs.next();
+                }
+            }
+        }
+        count
+    }
+
+    /// Removes from `self` every element that is also in `other`, in a single merge walk of both
+    /// lists without building a new set.
+    ///
+    /// The same restrictions as [`symmetric_difference`](Self::symmetric_difference) apply.
+    #[doc(alias = "difference_update")]
+    pub fn retain_difference(&self, other: &Self) {
+        if ptr::eq(self, other) {
+            self.clear();
+            return;
+        }
+        let mut cursor = Cursor(self.lock(&self.head));
+        let mut theirs = other.iter().peekable();
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            while theirs
+                .next_if(|b| self.compare(b, &node.data) == Less)
+                .is_some()
+            {}
+            match theirs.peek() {
+                None => break,
+                Some(b) if self.compare(b, &node.data) == Equal => {
+                    drop(unsafe { self.unlink(&mut cursor) });
+                }
+                Some(_) => cursor = Cursor(self.lock(&node.next)),
+            }
+        }
+    }
+}
+
+impl<T> FineGrainedListSet<T> {
+    /// Folds every element into an accumulator in sorted order.
+    ///
+    /// The list is traversed with lock-coupling, so only the locks around the current node are held
+    /// at a time and writers behind the traversal are not blocked until the fold finishes.
+    pub fn fold<B, F: FnMut(B, &T) -> B>(&self, init: B, mut f: F) -> B {
+        let mut acc = init;
+        let mut cursor = Cursor(self.lock(&self.head));
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            acc = f(acc, &node.data);
+            cursor = Cursor(self.lock(&node.next));
+        }
+        acc
+    }
+
+    /// Applies `f` to the elements in sorted order, and returns the first non-`None` result.
+    ///
+    /// Like [`fold`](Self::fold), the list is traversed with lock-coupling, and the traversal
+    /// stops at the first match, so the elements after it are not locked.
+    pub fn find_map<B, F: FnMut(&T) -> Option<B>>(&self, mut f: F) -> Option<B> {
+        let mut cursor = Cursor(self.lock(&self.head));
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            if let Some(result) = f(&node.data) {
+                return Some(result);
+            }
+            cursor = Cursor(self.lock(&node.next));
+        }
+        None
+    }
+
+    /// Returns clones of the elements in sorted order.
+    ///
+    /// The vector is allocated for [`len`](Self::len) elements upfront, so it is not reallocated
+    /// unless the set grows meanwhile. Like [`fold`](Self::fold), the list is traversed with
+    /// lock-coupling.
+    pub fn to_sorted_vec(&self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.fold(Vec::with_capacity(self.len()), |mut values, data| {
+            values.push(data.clone());
+            values
+        })
+    }
+
+    /// Removes every element for which `f` returns `false`.
+    pub fn retain<F: FnMut(&T) -> bool>(&self, f: F) {
+        let _ = self.retain_reporting(f);
+    }
+
+    /// Like [`retain`](Self::retain), but returns the number of removed elements.
+    ///
+    /// The list is traversed once with lock-coupling, and the length is decremented per removal,
+    /// so concurrent readers of [`len`](Self::len) never see it run ahead of the list.
+    pub fn retain_reporting<F: FnMut(&T) -> bool>(&self, mut f: F) -> usize {
+        let mut count = 0;
+        self.remove_matching(|data| !f(data), |_| count += 1);
+        count
+    }
+
+    /// Removes every element for which `f` returns `true`, and returns them in sorted order.
+    ///
+    /// Like [`retain`](Self::retain), the list is traversed once with lock-coupling.
+    pub fn drain_filter<F: FnMut(&T) -> bool>(&self, f: F) -> Vec<T> {
+        let mut removed = Vec::new();
+        self.remove_matching(f, |data| removed.push(data));
+        removed
+    }
+
+    /// Unlinks every element for which `f` returns `true` in a single traversal, and passes it to
+    /// `removed`.
+    fn remove_matching<F: FnMut(&T) -> bool, G: FnMut(T)>(&self, mut f: F, mut removed: G) {
+        let mut cursor = Cursor(self.lock(&self.head));
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            if f(&node.data) {
+                removed(unsafe { self.unlink(&mut cursor) });
+            } else {
+                cursor = Cursor(self.lock(&node.next));
+            }
+        }
+    }
+
+    /// Removes and returns the smallest element.
+    pub fn pop_first(&self) -> Option<T> {
+        let mut cursor = Cursor(self.lock(&self.head));
+        if cursor.0.is_null() {
+            return None;
+        }
+        Some(unsafe { self.unlink(&mut cursor) })
+    }
+
+    /// Removes and returns the largest element.
+    ///
+    /// The list is walked with lock-coupling, so the predecessor of the last node stays locked and
+    /// the node cannot be changed by others when it is unlinked.
+    pub fn pop_last(&self) -> Option<T> {
+        let mut cursor = Cursor(self.lock(&self.head));
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            let next = self.lock(&node.next);
+            if next.is_null() {
+                // Nobody else can reach `node.next` while the cursor is held.
+                drop(next);
+                return Some(unsafe { self.unlink(&mut cursor) });
+            }
+            cursor = Cursor(next);
+        }
+        None
+    }
+
+    /// Removes all elements and returns them in sorted order.
+    ///
+    /// The whole list is detached at once under the lock of the head, and then it is freed without
+    /// holding that lock, so inserts that start afterwards go to the now empty set without waiting.
+    /// Operations that were already traversing the detached part finish there, and their effects
+    /// are reflected in the returned elements. The length may overstate until this returns.
+    pub fn take(&self) -> Vec<T> {
+        let mut values = Vec::new();
+        self.drain(|value| values.push(value));
+        values
+    }
+
+    /// Removes all elements. With a node cache, their allocations are kept for later insertions.
+    ///
+    /// Like [`take`](Self::take), the list is detached at once.
+    pub fn clear(&self) {
+        self.drain(drop);
+    }
+
+    /// Detaches the list at once, then frees its nodes one by one and passes their elements to
+    /// `f` in sorted order.
+    fn drain<F: FnMut(T)>(&self, mut f: F) {
+        let mut current = mem::replace(&mut *self.lock(&self.head), ptr::null_mut());
+        let mut count = 0;
+        while !current.is_null() {
+            // Waits until the traversals in the detached part move past the node.
+            let next = *self.lock(unsafe { &(*current).next });
+            f(unsafe { self.free_node(current) });
+            count += 1;
+            current = next;
+        }
+        let _ = self.len.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+impl<T: Ord> FineGrainedListSet<T> {
+    /// Applies `f` to every element in place, in sorted order.
+    ///
+    /// `f` must not change the ordering of the elements, e.g., it may only modify fields that are
+    /// ignored by `Ord`. Otherwise, the list is no longer sorted and later operations misbehave.
+    /// In debug builds, the order is validated afterwards.
+    ///
+    /// This requires `&mut self` because `iter` hands out references to elements that outlive the
+    /// node locks, so mutating an element while other threads may access the set is a data race.
+    pub fn for_each_mut<F: FnMut(&mut T)>(&mut self, mut f: F) {
+        let mut curr = *self.poison.unwrap(self.head.get_mut());
+        while let Some(node) = unsafe { curr.as_mut() } {
+            f(&mut node.data);
+            curr = *self.poison.unwrap(node.next.get_mut());
+        }
+        if cfg!(debug_assertions) {
+            self.check_invariants();
+        }
+    }
+
+    /// Walks the list and describes the first broken invariant, e.g., after modifying the elements
+    /// with [`for_each_mut`](Self::for_each_mut) or using a comparator that is not a total order.
+    ///
+    /// The list must be strictly sorted and acyclic. The positions in the message count from 0. The
+    /// walk uses lock-coupling, and cycles are detected before they are entered, so it terminates
+    /// even on a corrupted list.
+    pub fn verify(&self) -> Result<(), String>
+    where
+        T: fmt::Debug,
+    {
+        let mut positions = HashMap::new();
+        let mut cursor = Cursor(self.lock(&self.head));
+        let mut prev: Option<&T> = None;
+        loop {
+            let node_ptr = *cursor.0;
+            let Some(node) = (unsafe { node_ptr.as_ref() }) else {
+                return Ok(());
+            };
+            let position = positions.len();
+            if let Some(first) = positions.insert(node_ptr, position) {
+                return Err(format!(
+                    "cycle at position {position}: the list links back to position {first}"
+                ));
+            }
+            if let Some(prev) = prev {
+                match self.compare(prev, &node.data) {
+                    Less => {}
+                    Equal => {
+                        return Err(format!(
+                            "duplicate at position {position}: {:?} is equal to {prev:?}",
+                            node.data
+                        ))
+                    }
+                    Greater => {
+                        return Err(format!(
+                            "out of order at position {position}: {:?} follows {prev:?}",
+                            node.data
+                        ))
+                    }
+                }
+            }
+            // The previous node stays reachable while `cursor` is held.
+            prev = Some(&node.data);
+            cursor = Cursor(self.lock(&node.next));
+        }
+    }
+
+    /// Panics if the list is not strictly sorted.
+    fn check_invariants(&self) {
+        let mut cursor = Cursor(self.lock(&self.head));
+        while let Some(node) = unsafe { cursor.0.as_ref() } {
+            let next = Cursor(self.lock(&node.next));
+            if let Some(next_node) = unsafe { next.0.as_ref() } {
+                assert!(
+                    self.compare(&node.data, &next_node.data) == Less,
+                    "list is not strictly sorted"
+                );
+            }
+            cursor = next;
         }
-        true
     }
 }
 
 #[derive(Debug)]
 pub struct Iter<'l, T> {
     cursor: MutexGuard<'l, *mut Node<T>>,
+    poison: PoisonPolicy,
 }
 
 impl<T> FineGrainedListSet<T> {
     /// An iterator visiting all elements.
     pub fn iter(&self) -> Iter<'_, T> {
         Iter {
-            cursor: self.head.lock().unwrap(),
+            cursor: self.lock(&self.head),
+            poison: self.poison,
         }
     }
 }
 
+impl<T: Ord + Clone> FineGrainedListSet<T> {
+    /// An iterator visiting clones of the elements `>= key` in sorted order, e.g. to resume a
+    /// paginated scan after the last key of the previous page.
+    ///
+    /// Like [`iter`](Self::iter), it holds the lock of the next element until it is yielded.
+    pub fn iter_from(&self, key: &T) -> impl Iterator<Item = T> + '_ {
+        let (_, cursor) = self.find(key);
+        Iter {
+            cursor: cursor.0,
+            poison: self.poison,
+        }
+        .cloned()
+    }
+}
+
 impl<'l, T> Iterator for Iter<'l, T> {
     type Item = &'l T;
 
     fn next(&mut self) -> Option<Self::Item> {
         unsafe {
             if let Some(node) = self.cursor.as_ref() {
-                self.cursor = node.next.lock().unwrap();
+                self.cursor = self.poison.unwrap(node.next.lock());
                 Some(&node.data)
             } else {
                 None
@@ -143,7 +1287,46 @@ impl<'l, T> Iterator for Iter<'l, T> {
     }
 }
 
+#[derive(Debug)]
+pub struct IterCopied<'l, T> {
+    /// The lock of the pointer to the next element, or `None` once the iteration is over.
+    cursor: Option<MutexGuard<'l, *mut Node<T>>>,
+    poison: PoisonPolicy,
+}
+
+impl<T: Copy> FineGrainedListSet<T> {
+    /// An iterator visiting copies of all elements in sorted order.
+    ///
+    /// Unlike [`iter`](Self::iter), each element is copied before the lock of its node is
+    /// released, and only the lock of the next element is held between calls to `next`. So a
+    /// suspended iteration only blocks the writers that need to pass its position, and the writers
+    /// behind it proceed. Elements inserted or removed ahead of the iterator during the iteration
+    /// may or may not be observed.
+    pub fn iter_copied(&self) -> IterCopied<'_, T> {
+        IterCopied {
+            cursor: Some(self.lock(&self.head)),
+            poison: self.poison,
+        }
+    }
+}
+
+impl<T: Copy> Iterator for IterCopied<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // At the end, the lock of the last pointer is released, so that appends can proceed.
+        let cursor = self.cursor.take()?;
+        let node = unsafe { cursor.as_ref() }?;
+        let data = node.data;
+        self.cursor = Some(self.poison.unwrap(node.next.lock()));
+        drop(cursor);
+        Some(data)
+    }
+}
+
 impl<T> Drop for FineGrainedListSet<T> {
+    /// Drops the elements in sorted order, from the smallest one, so that resources held by them
+    /// are released deterministically.
     fn drop(&mut self) {
         // let mut cursor = Cursor(self.head.lock().unwrap());
         // unsafe {
@@ -153,11 +1336,12 @@ impl<T> Drop for FineGrainedListSet<T> {
         //         cursor = Cursor(next)
         //     }
         // }
-        let mut current_ptr = self.head.lock().unwrap();
+        // The list is consistent even if it is poisoned, so it can always be freed.
+        let mut current_ptr = PoisonPolicy::Recover.unwrap(self.head.lock());
         unsafe {
             while !current_ptr.is_null() {
                 let current_box = Box::from_raw(*current_ptr);
-                let next_ptr = *current_box.next.lock().unwrap();
+                let next_ptr = *PoisonPolicy::Recover.unwrap(current_box.next.lock());
                 // drop(current_box);
                 *current_ptr = next_ptr;
             }
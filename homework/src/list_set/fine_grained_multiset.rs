@@ -0,0 +1,145 @@
+use std::cmp::Ordering::*;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::PoisonPolicy;
+
+#[derive(Debug)]
+struct Node<T> {
+    data: T,
+    /// Number of occurrences of `data`, positive while the node is reachable. It is only modified
+    /// while holding the lock of the `next` field of the previous node.
+    count: AtomicUsize,
+    next: Mutex<*mut Node<T>>,
+}
+
+/// Concurrent sorted multiset using fine-grained lock-coupling.
+///
+/// Each distinct value is stored once in a node of the list, together with its number of
+/// occurrences.
+#[derive(Debug)]
+pub struct FineGrainedListMultiset<T> {
+    head: Mutex<*mut Node<T>>,
+    poison: PoisonPolicy,
+}
+
+// Same as `FineGrainedListSet`.
+unsafe impl<T: Send> Send for FineGrainedListMultiset<T> {}
+unsafe impl<T: Send + Sync> Sync for FineGrainedListMultiset<T> {}
+
+/// Reference to the `next` field of previous node which points to the current node. See the
+/// `Cursor` of `FineGrainedListSet`.
+struct Cursor<'l, T>(MutexGuard<'l, *mut Node<T>>);
+
+impl<T> Node<T> {
+    fn new(data: T, next: *mut Self) -> *mut Self {
+        Box::into_raw(Box::new(Self {
+            data,
+            count: AtomicUsize::new(1),
+            next: Mutex::new(next),
+        }))
+    }
+}
+
+impl<T: Ord> Cursor<'_, T> {
+    /// Moves the cursor to the position of key in the sorted list.
+    /// Returns whether the value was found.
+    fn find(&mut self, key: &T, multiset: &FineGrainedListMultiset<T>) -> bool {
+        while let Some(node) = unsafe { self.0.as_ref() } {
+            match node.data.cmp(key) {
+                Equal => return true,
+                Greater => return false,
+                Less => *self = Cursor(multiset.lock(&node.next)),
+            }
+        }
+        false
+    }
+}
+
+impl<T> FineGrainedListMultiset<T> {
+    /// Creates a new multiset.
+    pub fn new() -> Self {
+        Self::with_poison_policy(PoisonPolicy::default())
+    }
+
+    /// Creates a new multiset that handles poisoned locks according to `poison`.
+    ///
+    /// The only code that can panic while holding a lock is the comparison of the elements, which
+    /// runs before the list is modified, so `PoisonPolicy::Recover` is safe to use.
+    pub fn with_poison_policy(poison: PoisonPolicy) -> Self {
+        Self {
+            head: Mutex::new(ptr::null_mut()),
+            poison,
+        }
+    }
+
+    fn lock<'a, U>(&self, mutex: &'a Mutex<U>) -> MutexGuard<'a, U> {
+        self.poison.unwrap(mutex.lock())
+    }
+}
+
+impl<T: Ord> FineGrainedListMultiset<T> {
+    fn find(&self, key: &T) -> (bool, Cursor<'_, T>) {
+        let mut cursor = Cursor(self.lock(&self.head));
+        let found = cursor.find(key, self);
+        (found, cursor)
+    }
+
+    /// Returns the number of occurrences of `key`.
+    pub fn count(&self, key: &T) -> usize {
+        let (found, cursor) = self.find(key);
+        if !found {
+            return 0;
+        }
+        unsafe { &**cursor.0 }.count.load(Ordering::Relaxed)
+    }
+
+    /// Adds an occurrence of `key`, and returns the number of occurrences after the insertion.
+    pub fn insert(&self, key: T) -> usize {
+        let (found, mut cursor) = self.find(&key);
+        if found {
+            let node = unsafe { &**cursor.0 };
+            return node.count.fetch_add(1, Ordering::Relaxed) + 1;
+        }
+        *cursor.0 = Node::new(key, *cursor.0);
+        1
+    }
+
+    /// Removes an occurrence of `key`, and returns whether there was one. The node of `key` is
+    /// freed when its last occurrence is removed.
+    pub fn remove(&self, key: &T) -> bool {
+        let (found, mut cursor) = self.find(key);
+        if !found {
+            return false;
+        }
+        let node_ptr = *cursor.0;
+        let node = unsafe { &*node_ptr };
+        if node.count.fetch_sub(1, Ordering::Relaxed) > 1 {
+            return true;
+        }
+        let next_guard = self.lock(&node.next);
+        *cursor.0 = *next_guard;
+        drop(next_guard);
+        drop(unsafe { Box::from_raw(node_ptr) });
+        true
+    }
+}
+
+impl<T> Drop for FineGrainedListMultiset<T> {
+    fn drop(&mut self) {
+        // A lock is only poisoned by a comparison panicking in `find`, before any `next` pointer or
+        // count is written, so the nodes are freed even if the policy is `Propagate`.
+        let mut node_ptr = *PoisonPolicy::Recover.unwrap(self.head.get_mut());
+        while !node_ptr.is_null() {
+            let mut node = unsafe { Box::from_raw(node_ptr) };
+            node_ptr = *PoisonPolicy::Recover.unwrap(node.next.get_mut());
+        }
+    }
+}
+
+impl<T> Default for FineGrainedListMultiset<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
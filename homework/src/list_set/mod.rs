@@ -1,5 +1,11 @@
+mod dedup_window;
 mod fine_grained;
+mod fine_grained_multiset;
 mod optimistic_fine_grained;
+mod queue;
 
-pub use fine_grained::FineGrainedListSet;
+pub use dedup_window::DedupWindow;
+pub use fine_grained::{FineGrainedListSet, TryInsertError};
+pub use fine_grained_multiset::FineGrainedListMultiset;
 pub use optimistic_fine_grained::OptimisticFineGrainedListSet;
+pub use queue::ConcurrentQueue;
@@ -18,8 +18,11 @@ pub struct OptimisticFineGrainedListSet<T> {
     head: SeqLock<Atomic<Node<T>>>,
 }
 
+// Readers dereference the elements through `&self` under a seqlock read guard, which does not
+// exclude other readers or writers, so `Sync` needs `T: Sync`. Unlinked nodes are destroyed by
+// whichever thread collects the epoch garbage, so it also needs `T: Send`.
 unsafe impl<T: Send> Send for OptimisticFineGrainedListSet<T> {}
-unsafe impl<T: Send> Sync for OptimisticFineGrainedListSet<T> {}
+unsafe impl<T: Send + Sync> Sync for OptimisticFineGrainedListSet<T> {}
 
 #[derive(Debug)]
 struct Cursor<'g, T> {
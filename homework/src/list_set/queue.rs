@@ -0,0 +1,92 @@
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug)]
+struct Node<T> {
+    /// `None` only for the dummy node at the front.
+    data: Option<T>,
+    next: Mutex<*mut Node<T>>,
+}
+
+/// Concurrent FIFO queue using a lock for each end (the two-lock queue of Michael and Scott).
+///
+/// The front of the list is always a dummy node, so producers only lock the tail and consumers
+/// only lock the head, and they never block each other.
+#[derive(Debug)]
+pub struct ConcurrentQueue<T> {
+    /// Points to the dummy node.
+    head: Mutex<*mut Node<T>>,
+    /// Points to the last node, which is the dummy node if the queue is empty.
+    tail: Mutex<*mut Node<T>>,
+}
+
+unsafe impl<T: Send> Send for ConcurrentQueue<T> {}
+unsafe impl<T: Send> Sync for ConcurrentQueue<T> {}
+
+impl<T> Node<T> {
+    fn new(data: Option<T>) -> *mut Self {
+        Box::into_raw(Box::new(Self {
+            data,
+            next: Mutex::new(ptr::null_mut()),
+        }))
+    }
+}
+
+impl<T> ConcurrentQueue<T> {
+    /// Creates a new queue.
+    pub fn new() -> Self {
+        let dummy = Node::new(None);
+        Self {
+            head: Mutex::new(dummy),
+            tail: Mutex::new(dummy),
+        }
+    }
+
+    /// Adds the value to the back of the queue.
+    pub fn push_back(&self, value: T) {
+        let new_node = Node::new(Some(value));
+        let mut tail = self.tail.lock().unwrap();
+        *unsafe { &(**tail).next }.lock().unwrap() = new_node;
+        *tail = new_node;
+    }
+
+    /// Removes the value at the front of the queue, or returns `None` if the queue is empty.
+    pub fn pop_front(&self) -> Option<T> {
+        let mut head = self.head.lock().unwrap();
+        let dummy = *head;
+        let next = *unsafe { &(*dummy).next }.lock().unwrap();
+        if next.is_null() {
+            return None;
+        }
+        // `next` becomes the new dummy node. Producers only touch its `next` field.
+        let value = unsafe { (*next).data.take() };
+        *head = next;
+        drop(head);
+        drop(unsafe { Box::from_raw(dummy) });
+        value
+    }
+
+    /// Returns `true` iff the queue has no elements. It may be outdated if other threads modify
+    /// the queue.
+    pub fn is_empty(&self) -> bool {
+        let head = self.head.lock().unwrap();
+        let next = unsafe { &(**head).next }.lock().unwrap();
+        next.is_null()
+    }
+}
+
+impl<T> Drop for ConcurrentQueue<T> {
+    fn drop(&mut self) {
+        let mut current = *self.head.get_mut().unwrap();
+        while !current.is_null() {
+            let mut node = unsafe { Box::from_raw(current) };
+            current = *node.next.get_mut().unwrap();
+        }
+    }
+}
+
+impl<T> Default for ConcurrentQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
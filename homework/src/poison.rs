@@ -0,0 +1,26 @@
+//! Policies for handling poisoned locks.
+
+use std::sync::{LockResult, PoisonError};
+
+/// What to do when a lock is poisoned, i.e., when another thread panicked while holding it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PoisonPolicy {
+    /// Panics as well, so that a single panic makes the data structure unusable.
+    #[default]
+    Propagate,
+    /// Ignores the poison and keeps using the protected data.
+    ///
+    /// This is only sound if the data structure is left consistent whenever a panic can occur while
+    /// a lock is held.
+    Recover,
+}
+
+impl PoisonPolicy {
+    /// Extracts the guard from the result of a lock operation according to the policy.
+    pub fn unwrap<G>(self, result: LockResult<G>) -> G {
+        match self {
+            Self::Propagate => result.unwrap(),
+            Self::Recover => result.unwrap_or_else(PoisonError::into_inner),
+        }
+    }
+}
@@ -2,12 +2,15 @@
 
 use core::fmt::Debug;
 use core::hash::Hash;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::thread::scope;
 
 use super::map;
 use crate::test::RandGen;
 use crate::{ConcurrentMap, ConcurrentSet};
 
 use crossbeam_epoch::Guard;
+use rand::prelude::*;
 
 // A set can be seen as a map with value `()`. Thus, we can reuse the tests for maps.
 impl<T, S: ConcurrentSet<T>> ConcurrentMap<T, ()> for S {
@@ -61,3 +64,53 @@ pub fn log_concurrent<
 ) {
     map::log_concurrent::<T, (), S>(threads, steps);
 }
+
+/// Runs `threads` threads that repeatedly insert and remove a few `hot_keys` in `set` for `steps`
+/// operations each, so that they all contend on the same part of the set. This is where
+/// fine-grained locking degenerates to a single lock, and is prone to deadlocks and lost updates.
+///
+/// Each thread counts its successful insertions and removals per key, and in the end the set must
+/// contain exactly the keys whose count says so.
+pub fn hotspot_workload<T: Clone + Debug + Sync, S: Sync + ConcurrentSet<T>>(
+    set: &S,
+    hot_keys: &[T],
+    threads: usize,
+    steps: usize,
+) {
+    let initial = hot_keys
+        .iter()
+        .map(|key| set.contains(key) as isize)
+        .collect::<Vec<_>>();
+    let net = hot_keys
+        .iter()
+        .map(|_| AtomicIsize::new(0))
+        .collect::<Vec<_>>();
+
+    scope(|s| {
+        for _ in 0..threads {
+            let _ = s.spawn(|| {
+                let mut rng = thread_rng();
+                for _ in 0..steps {
+                    let index = rng.gen_range(0..hot_keys.len());
+                    let key = &hot_keys[index];
+                    if rng.gen_bool(0.5) {
+                        if set.insert(key.clone()) {
+                            let _ = net[index].fetch_add(1, Ordering::Relaxed);
+                        }
+                    } else if set.remove(key) {
+                        let _ = net[index].fetch_sub(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+
+    for (index, key) in hot_keys.iter().enumerate() {
+        let expected = initial[index] + net[index].load(Ordering::Relaxed);
+        assert!(
+            expected == 0 || expected == 1,
+            "{key:?} was inserted or removed twice in a row"
+        );
+        assert_eq!(set.contains(key), expected == 1, "{key:?}");
+    }
+}
@@ -1,9 +1,13 @@
 use crossbeam_channel::bounded;
-use cs431_homework::hello_server::Cache;
+use cs431_homework::hello_server::{
+    Cache, EvictionPolicy, L1L2Cache, Loader, ShardedCache, StampedeMode, ThreadPool, Timeout,
+};
+use cs431_homework::PoisonPolicy;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Barrier;
-use std::thread::scope;
-use std::time::Duration;
+use std::sync::{Arc, Barrier, Mutex, RwLock};
+use std::thread::{self, scope};
+use std::time::{Duration, Instant};
 
 const NUM_THREADS: usize = 8;
 const NUM_KEYS: usize = 128;
@@ -110,3 +114,1034 @@ fn cache_no_reader_block() {
         t1_quit_sender.send(()).unwrap();
     });
 }
+
+#[test]
+fn cache_entry_info() {
+    let cache = Cache::default();
+    assert_eq!(cache.entry_info(&1), None);
+
+    let before = Instant::now();
+    assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+    let info = cache.entry_info(&1).unwrap();
+    assert_eq!(info.hits, 0);
+    assert!(info.computed_at >= before);
+
+    for _ in 0..5 {
+        assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 1);
+    }
+    let info = cache.entry_info(&1).unwrap();
+    assert_eq!(info.hits, 5);
+    assert!(info.age() <= before.elapsed());
+    assert_eq!(cache.entry_info(&2), None);
+}
+
+#[test]
+fn cache_poison_recover() {
+    let cache = Cache::with_poison_policy(PoisonPolicy::Recover);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        cache.get_or_insert_with(1, |_| panic!("computation failed"))
+    }));
+    assert!(result.is_err());
+    // The poisoned entry is recomputed.
+    assert_eq!(cache.get_or_insert_with(1, |k| k + 1), 2);
+    assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 2);
+    assert_eq!(cache.get_or_insert_with(2, |k| k), 2);
+}
+
+#[test]
+#[should_panic]
+fn cache_poison_propagate() {
+    let cache = Cache::default();
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        cache.get_or_insert_with(1, |_| panic!("computation failed"))
+    }));
+    assert!(result.is_err());
+    let _ = cache.get_or_insert_with(1, |k| k);
+}
+
+/// A value that counts its clones.
+#[derive(Debug)]
+struct Document {
+    body: Vec<u8>,
+    copies: Arc<AtomicUsize>,
+}
+
+impl Clone for Document {
+    fn clone(&self) -> Self {
+        let _ = self.copies.fetch_add(1, Ordering::Relaxed);
+        Document {
+            body: self.body.clone(),
+            copies: self.copies.clone(),
+        }
+    }
+}
+
+#[test]
+fn cache_value_clones() {
+    let copies = Arc::new(AtomicUsize::new(0));
+    let cache = Cache::with_poison_policy(PoisonPolicy::default());
+    let make = |_| Document {
+        body: vec![1; 1024],
+        copies: copies.clone(),
+    };
+    let first = cache.get_or_insert_with(1, make);
+    // The computed value is copied into the cache.
+    assert_eq!(copies.load(Ordering::Relaxed), 1);
+    for _ in 0..3 {
+        let hit = cache.get_or_insert_with(1, |_| panic!());
+        assert_eq!(hit.body, first.body);
+        assert_ne!(hit.body.as_ptr(), first.body.as_ptr());
+    }
+    assert_eq!(copies.load(Ordering::Relaxed), 4);
+
+    // Cloning an `Arc` only clones the pointer.
+    let cache = Cache::default();
+    let first = cache.get_or_insert_with(1, |_| Arc::new(vec![1u8; 1024]));
+    for _ in 0..3 {
+        assert!(Arc::ptr_eq(
+            &cache.get_or_insert_with(1, |_| panic!()),
+            &first
+        ));
+    }
+}
+
+#[test]
+fn cache_with_cache() {
+    let cache = Cache::default();
+    assert_eq!(cache.get_or_insert_with(1, |_| 10), 10);
+    // Computing 2 reads the cached value of 1.
+    let value =
+        cache.get_or_insert_with_cache(2, |cache, k| cache.get_or_insert_with(1, |_| panic!()) + k);
+    assert_eq!(value, 12);
+    // Missing dependencies are computed along the way.
+    let value = cache.get_or_insert_with_cache(4, |cache, k| {
+        cache.get_or_insert_with_cache(k - 1, |cache, k| {
+            cache.get_or_insert_with(k - 1, |_| panic!()) * k
+        })
+    });
+    assert_eq!(value, 36);
+    assert_eq!(cache.get_or_insert_with(3, |_| panic!()), 36);
+}
+
+#[test]
+#[should_panic(expected = "requested the same key")]
+fn cache_reentrant_same_key() {
+    let cache = Cache::default();
+    // Other keys can be looked up during a computation.
+    assert_eq!(
+        cache.get_or_insert_with(1, |k| cache.get_or_insert_with(k + 1, |k| k * 2) + 1),
+        5
+    );
+    let _ = cache.get_or_insert_with(3, |k| cache.get_or_insert_with(k, |k| k));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn cache_async_no_duplicate() {
+    use std::sync::Arc;
+
+    let cache = Arc::new(Cache::default());
+    let num_compute = Arc::new(AtomicUsize::new(0));
+    let tasks = (0..NUM_THREADS)
+        .map(|_| {
+            let cache = Arc::clone(&cache);
+            let num_compute = Arc::clone(&num_compute);
+            tokio::spawn(async move {
+                cache
+                    .get_or_insert_with_async(1, |k| async move {
+                        let _ = num_compute.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        k
+                    })
+                    .await
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), 1);
+    }
+    assert_eq!(num_compute.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn cache_insert_returns_previous() {
+    let cache = Cache::default();
+    assert_eq!(cache.insert(1, 10), None);
+    assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 10);
+    assert_eq!(cache.insert(1, 11), Some(10));
+    assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 11);
+    assert_eq!(cache.get_or_insert_with(2, |k| k), 2);
+    assert_eq!(cache.insert(2, 20), Some(2));
+}
+
+#[test]
+fn cache_compute_concurrent() {
+    const STEPS: usize = 1000;
+
+    let cache = Cache::default();
+    scope(|s| {
+        for _ in 0..NUM_THREADS {
+            let _ = s.spawn(|| {
+                for _ in 0..STEPS {
+                    let _ = cache.compute("counter", |v| v.unwrap_or(0) + 1);
+                }
+            });
+        }
+    });
+    assert_eq!(
+        cache.get_or_insert_with("counter", |_| panic!()),
+        NUM_THREADS * STEPS
+    );
+    assert_eq!(cache.compute("other", |v| v.map_or(1, |v| v * 2)), 1);
+    assert_eq!(cache.compute("other", |v| v.map_or(1, |v| v * 2)), 2);
+}
+
+#[test]
+fn cache_status_concurrent() {
+    let cache = Cache::default();
+    let computed = AtomicUsize::new(0);
+    scope(|s| {
+        for _ in 0..NUM_THREADS {
+            let _ = s.spawn(|| {
+                let (v, ran) = cache.get_or_insert_with_status(1, |k| {
+                    thread::sleep(Duration::from_millis(50));
+                    k + 1
+                });
+                assert_eq!(v, 2);
+                if ran {
+                    let _ = computed.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    assert_eq!(computed.load(Ordering::Relaxed), 1);
+    assert_eq!(cache.get_or_insert_with_status(1, |_| panic!()), (2, false));
+}
+
+#[test]
+fn cache_invalidate_matching() {
+    let cache = Cache::default();
+    for k in 0..NUM_KEYS {
+        assert_eq!(cache.get_or_insert_with(k, |k| k), k);
+    }
+    assert_eq!(cache.invalidate_matching(|k| k % 2 == 0), NUM_KEYS / 2);
+    assert_eq!(cache.invalidate_matching(|k| k % 2 == 0), 0);
+    for k in 0..NUM_KEYS {
+        if k % 2 == 0 {
+            assert_eq!(cache.get_or_insert_with(k, |k| k + 1), k + 1);
+        } else {
+            assert_eq!(cache.get_or_insert_with(k, |_| panic!()), k);
+        }
+    }
+}
+
+#[test]
+fn cache_combined_options() {
+    let cache = Cache::new()
+        .with_capacity(2)
+        .with_policy(EvictionPolicy::Lfu)
+        .with_ttl(Duration::from_millis(50))
+        .with_max_concurrent_fills(1);
+    assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+    assert_eq!(cache.get_or_insert_with(1, |_| unreachable!()), 1);
+    assert_eq!(cache.get_or_insert_with(2, |k| k), 2);
+
+    // The capacity and the eviction policy apply together.
+    assert_eq!(cache.get_or_insert_with(3, |k| k), 3);
+    assert_eq!(cache.len(), 2);
+    assert!(cache.entry_info(&1).is_some());
+    assert!(cache.entry_info(&2).is_none());
+
+    // So does the TTL.
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(cache.get_or_insert_with(1, |k| k + 10), 11);
+}
+
+#[test]
+fn cache_ttl_sweeper() {
+    let cache = Cache::new().with_ttl(Duration::from_millis(50));
+    for k in 0..NUM_KEYS {
+        assert_eq!(cache.get_or_insert_with(k, |k| k), k);
+    }
+    // Expired values are recomputed.
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(cache.get_or_insert_with(0, |k| k + 1), 1);
+    assert_eq!(cache.len(), NUM_KEYS);
+
+    let _sweeper = cache.start_sweeper(Duration::from_millis(5));
+    let start = Instant::now();
+    while !cache.is_empty() {
+        assert!(
+            start.elapsed() < Duration::from_secs(3),
+            "expired entries were not swept"
+        );
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn cache_invalidate_all() {
+    let cache = Cache::default();
+    for k in 0..NUM_KEYS {
+        assert_eq!(cache.get_or_insert_with(k, |k| k), k);
+    }
+    cache.invalidate_all();
+    assert_eq!(cache.entry_info(&0), None);
+    // Invalidated values are recomputed.
+    assert_eq!(cache.get_or_insert_with(0, |k| k + 1), 1);
+    assert_eq!(cache.get_or_insert_with(0, |_| panic!()), 1);
+    assert_eq!(cache.len(), NUM_KEYS);
+
+    // The sweeper drops the rest.
+    let _sweeper = cache.start_sweeper(Duration::from_millis(5));
+    let start = Instant::now();
+    while cache.len() > 1 {
+        assert!(
+            start.elapsed() < Duration::from_secs(3),
+            "invalidated entries were not swept"
+        );
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(cache.get_or_insert_with(0, |_| panic!()), 1);
+}
+
+#[test]
+fn cache_pin() {
+    let cache = Cache::new().with_capacity(3);
+    assert_eq!(cache.get_or_insert_with(0, |k| k), 0);
+    cache.pin(&0);
+    // A key can be pinned before it is computed.
+    cache.pin(&1);
+    assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+    for k in 2..10 {
+        assert_eq!(cache.get_or_insert_with(k, |k| k), k);
+    }
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.get_or_insert_with(0, |_| panic!()), 0);
+    assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 1);
+    assert_eq!(cache.get_or_insert_with(9, |_| panic!()), 9);
+
+    cache.unpin(&0);
+    assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 1);
+    assert_eq!(cache.get_or_insert_with(9, |_| panic!()), 9);
+    // 0 is now the least recently used.
+    assert_eq!(cache.get_or_insert_with(10, |k| k), 10);
+    assert_eq!(cache.get_or_insert_with(0, |k| k + 1), 1);
+}
+
+#[test]
+fn cache_lru_capacity_invalidated_first() {
+    let cache = Cache::new().with_capacity(2);
+    assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+    cache.invalidate_all();
+    assert_eq!(cache.get_or_insert_with(2, |k| k), 2);
+    assert_eq!(cache.get_or_insert_with(2, |_| panic!()), 2);
+    // The invalidated 1 is evicted although 2 was used more recently.
+    assert_eq!(cache.get_or_insert_with(3, |k| k), 3);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get_or_insert_with(2, |_| panic!()), 2);
+}
+
+#[test]
+fn cache_lru_capacity() {
+    let cache = Cache::new().with_capacity(2);
+    assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+    assert_eq!(cache.get_or_insert_with(2, |k| k), 2);
+    assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 1);
+    // 2 is the least recently used.
+    assert_eq!(cache.get_or_insert_with(3, |k| k), 3);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 1);
+    assert_eq!(cache.get_or_insert_with(3, |_| panic!()), 3);
+    assert_eq!(cache.get_or_insert_with(2, |k| k + 10), 12);
+    assert_eq!(cache.len(), 2);
+}
+
+#[test]
+fn l1l2_cache_hits_l1() {
+    let cache = L1L2Cache::new(4);
+    assert_eq!(cache.get_or_insert_with(1, |k| k * 10), 10);
+    let lookups = cache.l2_lookups();
+    for _ in 0..100 {
+        assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 10);
+    }
+    assert_eq!(cache.l2_lookups(), lookups);
+
+    // Other threads have their own L1.
+    scope(|s| {
+        let _ = s.spawn(|| assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 10));
+    });
+    assert_eq!(cache.l2_lookups(), lookups + 1);
+
+    // The L1 is bounded.
+    for key in 2..10 {
+        assert_eq!(cache.get_or_insert_with(key, |k| k * 10), key * 10);
+    }
+    let lookups = cache.l2_lookups();
+    for key in 1..10 {
+        assert_eq!(cache.get_or_insert_with(key, |_| panic!()), key * 10);
+    }
+    assert!(cache.l2_lookups() - lookups >= 9 - 4);
+
+    // Writes invalidate the L1 of every thread.
+    assert_eq!(cache.insert(1, 11), Some(10));
+    assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 11);
+    scope(|s| {
+        let _ = s.spawn(|| assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 11));
+    });
+}
+
+#[test]
+fn sharded_cache_local_eviction() {
+    const SHARDS: usize = 4;
+    const PER_SHARD: usize = 2;
+
+    let cache = ShardedCache::with_capacity(SHARDS, PER_SHARD);
+    let mut hot = (0..).filter(|k| cache.shard_of(k) == 0);
+    let cold = (0..).find(|k| cache.shard_of(k) == 1).unwrap();
+    assert_eq!(cache.get_or_insert_with(cold, |k| k), cold);
+
+    for _ in 0..10 * PER_SHARD {
+        let k = hot.next().unwrap();
+        assert_eq!(cache.get_or_insert_with(k, |k| k), k);
+        assert!(cache.shard_len(0) <= PER_SHARD);
+    }
+    assert_eq!(cache.shard_len(0), PER_SHARD);
+    assert_eq!(cache.shard_len(1), 1);
+    assert_eq!(cache.len(), PER_SHARD + 1);
+    assert_eq!(cache.get_or_insert_with(cold, |_| panic!()), cold);
+}
+
+/// Loader that counts how many times each key was loaded.
+#[derive(Debug, Default)]
+struct CountingLoader {
+    loads: Vec<AtomicUsize>,
+}
+
+impl Loader<usize, usize> for CountingLoader {
+    fn load(&self, key: &usize) -> usize {
+        let _ = self.loads[*key].fetch_add(1, Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(1));
+        key * 2
+    }
+}
+
+#[test]
+fn cache_read_through() {
+    let loader = CountingLoader {
+        loads: (0..NUM_KEYS).map(|_| AtomicUsize::new(0)).collect(),
+    };
+    let cache = Cache::default();
+    scope(|s| {
+        for _ in 0..NUM_THREADS {
+            let _ = s.spawn(|| {
+                let loader: &dyn Loader<usize, usize> = &loader;
+                for k in 0..NUM_KEYS {
+                    assert_eq!(cache.read_through(k, loader), k * 2);
+                }
+            });
+        }
+    });
+    assert!(loader
+        .loads
+        .iter()
+        .all(|loads| loads.load(Ordering::Relaxed) == 1));
+    assert_eq!(cache.read_through(1, &|_: &usize| unreachable!()), 2);
+}
+
+#[test]
+fn cache_read_through_pooled() {
+    const REQUESTS: usize = 10;
+
+    let threads = Arc::new(Mutex::new(Vec::new()));
+    let (release_sender, release_receiver) = bounded::<()>(0);
+    let loader = {
+        let threads = Arc::clone(&threads);
+        move |key: &usize| {
+            let _ = release_receiver.recv();
+            threads.lock().unwrap().push(thread::current().id());
+            key * 2
+        }
+    };
+    let pool = ThreadPool::new(4);
+    let cache = Cache::default();
+    let barrier = Barrier::new(REQUESTS);
+    let (requesters, values) = scope(|s| {
+        let handles = (0..REQUESTS)
+            .map(|_| {
+                s.spawn(|| {
+                    let _ = barrier.wait();
+                    (
+                        thread::current().id(),
+                        cache.read_through_pooled(1, &loader, &pool),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        // The requests return while the load is still blocked.
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .unzip::<_, _, Vec<_>, Vec<_>>()
+    });
+    drop(release_sender);
+    assert!(values.iter().all(|value| value.recv() == Ok(2)));
+    let threads = threads.lock().unwrap();
+    assert_eq!(threads.len(), 1);
+    assert!(!requesters.contains(&threads[0]));
+    assert_ne!(threads[0], thread::current().id());
+
+    // A hit is ready right away.
+    assert_eq!(
+        cache
+            .read_through_pooled(1, &|_: &usize| unreachable!(), &pool)
+            .try_recv(),
+        Ok(2)
+    );
+}
+
+#[test]
+fn cache_timeout_in_flight() {
+    let cache = Cache::default();
+    let (started_sender, started_receiver) = bounded(0);
+    scope(|s| {
+        let _ = s.spawn(|| {
+            cache.get_or_insert_with(1, |k| {
+                started_sender.send(()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+                k + 1
+            })
+        });
+        started_receiver.recv().unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            cache.get_or_insert_with_timeout(1, Duration::from_millis(20), |_| panic!()),
+            Err(Timeout)
+        );
+        assert!(start.elapsed() < Duration::from_millis(200));
+        // Other keys are not affected.
+        assert_eq!(
+            cache.get_or_insert_with_timeout(2, Duration::from_millis(20), |k| k),
+            Ok(2)
+        );
+    });
+    // The slow computation still cached its value.
+    assert_eq!(
+        cache.get_or_insert_with_timeout(1, Duration::from_millis(20), |_| panic!()),
+        Ok(2)
+    );
+}
+
+#[test]
+fn cache_compare_and_set_race() {
+    let cache = Cache::default();
+    assert!(!cache.compare_and_set(&1, &10, 11));
+    let _ = cache.insert(1, 10);
+    let barrier = Barrier::new(2);
+    let succeeded = AtomicUsize::new(0);
+    scope(|s| {
+        for new in [20, 30] {
+            let (cache, barrier, succeeded) = (&cache, &barrier, &succeeded);
+            let _ = s.spawn(move || {
+                let _ = barrier.wait();
+                if cache.compare_and_set(&1, &10, new) {
+                    let _ = succeeded.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    assert_eq!(succeeded.load(Ordering::Relaxed), 1);
+    let v = cache.get_or_insert_with(1, |_| panic!());
+    assert!(v == 20 || v == 30);
+    assert!(!cache.compare_and_set(&1, &10, 40));
+}
+
+#[test]
+fn cache_weak_recompute_after_drop() {
+    let cache = Cache::weak();
+    let num_compute = AtomicUsize::new(0);
+    let compute = |k: usize| {
+        let _ = num_compute.fetch_add(1, Ordering::Relaxed);
+        vec![k; 1000]
+    };
+    let first = cache.get_or_insert_arc_with(1, compute);
+    let second = cache.get_or_insert_arc_with(1, compute);
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(num_compute.load(Ordering::Relaxed), 1);
+
+    drop((first, second));
+    let third = cache.get_or_insert_arc_with(1, compute);
+    assert_eq!(*third, vec![1; 1000]);
+    assert_eq!(num_compute.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn cache_weak_hits_and_ttl() {
+    let cache = Cache::new().with_ttl(Duration::from_millis(100));
+    let num_compute = AtomicUsize::new(0);
+    let compute = |k: usize| {
+        let _ = num_compute.fetch_add(1, Ordering::Relaxed);
+        vec![k; 1000]
+    };
+    let first = cache.get_or_insert_arc_with(1, compute);
+    let computed_at = Instant::now();
+    for _ in 0..3 {
+        assert!(Arc::ptr_eq(
+            &first,
+            &cache.get_or_insert_arc_with(1, compute)
+        ));
+    }
+    // Hits neither recompute nor restart the TTL.
+    assert_eq!(num_compute.load(Ordering::Relaxed), 1);
+    let info = cache.entry_info(&1).unwrap();
+    assert_eq!(info.hits, 3);
+    assert!(info.age() >= computed_at.elapsed());
+
+    // The value expires even though it is alive.
+    thread::sleep(Duration::from_millis(150));
+    let second = cache.get_or_insert_arc_with(1, compute);
+    assert!(!Arc::ptr_eq(&first, &second));
+    assert_eq!(num_compute.load(Ordering::Relaxed), 2);
+}
+
+/// Key that counts how many times it is cloned.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct CloneCounted(usize);
+
+static KEY_CLONES: AtomicUsize = AtomicUsize::new(0);
+
+impl Clone for CloneCounted {
+    fn clone(&self) -> Self {
+        let _ = KEY_CLONES.fetch_add(1, Ordering::Relaxed);
+        Self(self.0)
+    }
+}
+
+#[test]
+fn cache_arc_key_clones_once() {
+    const KEYS: usize = 5;
+
+    let cache = Cache::arc_keys();
+    scope(|s| {
+        for _ in 0..NUM_THREADS {
+            let _ = s.spawn(|| {
+                for k in 0..KEYS {
+                    for _ in 0..10 {
+                        let key = CloneCounted(k);
+                        assert_eq!(cache.get_or_insert_with_ref(&key, |key| key.0 * 2), k * 2);
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(cache.len(), KEYS);
+    assert_eq!(KEY_CLONES.load(Ordering::Relaxed), KEYS);
+}
+
+#[cfg(feature = "snapshot")]
+#[test]
+fn cache_snapshot_round_trip() {
+    let cache = Cache::default();
+    for i in 0..10 {
+        let _ = cache.get_or_insert_with(format!("key{i}"), |_| i * 10);
+    }
+    let (started_sender, started_receiver) = bounded(0);
+    let mut buf = Vec::new();
+    scope(|s| {
+        let _ = s.spawn(|| {
+            cache.get_or_insert_with("in flight".to_string(), |_| {
+                started_sender.send(()).unwrap();
+                thread::sleep(Duration::from_millis(100));
+                0
+            })
+        });
+        started_receiver.recv().unwrap();
+        cache.snapshot_to(&mut buf).unwrap();
+    });
+
+    let restored = Cache::default();
+    assert_eq!(restored.load_from(buf.as_slice()).unwrap(), 10);
+    assert_eq!(restored.len(), 10);
+    for i in 0..10 {
+        assert_eq!(
+            restored.get_or_insert_with(format!("key{i}"), |_| panic!()),
+            i * 10
+        );
+    }
+    assert_eq!(
+        restored.get_or_insert_with("in flight".to_string(), |_| 1),
+        1
+    );
+
+    // Truncated data is rejected.
+    buf.truncate(buf.len() - 1);
+    assert!(Cache::<String, usize>::default()
+        .load_from(buf.as_slice())
+        .is_err());
+}
+
+#[test]
+fn cache_max_concurrent_fills() {
+    let cache = Cache::new().with_max_concurrent_fills(2);
+    let running = AtomicUsize::new(0);
+    let max_running = AtomicUsize::new(0);
+    scope(|s| {
+        for i in 0..NUM_THREADS {
+            let (cache, running, max_running) = (&cache, &running, &max_running);
+            let _ = s.spawn(move || {
+                assert_eq!(
+                    cache.get_or_insert_with(i, |k| {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        let _ = max_running.fetch_max(now, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(20));
+                        let _ = running.fetch_sub(1, Ordering::SeqCst);
+                        k
+                    }),
+                    i
+                );
+            });
+        }
+    });
+    assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    assert_eq!(cache.len(), NUM_THREADS);
+}
+
+#[test]
+fn cache_nested_fills_within_limit() {
+    let cache = Cache::new().with_max_concurrent_fills(1);
+    let (done_sender, done_receiver) = bounded(1);
+    scope(|s| {
+        let cache = &cache;
+        let _ = s.spawn(move || {
+            // Each level is computed while the one above holds the only slot.
+            let v = cache.get_or_insert_with_cache(3, |cache, k| {
+                cache.get_or_insert_with_cache(k - 1, |cache, k| {
+                    cache.get_or_insert_with(k - 1, |k| k) + k
+                }) + k
+            });
+            done_sender.send(v).unwrap();
+        });
+        assert_eq!(done_receiver.recv_timeout(Duration::from_secs(3)), Ok(6));
+    });
+    assert_eq!(cache.len(), 3);
+    // The slot is released, and can be taken by another thread.
+    assert_eq!(
+        thread::scope(|s| s
+            .spawn(|| cache.get_or_insert_with(4, |k| k))
+            .join()
+            .unwrap()),
+        4
+    );
+}
+
+#[test]
+fn cache_in_flight_keys() {
+    let cache = Cache::default();
+    let _ = cache.get_or_insert_with(1, |k| k);
+    let (started_sender, started_receiver) = bounded(0);
+    let (finish_sender, finish_receiver) = bounded::<()>(0);
+    scope(|s| {
+        let _ = s.spawn(|| {
+            cache.get_or_insert_with(2, |k| {
+                started_sender.send(()).unwrap();
+                finish_receiver.recv().unwrap();
+                k
+            })
+        });
+        started_receiver.recv().unwrap();
+        assert_eq!(cache.in_flight_keys(), [2]);
+        finish_sender.send(()).unwrap();
+    });
+    assert!(cache.in_flight_keys().is_empty());
+}
+
+#[test]
+fn cache_hits_share_entry_lock() {
+    let cache = Cache::default();
+    assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+    assert_eq!(cache.exclusive_lookups(), 1);
+    let barrier = Barrier::new(NUM_THREADS);
+    scope(|s| {
+        for _ in 0..NUM_THREADS {
+            let _ = s.spawn(|| {
+                let _ = barrier.wait();
+                for _ in 0..1000 {
+                    assert_eq!(cache.get_or_insert_with(1, |_| unreachable!()), 1);
+                }
+            });
+        }
+    });
+    assert_eq!(cache.entry_info(&1).unwrap().hits, NUM_THREADS * 1000);
+    assert_eq!(cache.exclusive_lookups(), 1);
+}
+
+#[test]
+fn cache_computed_ttl() {
+    let cache = Cache::new().with_ttl(Duration::from_secs(10));
+    let calls = AtomicUsize::new(0);
+    let compute = |k: usize| {
+        let _ = calls.fetch_add(1, Ordering::SeqCst);
+        (k, Duration::from_millis(100 * k as u64))
+    };
+    assert_eq!(cache.get_or_insert_with_computed_ttl(1, compute), 1);
+    assert_eq!(cache.get_or_insert_with_computed_ttl(3, compute), 3);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // Key 1 expired, but not key 3.
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(cache.get_or_insert_with_computed_ttl(1, compute), 1);
+    assert_eq!(cache.get_or_insert_with_computed_ttl(3, compute), 3);
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // Key 3 expired too.
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(cache.get_or_insert_with_computed_ttl(3, compute), 3);
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+    // A plain recomputation falls back to the TTL of the cache.
+    thread::sleep(Duration::from_millis(350));
+    assert_eq!(cache.get_or_insert_with(3, |k| k), 3);
+    thread::sleep(Duration::from_millis(350));
+    let (_, computed) = cache.get_or_insert_with_status(3, |k| k);
+    assert!(!computed);
+}
+
+#[test]
+fn cache_on_evict() {
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    let cache = Cache::new().with_capacity(2).on_evict({
+        let evicted = evicted.clone();
+        move |k, v| evicted.lock().unwrap().push((k, v))
+    });
+    for i in 0..3 {
+        assert_eq!(cache.get_or_insert_with(i, |k| k * 10), i * 10);
+    }
+    assert_eq!(*evicted.lock().unwrap(), [(0, 0)]);
+
+    // Dropping the cache flushes the remaining entries, but not the invalidated ones.
+    assert_eq!(cache.invalidate_matching(|k| *k == 1), 1);
+    assert_eq!(cache.get_or_insert_with(3, |k| k * 10), 30);
+    drop(cache);
+    let mut evicted = evicted.lock().unwrap().clone();
+    evicted.sort();
+    assert_eq!(evicted, [(0, 0), (2, 20), (3, 30)]);
+}
+
+#[test]
+fn cache_replace_all() {
+    let cache = Cache::default();
+    for i in 0..10 {
+        assert_eq!(cache.get_or_insert_with(i, |k| k), i);
+    }
+    cache.replace_all((5..15).map(|k| (k, k * 10)));
+    assert_eq!(cache.len(), 10);
+    for i in 0..5 {
+        assert!(cache.entry_info(&i).is_none());
+    }
+    for i in 5..15 {
+        assert_eq!(cache.get_or_insert_with(i, |_| unreachable!()), i * 10);
+    }
+}
+
+#[test]
+fn cache_arc_cell() {
+    let cache = Cache::default();
+    let cell: Arc<RwLock<Option<usize>>> = cache.get_or_insert_arc_cell(1, |k| k);
+    assert_eq!(*cell.read().unwrap(), Some(1));
+    assert!(Arc::ptr_eq(
+        &cell,
+        &cache.get_or_insert_arc_cell(1, |_| unreachable!())
+    ));
+
+    // Updates of the entry are visible through the cell.
+    assert_eq!(cache.update(&1, |v| v + 1), Some(2));
+    assert_eq!(*cell.read().unwrap(), Some(2));
+    assert_eq!(cache.update(&2, |_| unreachable!()), None);
+    assert!(cache.entry_info(&2).is_none());
+    assert_eq!(cache.insert(1, 3), Some(2));
+    assert_eq!(cache.compute(1, |v| v.unwrap() + 1), 4);
+    assert_eq!(*cell.read().unwrap(), Some(4));
+
+    // Writes through the cell are served by the cache.
+    *cell.write().unwrap() = Some(5);
+    assert_eq!(cache.get_or_insert_with(1, |_| unreachable!()), 5);
+
+    // A removed entry is detached from the cache.
+    assert_eq!(cache.invalidate_matching(|_| true), 1);
+    assert_eq!(cache.get_or_insert_with(1, |_| 10), 10);
+    assert_eq!(*cell.read().unwrap(), Some(5));
+}
+
+#[test]
+fn cache_lfu_capacity() {
+    for (policy, evicted) in [(EvictionPolicy::Lru, 1), (EvictionPolicy::Lfu, 2)] {
+        let cache = Cache::new().with_capacity(2).with_policy(policy);
+        assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+        for _ in 0..5 {
+            assert_eq!(cache.get_or_insert_with(1, |_| unreachable!()), 1);
+        }
+        // Key 2 is hit once, more recently than key 1.
+        assert_eq!(cache.get_or_insert_with(2, |k| k), 2);
+        assert_eq!(cache.get_or_insert_with(2, |_| unreachable!()), 2);
+
+        assert_eq!(cache.get_or_insert_with(3, |k| k), 3);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.entry_info(&evicted).is_none(), "{policy:?}");
+        assert!(cache.entry_info(&3).is_some());
+    }
+}
+
+#[test]
+fn cache_clock_keeps_hot_entries() {
+    for policy in [EvictionPolicy::Lru, EvictionPolicy::Lfu] {
+        let cache = Cache::new().with_capacity(4).with_policy(policy);
+        assert_eq!(cache.get_or_insert_with(0, |k| k), 0);
+        assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+        for k in 2..1000 {
+            // The hot keys are hit between the one-off keys, so they are never evicted.
+            assert_eq!(cache.get_or_insert_with(0, |_| unreachable!()), 0);
+            assert_eq!(cache.get_or_insert_with(1, |_| unreachable!()), 1);
+            assert_eq!(cache.get_or_insert_with(k, |k| k), k);
+            assert!(cache.len() <= 4, "{policy:?}");
+            if k % 100 == 0 {
+                // Removed entries leave stale slots behind in the clock.
+                assert_eq!(cache.invalidate_matching(|key| *key == k), 1);
+            }
+        }
+    }
+}
+
+#[test]
+fn cache_stampede_mode() {
+    fn stampede(cache: &Cache<usize, usize>, barrier: Option<&Barrier>) -> usize {
+        let calls = AtomicUsize::new(0);
+        let start = Barrier::new(NUM_THREADS);
+        let values = scope(|s| {
+            let handles = (0..NUM_THREADS)
+                .map(|i| {
+                    let (calls, start) = (&calls, &start);
+                    s.spawn(move || {
+                        let _ = start.wait();
+                        cache.get_or_insert_with(1, |_| {
+                            let _ = calls.fetch_add(1, Ordering::SeqCst);
+                            match barrier {
+                                // Every caller computes at the same time.
+                                Some(barrier) => drop(barrier.wait()),
+                                None => thread::sleep(Duration::from_millis(100)),
+                            }
+                            i
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        // Every caller returns the stored value.
+        assert!(values.iter().all(|&v| v == values[0]));
+        assert_eq!(cache.get_or_insert_with(1, |_| unreachable!()), values[0]);
+        calls.load(Ordering::SeqCst)
+    }
+
+    assert_eq!(
+        stampede(&Cache::new().with_stampede(StampedeMode::Dedup), None),
+        1
+    );
+    let barrier = Barrier::new(NUM_THREADS);
+    assert_eq!(
+        stampede(
+            &Cache::new().with_stampede(StampedeMode::AllowDuplicate),
+            Some(&barrier)
+        ),
+        NUM_THREADS
+    );
+}
+
+#[test]
+fn cache_racing_reentrant_same_key() {
+    let cache = Cache::new().with_stampede(StampedeMode::AllowDuplicate);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        cache.get_or_insert_with(1, |k| cache.get_or_insert_with(k, |k| k))
+    }));
+    let message = *result.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.contains("requested the same key"));
+    // The check does not poison the entry.
+    assert_eq!(cache.get_or_insert_with(1, |k| k + 1), 2);
+    assert_eq!(cache.get_or_insert_with(1, |_| unreachable!()), 2);
+}
+
+#[test]
+fn cache_racing_store_during_compute() {
+    let cache = Cache::new().with_stampede(StampedeMode::AllowDuplicate);
+    assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+    let (started_sender, started_receiver) = bounded(0);
+    let (raced_sender, raced_receiver) = bounded(0);
+    thread::scope(|s| {
+        let computing = s.spawn(|| {
+            cache.compute(1, |prev| {
+                started_sender.send(()).unwrap();
+                raced_receiver.recv().unwrap();
+                prev.unwrap() + 10
+            })
+        });
+        started_receiver.recv().unwrap();
+        // The value raced with the computation is not stored, so it is not overwritten silently.
+        assert_eq!(cache.get_or_insert_with_status(1, |_| 20), (20, false));
+        raced_sender.send(()).unwrap();
+        assert_eq!(computing.join().unwrap(), 11);
+    });
+    assert_eq!(cache.get_or_insert_with(1, |_| unreachable!()), 11);
+
+    // A computation does not overwrite a value stored by a racing lookup that finished first.
+    let cache = Cache::new().with_stampede(StampedeMode::AllowDuplicate);
+    let (started_sender, started_receiver) = bounded(0);
+    let (computed_sender, computed_receiver) = bounded(0);
+    thread::scope(|s| {
+        let racing = s.spawn(|| {
+            cache.get_or_insert_with_status(1, |_| {
+                started_sender.send(()).unwrap();
+                computed_receiver.recv().unwrap();
+                20
+            })
+        });
+        started_receiver.recv().unwrap();
+        assert_eq!(cache.compute(1, |prev| prev.map_or(10, |v| v + 10)), 10);
+        computed_sender.send(()).unwrap();
+        assert_eq!(racing.join().unwrap(), (10, false));
+    });
+    assert_eq!(cache.get_or_insert_with(1, |_| unreachable!()), 10);
+}
+
+#[test]
+fn cache_try_insert_with_arc() {
+    let cache = Cache::default();
+    let num_compute = &AtomicUsize::new(0);
+    let load = |fail: bool| {
+        move |k: usize| {
+            let _ = num_compute.fetch_add(1, Ordering::Relaxed);
+            if fail {
+                Err("unreachable host")
+            } else {
+                Ok(vec![k; 1000])
+            }
+        }
+    };
+
+    assert_eq!(
+        cache.get_or_try_insert_with_arc(1, load(true)),
+        Err("unreachable host")
+    );
+    // The failure is not cached, not even as an empty entry.
+    assert!(cache.is_empty());
+
+    let first = cache.get_or_try_insert_with_arc(1, load(false)).unwrap();
+    assert_eq!(*first, vec![1; 1000]);
+    let second = cache.get_or_try_insert_with_arc(1, load(true)).unwrap();
+    let third = cache.get_or_insert_with(1, |_| unreachable!());
+    assert!(Arc::ptr_eq(&first, &second));
+    assert!(Arc::ptr_eq(&first, &third));
+    assert_eq!(num_compute.load(Ordering::Relaxed), 2);
+    assert_eq!(cache.len(), 1);
+}
@@ -0,0 +1,56 @@
+use std::thread;
+
+use cs431_homework::DedupWindow;
+
+#[test]
+fn evicts_oldest() {
+    let window = DedupWindow::new(3);
+    assert!(window.insert(10));
+    assert!(window.insert(5));
+    assert!(window.insert(7));
+    assert!(!window.insert(10));
+    assert_eq!(window.len(), 3);
+
+    // The earliest inserted element is evicted, regardless of its order in the set.
+    assert!(window.insert(1));
+    assert_eq!(window.len(), 3);
+    assert!(!window.contains(&10));
+    for key in [5, 7, 1] {
+        assert!(window.contains(&key));
+    }
+    assert!(window.insert(2));
+    assert!(!window.contains(&5));
+
+    // An evicted element is new again.
+    assert!(window.insert(10));
+    assert!(!window.contains(&7));
+}
+
+#[test]
+fn concurrent_stream() {
+    const THREADS: usize = 4;
+    const IDS: usize = 1000;
+    const CAPACITY: usize = 16;
+
+    let window = DedupWindow::new(CAPACITY);
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            let window = &window;
+            let _ = s.spawn(move || {
+                for id in 0..IDS {
+                    let _ = window.insert(id * THREADS + t);
+                }
+            });
+        }
+    });
+    assert_eq!(window.len(), CAPACITY);
+
+    // Fresh ids push out all of the old ones.
+    let fresh = IDS * THREADS..IDS * THREADS + CAPACITY;
+    for id in fresh.clone() {
+        assert!(window.insert(id));
+    }
+    assert_eq!(window.len(), CAPACITY);
+    assert!(fresh.clone().all(|id| window.contains(&id)));
+    assert!((0..IDS * THREADS).all(|id| !window.contains(&id)));
+}
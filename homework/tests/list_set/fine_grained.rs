@@ -1,14 +1,20 @@
 use rand::prelude::*;
-use std::collections::HashSet;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashSet};
 use std::iter::zip;
+use std::ops::Bound::{Excluded, Included};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::atomic::{
-    AtomicBool,
-    Ordering::{Acquire, Release},
+    AtomicBool, AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release},
 };
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 use cs431_homework::test::adt::set;
-use cs431_homework::{ConcurrentSet, FineGrainedListSet};
+use cs431_homework::{ConcurrentSet, FineGrainedListSet, PoisonPolicy, TryInsertError};
 
 #[test]
 fn smoke() {
@@ -43,6 +49,21 @@ fn log_concurrent() {
     set::log_concurrent::<_, FineGrainedListSet<u8>>(THREADS, STEPS);
 }
 
+#[test]
+fn hotspot_concurrent() {
+    const THREADS: usize = 16;
+    const STEPS: usize = 4096 * 4;
+    let set = FineGrainedListSet::new();
+    for i in (0..100).step_by(10) {
+        assert!(set.insert(i));
+    }
+    set::hotspot_workload(&set, &[41, 42, 43, 50], THREADS, STEPS);
+    // The cold keys around the hot spot are intact.
+    for i in (0..100).step_by(10).filter(|i| *i != 50) {
+        assert!(set.contains(&i));
+    }
+}
+
 /// Check the consistency of iterator while other operations are running concurrently.
 #[test]
 fn iter_consistent() {
@@ -86,3 +107,1164 @@ fn iter_consistent() {
         });
     });
 }
+
+#[test]
+fn remove_range() {
+    let set = FineGrainedListSet::new();
+    for i in 0..30 {
+        assert!(set.insert(i));
+    }
+    assert_eq!(set.remove_range(10..20), 10);
+    assert!(set.iter().copied().eq((0..10).chain(20..30)));
+
+    // Removing from the head.
+    assert_eq!(set.remove_range(..=4), 5);
+    assert!(set.iter().copied().eq((5..10).chain(20..30)));
+
+    // Removing a contiguous run across the gap.
+    assert_eq!(set.remove_range(8..22), 4);
+    assert!(set.iter().copied().eq((5..8).chain(22..30)));
+
+    // Nothing to remove.
+    assert_eq!(set.remove_range(10..20), 0);
+    assert_eq!(set.remove_range(..), 11);
+    assert_eq!(set.iter().next(), None);
+}
+
+#[test]
+fn range_len() {
+    let set = FineGrainedListSet::new();
+    for i in 0..100 {
+        assert!(set.insert(i));
+    }
+    assert_eq!(set.range_len(20..40), 20);
+    assert_eq!(set.range_len(20..=40), 21);
+    assert_eq!(set.range_len((Excluded(20), Included(40))), 20);
+    assert_eq!(set.range_len(..10), 10);
+    assert_eq!(set.range_len(90..), 10);
+    assert_eq!(set.range_len(..), 100);
+    assert_eq!(set.range_len(100..200), 0);
+    assert_eq!(set.range_len((Included(40), Excluded(20))), 0);
+}
+
+#[test]
+fn fold_sum() {
+    let set = FineGrainedListSet::new();
+    for i in 0..100 {
+        assert!(set.insert(i));
+    }
+    assert_eq!(set.fold(0, |acc, v| acc + v), (0..100).sum());
+}
+
+#[test]
+fn fold_max() {
+    let set = FineGrainedListSet::new();
+    assert_eq!(
+        set.fold(None, |acc: Option<i32>, &v| acc.max(Some(v))),
+        None
+    );
+    for i in [42, 7, 99, 13, 0] {
+        assert!(set.insert(i));
+    }
+    assert_eq!(set.fold(None, |acc, &v| acc.max(Some(v))), Some(99));
+}
+
+/// A key whose comparison panics if either side is negative.
+#[derive(Debug, PartialEq, Eq)]
+struct PanickyKey(i32);
+
+impl PartialOrd for PanickyKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PanickyKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        assert!(self.0 >= 0 && other.0 >= 0, "negative key");
+        self.0.cmp(&other.0)
+    }
+}
+
+#[test]
+fn poison_recover() {
+    let set = FineGrainedListSet::with_poison_policy(PoisonPolicy::Recover);
+    assert!(set.insert(PanickyKey(1)));
+    assert!(set.insert(PanickyKey(3)));
+    assert!(catch_unwind(AssertUnwindSafe(|| set.insert(PanickyKey(-1)))).is_err());
+
+    assert!(set.contains(&PanickyKey(1)));
+    assert!(set.insert(PanickyKey(2)));
+    assert!(set.remove(&PanickyKey(3)));
+    assert!(set.iter().map(|k| k.0).eq([1, 2]));
+}
+
+#[test]
+#[should_panic]
+fn poison_propagate() {
+    let set = FineGrainedListSet::new();
+    assert!(set.insert(PanickyKey(1)));
+    assert!(catch_unwind(AssertUnwindSafe(|| set.insert(PanickyKey(-1)))).is_err());
+    let _ = set.contains(&PanickyKey(1));
+}
+
+/// An element ordered only by `key`.
+#[derive(Debug, Clone)]
+struct Versioned {
+    key: i32,
+    version: u32,
+}
+
+impl PartialEq for Versioned {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Versioned {}
+
+impl PartialOrd for Versioned {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Versioned {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+#[test]
+fn for_each_mut() {
+    let mut set = FineGrainedListSet::new();
+    for key in 0..10 {
+        assert!(set.insert(Versioned { key, version: 0 }));
+    }
+    set.for_each_mut(|v| v.version += 1);
+    set.for_each_mut(|v| v.version += v.key as u32);
+    for (v, key) in zip(set.iter(), 0..10) {
+        assert_eq!(v.key, key);
+        assert_eq!(v.version, 1 + key as u32);
+    }
+}
+
+#[test]
+fn len() {
+    let set = FineGrainedListSet::new();
+    assert!(set.is_empty());
+    for i in 0..10 {
+        assert!(set.insert(i));
+    }
+    assert!(!set.insert(3));
+    assert_eq!(set.len(), 10);
+    assert!(set.remove(&3));
+    assert!(!set.remove(&3));
+    assert_eq!(set.len(), 9);
+    assert_eq!(set.remove_range(..5), 4);
+    assert_eq!(set.len(), 5);
+}
+
+#[test]
+fn combined_options() {
+    let set = FineGrainedListSet::new()
+        .with_cmp(|a: &i32, b: &i32| b.cmp(a))
+        .with_capacity(3)
+        .with_node_cache(2);
+    for i in 0..4 {
+        let _ = set.insert(i);
+    }
+    assert!(set.iter().copied().eq([2, 1, 0]));
+    assert!(set.remove(&1));
+    assert!(set.insert(5));
+    assert!(set.iter().copied().eq([5, 2, 0]));
+}
+
+#[test]
+#[should_panic]
+fn with_cmp_non_empty() {
+    let set = FineGrainedListSet::new();
+    assert!(set.insert(1));
+    let _ = set.with_cmp(|a: &i32, b: &i32| b.cmp(a));
+}
+
+#[test]
+fn with_capacity() {
+    let set = FineGrainedListSet::new().with_capacity(3);
+    assert!(set.insert(1));
+    assert!(set.insert(2));
+    assert!(set.insert(3));
+    assert_eq!(set.len(), 3);
+
+    // Further inserts are rejected.
+    assert!(!set.insert(4));
+    assert_eq!(set.try_insert(0), Err(TryInsertError::Full));
+    assert_eq!(set.try_insert(2), Err(TryInsertError::Present));
+    assert!(!set.contains(&4));
+    assert_eq!(set.len(), 3);
+
+    // A removal frees space.
+    assert!(set.remove(&1));
+    assert_eq!(set.try_insert(4), Ok(()));
+    assert!(!set.insert(5));
+    assert!(set.iter().copied().eq([2, 3, 4]));
+}
+
+#[test]
+fn with_capacity_concurrent() {
+    const THREADS: usize = 8;
+    const CAPACITY: usize = 16;
+
+    let set = FineGrainedListSet::new().with_capacity(CAPACITY);
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            let set = &set;
+            let _ = s.spawn(move || {
+                for i in 0..CAPACITY {
+                    let _ = set.insert(t * CAPACITY + i);
+                }
+            });
+        }
+    });
+    assert_eq!(set.len(), CAPACITY);
+    assert_eq!(set.iter().count(), CAPACITY);
+}
+
+#[test]
+fn with_cmp_descending() {
+    let set = FineGrainedListSet::new().with_cmp(|a: &i32, b: &i32| b.cmp(a));
+    for i in [3, 1, 4, 5, 9, 2, 6] {
+        assert!(set.insert(i));
+    }
+    assert!(!set.insert(4));
+    assert!(set.iter().copied().eq([9, 6, 5, 4, 3, 2, 1]));
+
+    assert!(set.contains(&5));
+    assert!(!set.contains(&7));
+    assert!(set.remove(&5));
+    assert!(!set.remove(&5));
+
+    // Ranges follow the custom order as well.
+    assert_eq!(set.remove_range((Included(6), Included(3))), 3);
+    assert!(set.iter().copied().eq([9, 2, 1]));
+}
+
+#[test]
+fn retain_reporting() {
+    let set = FineGrainedListSet::new();
+    for i in 0..20 {
+        assert!(set.insert(i));
+    }
+    assert_eq!(set.retain_reporting(|x| x % 3 == 0), 13);
+    assert_eq!(set.len(), 7);
+    assert_eq!(
+        set.iter().copied().collect::<Vec<_>>(),
+        [0, 3, 6, 9, 12, 15, 18]
+    );
+    assert_eq!(set.retain_reporting(|_| true), 0);
+    set.retain(|&x| x > 10);
+    assert_eq!(set.iter().copied().collect::<Vec<_>>(), [12, 15, 18]);
+    assert_eq!(set.len(), 3);
+}
+
+#[test]
+fn rank_select() {
+    let set = FineGrainedListSet::new();
+    for i in (0..50).rev() {
+        assert!(set.insert(i));
+    }
+    assert_eq!(set.select(10), Some(10));
+    assert_eq!(set.rank(&25), 25);
+    assert_eq!(set.select(49), Some(49));
+    assert_eq!(set.select(50), None);
+    assert_eq!(set.rank(&100), 50);
+
+    assert!(set.remove(&3));
+    assert_eq!(set.select(10), Some(11));
+    assert_eq!(set.rank(&25), 24);
+    // The rank of an absent key counts the elements before its position.
+    assert_eq!(set.rank(&3), 3);
+}
+
+#[test]
+fn verify() {
+    // 0: natural order, 1: reversed, 2: by tens.
+    static MODE: AtomicUsize = AtomicUsize::new(0);
+    let set = FineGrainedListSet::new().with_cmp(|a: &i32, b: &i32| match MODE.load(Relaxed) {
+        0 => a.cmp(b),
+        1 => b.cmp(a),
+        _ => (a / 10).cmp(&(b / 10)),
+    });
+    for i in [5, 10, 11, 30] {
+        assert!(set.insert(i));
+    }
+    assert_eq!(set.verify(), Ok(()));
+
+    // The list no longer matches the comparator.
+    MODE.store(1, Relaxed);
+    assert_eq!(
+        set.verify(),
+        Err("out of order at position 1: 10 follows 5".to_owned())
+    );
+    MODE.store(2, Relaxed);
+    assert_eq!(
+        set.verify(),
+        Err("duplicate at position 2: 11 is equal to 10".to_owned())
+    );
+    MODE.store(0, Relaxed);
+    assert_eq!(set.verify(), Ok(()));
+}
+
+#[test]
+fn batch_contains() {
+    let set = FineGrainedListSet::new();
+    for i in (0..20).step_by(2) {
+        assert!(set.insert(i));
+    }
+    assert_eq!(
+        set.batch_contains(&[7, 4, 30, 4, 0, -1, 18, 7]),
+        [false, true, false, true, true, false, true, false]
+    );
+    assert!(set.batch_contains(&[]).is_empty());
+}
+
+#[test]
+fn drain_filter() {
+    let set = FineGrainedListSet::new();
+    for i in 0..20 {
+        assert!(set.insert(i));
+    }
+    assert_eq!(
+        set.drain_filter(|x| x % 2 == 0),
+        (0..20).step_by(2).collect::<Vec<_>>()
+    );
+    assert!(set.iter().copied().eq((1..20).step_by(2)));
+    assert_eq!(set.len(), 10);
+    assert!(set.drain_filter(|x| x % 2 == 0).is_empty());
+}
+
+#[test]
+fn iter_copied_does_not_block_writers() {
+    let set = FineGrainedListSet::new();
+    for i in 0..100u64 {
+        assert!(set.insert(2 * i));
+    }
+
+    let mut iter = set.iter_copied();
+    assert_eq!(iter.next(), Some(0));
+    assert_eq!(iter.next(), Some(2));
+
+    // A writer behind the iterator completes while the iteration is suspended.
+    thread::scope(|s| {
+        let _ = s.spawn(|| {
+            assert!(set.insert(1));
+            assert!(set.remove(&0));
+        });
+    });
+
+    // A writer ahead of the iterator completes once the iteration passes its position.
+    let rest = thread::scope(|s| {
+        let _ = s.spawn(|| assert!(set.insert(1001)));
+        iter.collect::<Vec<_>>()
+    });
+    assert_eq!(rest.first(), Some(&4));
+    assert!(rest.len() == 98 || rest.len() == 99);
+    assert!(rest.windows(2).all(|w| w[0] < w[1]));
+
+    // The lock of the last pointer is released at the end of the iteration.
+    let mut iter = set.iter_copied();
+    assert_eq!(iter.by_ref().last(), Some(1001));
+    assert_eq!(iter.next(), None);
+    assert!(set.insert(1003));
+}
+
+#[test]
+fn contains_remove_reinsert() {
+    const STEPS: usize = 10_000;
+
+    let set = FineGrainedListSet::new();
+    assert!(set.insert(4));
+    assert!(set.insert(6));
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        let _ = s.spawn(|| {
+            for _ in 0..STEPS {
+                let _ = set.insert(5);
+                let _ = set.remove(&5);
+            }
+            done.store(true, Release);
+        });
+        let _ = s.spawn(|| {
+            while !done.load(Acquire) {
+                let _ = set.contains(&5);
+                assert!(set.contains(&4));
+                assert!(set.contains(&6));
+            }
+        });
+    });
+    assert!(!set.contains(&5));
+    assert_eq!(set.len(), 2);
+}
+
+#[test]
+fn get_returns_stored() {
+    let set = FineGrainedListSet::new();
+    for key in 0..10 {
+        assert!(set.insert(Versioned { key, version: 1 }));
+    }
+    assert!(!set.insert(Versioned { key: 3, version: 2 }));
+    let stored = set.get(&Versioned { key: 3, version: 0 }).unwrap();
+    assert_eq!((stored.key, stored.version), (3, 1));
+    assert!(set
+        .get(&Versioned {
+            key: 10,
+            version: 1
+        })
+        .is_none());
+}
+
+#[test]
+fn get_or_insert() {
+    let set = FineGrainedListSet::new();
+    let inserted = set.get_or_insert(Versioned { key: 1, version: 1 });
+    assert_eq!(inserted.version, 1);
+    let existing = set.get_or_insert(Versioned { key: 1, version: 2 });
+    assert_eq!(existing.version, 1);
+    assert_eq!(set.len(), 1);
+
+    // Concurrent callers agree on a single winner.
+    const THREADS: usize = 8;
+    let versions = thread::scope(|s| {
+        let handles = (0..THREADS)
+            .map(|version| {
+                let set = &set;
+                s.spawn(move || {
+                    set.get_or_insert(Versioned {
+                        key: 2,
+                        version: version as u32,
+                    })
+                    .version
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    assert!(versions.iter().all(|&v| v == versions[0]));
+    assert_eq!(
+        set.get(&Versioned { key: 2, version: 0 }).unwrap().version,
+        versions[0]
+    );
+}
+
+#[test]
+fn take_concurrent_insert() {
+    let set = FineGrainedListSet::new();
+    for i in 0..100 {
+        assert!(set.insert(i));
+    }
+    let taken = thread::scope(|s| {
+        let _ = s.spawn(|| {
+            for i in 1000..1100 {
+                assert!(set.insert(i));
+            }
+        });
+        set.take()
+    });
+    assert!(taken.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(taken[..100], (0..100).collect::<Vec<_>>());
+    let mut all = taken.clone();
+    all.extend(set.iter().copied());
+    all.sort_unstable();
+    assert_eq!(all, (0..100).chain(1000..1100).collect::<Vec<_>>());
+    assert_eq!(set.len() + taken.len(), 200);
+
+    assert_eq!(set.take().len(), 200 - taken.len());
+    assert!(set.is_empty());
+    assert!(set.insert(5));
+    assert_eq!(set.iter().copied().collect::<Vec<_>>(), [5]);
+}
+
+#[test]
+fn insert_ranked() {
+    let set = FineGrainedListSet::new();
+    for i in [10, 20, 30] {
+        assert!(set.insert(i));
+    }
+    assert_eq!(set.insert_ranked(5), Ok(0));
+    assert_eq!(set.insert_ranked(25), Ok(3));
+    assert_eq!(set.insert_ranked(40), Ok(5));
+    assert_eq!(set.insert_ranked(20), Err(2));
+    assert_eq!(set.insert_ranked(5), Err(0));
+    assert_eq!(set.len(), 6);
+    assert_eq!(
+        set.iter().copied().collect::<Vec<_>>(),
+        [5, 10, 20, 25, 30, 40]
+    );
+
+    let full = FineGrainedListSet::new().with_capacity(1);
+    assert_eq!(full.insert_ranked(2), Ok(0));
+    assert_eq!(full.insert_ranked(3), Err(1));
+}
+
+/// An element that logs its key when dropped.
+#[derive(Debug)]
+struct DropLogged(i32, Arc<Mutex<Vec<i32>>>);
+
+impl PartialEq for DropLogged {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for DropLogged {}
+
+impl PartialOrd for DropLogged {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DropLogged {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl Drop for DropLogged {
+    fn drop(&mut self) {
+        self.1.lock().unwrap().push(self.0);
+    }
+}
+
+#[test]
+fn drop_in_sorted_order() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let set = FineGrainedListSet::new();
+    for key in [5, 1, 9, 3, 7] {
+        assert!(set.insert(DropLogged(key, log.clone())));
+    }
+    drop(set);
+    assert_eq!(*log.lock().unwrap(), [1, 3, 5, 7, 9]);
+}
+
+#[test]
+fn contains_any() {
+    let set = FineGrainedListSet::new();
+    for i in 0..1000 {
+        assert!(set.insert(2 * i));
+    }
+    assert!(!set.contains_any(&[]));
+    assert!(!set.contains_any(&[5001, 1, 3, 2001, -1]));
+    // The only match is the largest candidate, near the end of the list.
+    assert!(set.contains_any(&[1998, 7, 1001, 3, -5]));
+    assert!(set.contains_any(&[0]));
+
+    let descending = FineGrainedListSet::new().with_cmp(|a: &i32, b: &i32| b.cmp(a));
+    for i in 0..10 {
+        assert!(descending.insert(i));
+    }
+    assert!(descending.contains_any(&[20, 11, 0]));
+    assert!(!descending.contains_any(&[20, 11, -1]));
+}
+
+#[test]
+fn pop_first_last() {
+    let set = FineGrainedListSet::new();
+    assert_eq!(set.pop_first(), None);
+    assert_eq!(set.pop_last(), None);
+    for i in [3, 1, 4, 5, 9, 2, 6] {
+        let _ = set.insert(i);
+    }
+    assert_eq!(set.pop_first(), Some(1));
+    assert_eq!(set.pop_last(), Some(9));
+    assert_eq!(set.pop_last(), Some(6));
+    assert_eq!(set.pop_first(), Some(2));
+    assert_eq!(set.len(), 3);
+    assert_eq!(set.iter().copied().collect::<Vec<_>>(), [3, 4, 5]);
+}
+
+#[test]
+fn pop_first_last_concurrent() {
+    const COUNT: usize = 2000;
+
+    let set = FineGrainedListSet::new();
+    for i in 0..COUNT {
+        assert!(set.insert(i));
+    }
+    let popped = thread::scope(|s| {
+        let handles = (0..4)
+            .map(|t| {
+                let set = &set;
+                s.spawn(move || {
+                    let mut popped = Vec::new();
+                    loop {
+                        let value = if t % 2 == 0 {
+                            set.pop_first()
+                        } else {
+                            set.pop_last()
+                        };
+                        match value {
+                            Some(value) => popped.push(value),
+                            None => break popped,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(popped.len(), COUNT);
+    assert_eq!(popped.iter().collect::<HashSet<_>>().len(), COUNT);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn auto_traits() {
+    fn assert_send<S: Send>() {}
+    fn assert_sync<S: Sync>() {}
+
+    assert_send::<FineGrainedListSet<i32>>();
+    assert_sync::<FineGrainedListSet<i32>>();
+    assert_send::<FineGrainedListSet<String>>();
+    assert_sync::<FineGrainedListSet<Arc<Mutex<i32>>>>();
+    // `Cell` is `Send` but not `Sync`, so the set can be moved but not shared.
+    assert_send::<FineGrainedListSet<std::cell::Cell<i32>>>();
+}
+
+#[test]
+fn iter_from() {
+    let set = FineGrainedListSet::new();
+    for i in (0..100).step_by(2) {
+        assert!(set.insert(i));
+    }
+    assert!(set.iter_from(&40).eq((40..100).step_by(2)));
+    assert!(set.iter_from(&41).eq((42..100).step_by(2)));
+    assert!(set.iter_from(&-1).eq((0..100).step_by(2)));
+    assert_eq!(set.iter_from(&99).next(), None);
+}
+
+#[test]
+fn borrowed_lookup() {
+    let set = FineGrainedListSet::new();
+    for word in ["apple", "banana", "cherry"] {
+        assert!(set.insert(word.to_string()));
+    }
+    assert!(set.contains_q("banana"));
+    assert!(!set.contains_q("durian"));
+    assert!(set.remove_q("banana"));
+    assert!(!set.remove_q("banana"));
+    assert!(!set.contains_q("banana"));
+    assert!(set.iter().map(String::as_str).eq(["apple", "cherry"]));
+}
+
+/// Allocator that counts the allocations of each thread, so that concurrently running tests do not
+/// disturb each other's counts.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static DEALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = DEALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+/// Returns the number of allocations of `f` on the current thread.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+/// Returns the number of deallocations of `f` on the current thread.
+fn deallocations(f: impl FnOnce()) -> usize {
+    let before = DEALLOCATIONS.with(Cell::get);
+    f();
+    DEALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn node_cache_clear_refill() {
+    const ELEMENTS: usize = 32;
+    const CYCLES: usize = 10;
+
+    fn clear_refill(set: &FineGrainedListSet<usize>) -> usize {
+        allocations(|| {
+            for _ in 0..CYCLES {
+                for i in 0..ELEMENTS {
+                    assert!(set.insert(i));
+                }
+                set.clear();
+                assert!(set.is_empty());
+            }
+        })
+    }
+
+    let uncached = FineGrainedListSet::new();
+    assert_eq!(clear_refill(&uncached), ELEMENTS * CYCLES);
+
+    // All nodes are cached, so only the first fill allocates.
+    let cached = FineGrainedListSet::new().with_node_cache(ELEMENTS);
+    assert_eq!(clear_refill(&cached), ELEMENTS);
+
+    // The cache is bounded, and `remove` feeds it too.
+    let small = FineGrainedListSet::new().with_node_cache(4);
+    for i in 0..ELEMENTS {
+        assert!(small.insert(i));
+    }
+    for i in 0..ELEMENTS {
+        assert!(small.remove(&i));
+    }
+    assert_eq!(
+        allocations(|| {
+            for i in 0..ELEMENTS {
+                assert!(small.insert(i));
+            }
+        }),
+        ELEMENTS - 4
+    );
+}
+
+#[test]
+fn clone_into() {
+    const ELEMENTS: usize = 100;
+
+    let src = FineGrainedListSet::new();
+    for i in 0..ELEMENTS {
+        assert!(src.insert(Arc::new(i)));
+    }
+    let mut dst = FineGrainedListSet::new().with_node_cache(ELEMENTS);
+    let old = (0..ELEMENTS)
+        .map(|i| Arc::new(i + 1000))
+        .collect::<Vec<_>>();
+    for value in &old {
+        assert!(dst.insert(value.clone()));
+    }
+
+    // Only the buffer of the clones is allocated, as the nodes of `dst` are reused.
+    assert_eq!(allocations(|| src.clone_into(&mut dst)), 1);
+    assert_eq!(dst.len(), ELEMENTS);
+    assert!(dst.iter().eq(src.iter()));
+    assert!(old.iter().all(|value| Arc::strong_count(value) == 1));
+    assert!(src.iter().all(|value| Arc::strong_count(value) == 2));
+
+    drop(dst);
+    assert!(src.iter().all(|value| Arc::strong_count(value) == 1));
+}
+
+#[test]
+fn node_cache_shrink_to_fit() {
+    const ELEMENTS: usize = 1000;
+
+    let set = FineGrainedListSet::new().with_node_cache(ELEMENTS);
+    for i in 0..ELEMENTS {
+        assert!(set.insert(i));
+    }
+    // The nodes go to the cache.
+    assert_eq!(deallocations(|| set.clear()), 0);
+    assert_eq!(deallocations(|| set.shrink_to_fit()), ELEMENTS);
+    assert_eq!(deallocations(|| set.shrink_to_fit()), 0);
+
+    // Refilling allocates again.
+    assert_eq!(
+        allocations(|| {
+            for i in 0..ELEMENTS {
+                assert!(set.insert(i));
+            }
+        }),
+        ELEMENTS
+    );
+}
+
+#[test]
+fn entry_guard() {
+    let set = FineGrainedListSet::new();
+    let mut entry = set.entry(&3);
+    assert!(!entry.exists());
+    assert_eq!(entry.remove_here(), None);
+    assert_eq!(entry.insert_here(3), Ok(()));
+    assert_eq!(entry.insert_here(3), Err(TryInsertError::Present));
+    assert_eq!(entry.get(), Some(&3));
+    drop(entry);
+    assert!(set.contains(&3));
+
+    let mut entry = set.entry(&3);
+    assert_eq!(entry.remove_here(), Some(3));
+    assert!(!entry.exists());
+    drop(entry);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn entry_guard_sibling_absent() {
+    const PAIRS: usize = 100;
+
+    // `2 * p` and `2 * p + 1` are siblings: they are ordered as the same element, so the guard of
+    // one also finds the other.
+    let set = FineGrainedListSet::new().with_cmp(|a: &usize, b: &usize| (a / 2).cmp(&(b / 2)));
+    let inserted = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for offset in 0..2 {
+            let set = &set;
+            let inserted = &inserted;
+            let _ = s.spawn(move || {
+                for pair in 0..PAIRS {
+                    let key = 2 * pair + offset;
+                    let mut entry = set.entry(&key);
+                    if !entry.exists() {
+                        entry.insert_here(key).unwrap();
+                        let _ = inserted.fetch_add(1, Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(inserted.load(Relaxed), PAIRS);
+    let mut pairs = set.iter().map(|key| key / 2).collect::<Vec<_>>();
+    pairs.dedup();
+    assert_eq!(pairs, (0..PAIRS).collect::<Vec<_>>());
+}
+
+#[test]
+fn symmetric_difference() {
+    let a = FineGrainedListSet::new();
+    let b = FineGrainedListSet::new();
+    for i in 0..10 {
+        assert!(a.insert(i));
+        assert!(b.insert(i + 5));
+    }
+    let c = a.symmetric_difference(&b);
+    assert!(c.iter().copied().eq((0..5).chain(10..15)));
+    assert_eq!(c.len(), 10);
+    assert!(b.symmetric_difference(&a).iter().eq(c.iter()));
+
+    // Disjoint sets.
+    let d = FineGrainedListSet::new();
+    for i in 20..25 {
+        assert!(d.insert(i));
+    }
+    assert!(a
+        .symmetric_difference(&d)
+        .iter()
+        .copied()
+        .eq((0..10).chain(20..25)));
+    assert!(a.symmetric_difference(&a).is_empty());
+
+    // The ordering of `self` is kept.
+    let desc = FineGrainedListSet::new().with_cmp(|a: &i32, b: &i32| b.cmp(a));
+    let desc_other = FineGrainedListSet::new().with_cmp(|a: &i32, b: &i32| b.cmp(a));
+    for i in 0..4 {
+        assert!(desc.insert(i));
+        assert!(desc_other.insert(i + 2));
+    }
+    let result = desc.symmetric_difference(&desc_other);
+    assert!(result.iter().copied().eq([5, 4, 1, 0]));
+    assert!(result.insert(3));
+    assert!(result.iter().copied().eq([5, 4, 3, 1, 0]));
+}
+
+#[test]
+fn intersection_count() {
+    let evens = FineGrainedListSet::new();
+    let odds = FineGrainedListSet::new();
+    let threes = FineGrainedListSet::new();
+    for i in 0..30 {
+        let set = if i % 2 == 0 { &evens } else { &odds };
+        assert!(set.insert(i));
+        if i % 3 == 0 {
+            assert!(threes.insert(i));
+        }
+    }
+    assert_eq!(evens.intersection_count(&odds), 0);
+    assert_eq!(evens.intersection_count(&threes), 5);
+    assert_eq!(threes.intersection_count(&odds), 5);
+    assert_eq!(evens.intersection_count(&evens), 15);
+    let copy = FineGrainedListSet::new();
+    for i in (0..30).step_by(2) {
+        assert!(copy.insert(i));
+    }
+    assert_eq!(copy.intersection_count(&evens), 15);
+    assert_eq!(FineGrainedListSet::new().intersection_count(&evens), 0);
+}
+
+#[test]
+fn retain_difference() {
+    let a = FineGrainedListSet::new();
+    let b = FineGrainedListSet::new();
+    for i in 0..20 {
+        assert!(a.insert(i));
+    }
+    for i in (0..30).step_by(3) {
+        assert!(b.insert(i));
+    }
+    a.retain_difference(&b);
+    assert!(a.iter().copied().eq((0..20).filter(|i| i % 3 != 0)));
+    assert_eq!(a.len(), 13);
+    assert_eq!(b.len(), 10);
+
+    // Disjoint sets.
+    let d = FineGrainedListSet::new();
+    for i in 100..110 {
+        assert!(d.insert(i));
+    }
+    a.retain_difference(&d);
+    assert_eq!(a.len(), 13);
+
+    a.retain_difference(&a);
+    assert!(a.is_empty());
+}
+
+#[test]
+fn to_sorted_vec() {
+    let mut rng = thread_rng();
+    let set = FineGrainedListSet::new();
+    let mut expected = BTreeSet::new();
+    for _ in 0..200 {
+        let value = rng.gen_range(0..1000);
+        assert_eq!(set.insert(value), expected.insert(value));
+    }
+
+    let mut values = Vec::new();
+    assert_eq!(allocations(|| values = set.to_sorted_vec()), 1);
+    assert_eq!(values, expected.into_iter().collect::<Vec<_>>());
+    assert_eq!(values.capacity(), set.len());
+    assert!(FineGrainedListSet::<usize>::new()
+        .to_sorted_vec()
+        .is_empty());
+}
+
+#[test]
+fn extend_reporting() {
+    let set = FineGrainedListSet::new();
+    assert_eq!(set.extend_reporting([3, 1, 4]), (3, 0));
+    assert_eq!(set.extend_reporting([1, 5, 9, 2, 6, 5, 3, 5]), (4, 4));
+    assert!(set.iter().copied().eq([1, 2, 3, 4, 5, 6, 9]));
+    assert_eq!(set.len(), 7);
+    assert_eq!(set.extend_reporting([]), (0, 0));
+
+    let set = FineGrainedListSet::new().with_capacity(2);
+    assert_eq!(set.extend_reporting([1, 1, 2, 3, 2]), (2, 2));
+    assert_eq!(set.len(), 2);
+}
+
+#[test]
+fn find_map() {
+    let set = FineGrainedListSet::new();
+    for i in 0..100 {
+        assert!(set.insert(i));
+    }
+    let mut visited = 0;
+    let found = set.find_map(|&x| {
+        visited += 1;
+        (x > 41).then_some(x * 2)
+    });
+    assert_eq!(found, Some(84));
+    assert_eq!(visited, 43);
+    assert_eq!(set.find_map(|&x| (x > 100).then_some(x)), None);
+    assert_eq!(
+        FineGrainedListSet::<usize>::new().find_map(|_| Some(())),
+        None
+    );
+}
+
+#[test]
+fn partition() {
+    let set = FineGrainedListSet::new();
+    for i in 0..20 {
+        assert!(set.insert(i));
+    }
+    let (evens, odds) = set.partition(|x| x % 2 == 0);
+    assert!(evens.iter().copied().eq((0..20).step_by(2)));
+    assert!(odds.iter().copied().eq((1..20).step_by(2)));
+    assert_eq!((evens.len(), odds.len()), (10, 10));
+    assert_eq!(set.len(), 20);
+
+    // The new sets keep the ordering, so they can be searched and modified.
+    let descending = FineGrainedListSet::new().with_cmp(|a: &i32, b: &i32| b.cmp(a));
+    for i in 0..10 {
+        assert!(descending.insert(i));
+    }
+    let (small, large) = descending.partition(|&x| x < 5);
+    assert!(small.iter().copied().eq((0..5).rev()));
+    assert!(large.contains(&7));
+    assert!(large.insert(10));
+    assert!(large.iter().copied().eq((5..11).rev()));
+}
+
+#[test]
+fn retain_difference_frees_removed() {
+    let values = (0..20).map(Arc::new).collect::<Vec<_>>();
+    let a = FineGrainedListSet::new();
+    for value in &values {
+        assert!(a.insert(value.clone()));
+    }
+    let subset = FineGrainedListSet::new();
+    for value in values.iter().step_by(4) {
+        assert!(subset.insert(Arc::new(**value)));
+    }
+    let disjoint = FineGrainedListSet::new();
+    for i in 100..110 {
+        assert!(disjoint.insert(Arc::new(i)));
+    }
+
+    a.retain_difference(&subset);
+    a.retain_difference(&disjoint);
+    assert!(a.iter().map(|v| **v).eq((0..20).filter(|i| i % 4 != 0)));
+    assert_eq!(a.len(), 15);
+    for (i, value) in values.iter().enumerate() {
+        let expected = if i % 4 == 0 { 1 } else { 2 };
+        assert_eq!(Arc::strong_count(value), expected);
+    }
+}
+
+#[test]
+fn with_contains() {
+    const ROUNDS: usize = 200;
+
+    let set = FineGrainedListSet::new();
+    assert!(set.insert(1));
+    assert!(set.with_contains(&1, |found| found));
+    assert!(!set.with_contains(&2, |found| found));
+
+    // While `f` runs for a present key, the key cannot be removed.
+    let inside = AtomicBool::new(false);
+    let done = AtomicBool::new(false);
+    let hits = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let _ = s.spawn(|| {
+                while !done.load(Acquire) {
+                    set.with_contains(&0, |found| {
+                        if found {
+                            inside.store(true, Release);
+                            let _ = hits.fetch_add(1, Relaxed);
+                            thread::yield_now();
+                            inside.store(false, Release);
+                        }
+                    });
+                }
+            });
+        }
+        for _ in 0..ROUNDS {
+            let before = hits.load(Relaxed);
+            assert!(set.insert(0));
+            while hits.load(Relaxed) == before {
+                thread::yield_now();
+            }
+            assert!(set.remove(&0));
+            assert!(!inside.load(Acquire));
+        }
+        done.store(true, Release);
+    });
+}
+
+#[test]
+fn remove_value() {
+    let set = FineGrainedListSet::new();
+    for key in 0..10 {
+        assert!(set.insert(Versioned { key, version: 7 }));
+    }
+    let removed = set.remove_value(&Versioned { key: 3, version: 0 }).unwrap();
+    assert_eq!((removed.key, removed.version), (3, 7));
+    assert_eq!(set.len(), 9);
+    assert!(!set.contains(&Versioned { key: 3, version: 0 }));
+    assert!(set
+        .remove_value(&Versioned { key: 3, version: 0 })
+        .is_none());
+    assert!(set
+        .remove_value(&Versioned {
+            key: 42,
+            version: 0
+        })
+        .is_none());
+}
+
+#[test]
+fn merge_sorted() {
+    const N: usize = 1000;
+
+    let comparisons = Arc::new(AtomicUsize::new(0));
+    let set = FineGrainedListSet::new().with_cmp({
+        let comparisons = comparisons.clone();
+        move |a: &usize, b: &usize| {
+            let _ = comparisons.fetch_add(1, Relaxed);
+            a.cmp(b)
+        }
+    });
+    for i in (0..N).step_by(2) {
+        assert!(set.insert(i));
+    }
+    comparisons.store(0, Relaxed);
+    assert_eq!(set.merge_sorted(N / 2..N + N / 2), N - N / 4);
+    assert!(set
+        .iter()
+        .copied()
+        .eq((0..N / 2).step_by(2).chain(N / 2..N + N / 2)));
+    assert_eq!(set.len(), N / 4 + N);
+    // A single pass over both, instead of a traversal per value.
+    assert!(comparisons.load(Relaxed) <= 3 * (N / 2 + N));
+
+    // An unsorted stream is merged correctly too.
+    let set = FineGrainedListSet::new();
+    assert_eq!(set.merge_sorted([5, 3, 9, 3, 1, 7]), 5);
+    assert!(set.iter().copied().eq([1, 3, 5, 7, 9]));
+
+    let set = FineGrainedListSet::new().with_capacity(3);
+    assert_eq!(set.merge_sorted([1, 2, 2, 3, 4, 5]), 3);
+    assert!(set.iter().copied().eq([1, 2, 3]));
+}
+
+#[test]
+fn move_range_to() {
+    let values = (0..100).map(Arc::new).collect::<Vec<_>>();
+    let src = FineGrainedListSet::new();
+    for value in &values {
+        assert!(src.insert(value.clone()));
+    }
+    let dst = FineGrainedListSet::new();
+    for i in (50..55).chain([200]) {
+        assert!(dst.insert(Arc::new(i)));
+    }
+
+    // The nodes are relinked, not reallocated.
+    let range = Arc::new(30)..Arc::new(60);
+    assert_eq!(
+        allocations(|| assert_eq!(src.move_range_to(range, &dst), 25)),
+        0
+    );
+    // The values already in `dst` stay in `src`.
+    assert!(src
+        .iter()
+        .map(|v| **v)
+        .eq((0..30).chain(50..55).chain(60..100)));
+    assert_eq!(src.len(), 75);
+    assert!(dst.iter().map(|v| **v).eq((30..60).chain([200])));
+    assert_eq!(dst.len(), 31);
+    assert!(values.iter().all(|value| Arc::strong_count(value) == 2));
+    for value in &values[50..55] {
+        assert!(src.iter().any(|v| Arc::ptr_eq(v, value)));
+    }
+
+    assert_eq!(src.move_range_to(Arc::new(30)..Arc::new(60), &dst), 0);
+    assert_eq!(src.len(), 75);
+    drop((src, dst));
+    assert!(values.iter().all(|value| Arc::strong_count(value) == 1));
+}
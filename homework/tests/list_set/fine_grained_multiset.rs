@@ -0,0 +1,100 @@
+use std::cmp::Ordering;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::thread;
+
+use cs431_homework::{FineGrainedListMultiset, PoisonPolicy};
+
+const THREADS: usize = 4;
+const STEPS: usize = 1000;
+
+#[test]
+fn smoke() {
+    let multiset = FineGrainedListMultiset::new();
+    assert_eq!(multiset.count(&1), 0);
+    assert_eq!(multiset.insert(1), 1);
+    assert_eq!(multiset.insert(1), 2);
+    assert_eq!(multiset.insert(2), 1);
+    assert_eq!(multiset.insert(1), 3);
+    assert_eq!(multiset.count(&1), 3);
+    assert_eq!(multiset.count(&2), 1);
+
+    assert!(multiset.remove(&1));
+    assert!(multiset.remove(&1));
+    assert_eq!(multiset.count(&1), 1);
+    assert!(multiset.remove(&1));
+    assert_eq!(multiset.count(&1), 0);
+    assert!(!multiset.remove(&1));
+    assert_eq!(multiset.count(&2), 1);
+
+    // The key can be inserted again after its node is freed.
+    assert_eq!(multiset.insert(1), 1);
+}
+
+#[test]
+fn concurrent_counts() {
+    let multiset = FineGrainedListMultiset::new();
+    thread::scope(|s| {
+        for _ in 0..THREADS {
+            let _ = s.spawn(|| {
+                for i in 0..STEPS {
+                    let _ = multiset.insert(i % 10);
+                }
+            });
+        }
+    });
+    for key in 0..10 {
+        assert_eq!(multiset.count(&key), THREADS * STEPS / 10);
+    }
+
+    thread::scope(|s| {
+        for _ in 0..THREADS {
+            let _ = s.spawn(|| {
+                for i in 0..STEPS {
+                    assert!(multiset.remove(&(i % 10)));
+                }
+            });
+        }
+    });
+    for key in 0..10 {
+        assert_eq!(multiset.count(&key), 0);
+        assert!(!multiset.remove(&key));
+    }
+}
+
+/// A key whose comparison panics if either side is negative.
+#[derive(Debug, PartialEq, Eq)]
+struct PanickyKey(i32);
+
+impl PartialOrd for PanickyKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PanickyKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        assert!(self.0 >= 0 && other.0 >= 0, "negative key");
+        self.0.cmp(&other.0)
+    }
+}
+
+#[test]
+fn poison_recover() {
+    let multiset = FineGrainedListMultiset::with_poison_policy(PoisonPolicy::Recover);
+    assert_eq!(multiset.insert(PanickyKey(1)), 1);
+    assert_eq!(multiset.insert(PanickyKey(3)), 1);
+    assert!(catch_unwind(AssertUnwindSafe(|| multiset.insert(PanickyKey(-1)))).is_err());
+
+    assert_eq!(multiset.insert(PanickyKey(1)), 2);
+    assert!(multiset.remove(&PanickyKey(3)));
+    assert_eq!(multiset.count(&PanickyKey(3)), 0);
+}
+
+#[test]
+#[should_panic]
+fn poison_propagate() {
+    let multiset = FineGrainedListMultiset::new();
+    assert_eq!(multiset.insert(PanickyKey(1)), 1);
+    assert!(catch_unwind(AssertUnwindSafe(|| multiset.insert(PanickyKey(-1)))).is_err());
+    let _ = multiset.count(&PanickyKey(1));
+}
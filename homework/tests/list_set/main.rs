@@ -1,5 +1,8 @@
 // optimistic_fine_grained on thread santizer has very unstable performance on gg.
 #![feature(cfg_sanitize)]
 
+mod dedup_window;
 mod fine_grained;
+mod fine_grained_multiset;
 mod optimistic_fine_grained;
+mod queue;
@@ -0,0 +1,86 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use cs431_homework::ConcurrentQueue;
+
+const THREADS: usize = 4;
+const STEPS: usize = 4096;
+
+#[test]
+fn smoke() {
+    let queue = ConcurrentQueue::new();
+    assert!(queue.is_empty());
+    assert_eq!(queue.pop_front(), None);
+    for i in 0..10 {
+        queue.push_back(i);
+    }
+    assert!(!queue.is_empty());
+    for i in 0..5 {
+        assert_eq!(queue.pop_front(), Some(i));
+    }
+    queue.push_back(10);
+    for i in 5..11 {
+        assert_eq!(queue.pop_front(), Some(i));
+    }
+    assert_eq!(queue.pop_front(), None);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn producers_consumers() {
+    let queue = ConcurrentQueue::new();
+    let popped = AtomicUsize::new(0);
+    let results = thread::scope(|s| {
+        for producer in 0..THREADS {
+            let queue = &queue;
+            let _ = s.spawn(move || {
+                for seq in 0..STEPS {
+                    queue.push_back((producer, seq));
+                }
+            });
+        }
+        let consumers = (0..THREADS)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut received = Vec::new();
+                    let mut last = [None; THREADS];
+                    while popped.load(Ordering::Relaxed) < THREADS * STEPS {
+                        if let Some((producer, seq)) = queue.pop_front() {
+                            let _ = popped.fetch_add(1, Ordering::Relaxed);
+                            // The elements of each producer are received in order.
+                            assert!(last[producer] < Some(seq));
+                            last[producer] = Some(seq);
+                            received.push((producer, seq));
+                        }
+                    }
+                    received
+                })
+            })
+            .collect::<Vec<_>>();
+        consumers
+            .into_iter()
+            .flat_map(|c| c.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(results.len(), THREADS * STEPS);
+    let unique = results.iter().collect::<HashSet<_>>();
+    assert_eq!(unique.len(), THREADS * STEPS);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn drop_frees_elements() {
+    let element = Arc::new(());
+    let queue = ConcurrentQueue::new();
+    for _ in 0..100 {
+        queue.push_back(element.clone());
+    }
+    for _ in 0..30 {
+        drop(queue.pop_front().unwrap());
+    }
+    assert_eq!(Arc::strong_count(&element), 71);
+    drop(queue);
+    assert_eq!(Arc::strong_count(&element), 1);
+}
@@ -1,9 +1,14 @@
-use crossbeam_channel::bounded;
-use cs431_homework::hello_server::ThreadPool;
+use crossbeam_channel::{bounded, unbounded};
+use cs431_homework::hello_server::{
+    LabelStats, NoSuchWorker, PoolEvent, ThreadPool, ThreadPoolBuilder,
+};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Barrier};
-use std::thread::sleep;
-use std::time::Duration;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
 
 const NUM_THREADS: usize = 4;
 const NUM_JOBS: usize = 1024;
@@ -67,3 +72,939 @@ fn thread_pool_drop_propagate_panic() {
         panic!();
     });
 }
+
+/// Under heavy load, jobs overflow to the global queue and idle workers steal them.
+#[test]
+fn thread_pool_work_stealing() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let counter = Arc::new(AtomicUsize::new(0));
+    run_jobs(&pool, &counter);
+    pool.join();
+    assert_eq!(counter.load(Ordering::Relaxed), NUM_JOBS);
+    assert!(pool.metrics().steals() > 0);
+}
+
+#[test]
+fn thread_pool_set_size() {
+    let pool = ThreadPool::new(2);
+    assert_eq!(pool.size(), 2);
+    pool.set_size(NUM_THREADS);
+    assert_eq!(pool.size(), NUM_THREADS);
+    thread_pool_parallel_on(&pool, NUM_THREADS);
+
+    // Pending jobs of the retired workers are run by the remaining ones.
+    let counter = Arc::new(AtomicUsize::new(0));
+    run_jobs(&pool, &counter);
+    pool.set_size(NUM_THREADS / 2);
+    assert_eq!(pool.size(), NUM_THREADS / 2);
+    pool.join();
+    assert_eq!(counter.load(Ordering::Relaxed), NUM_JOBS);
+}
+
+/// Checks that `threads` jobs run in parallel.
+fn thread_pool_parallel_on(pool: &ThreadPool, threads: usize) {
+    let barrier = Arc::new(Barrier::new(threads));
+    let (done_sender, done_receiver) = bounded(threads);
+    for _ in 0..threads {
+        let barrier = barrier.clone();
+        let done_sender = done_sender.clone();
+        pool.execute(move || {
+            let _ = barrier.wait();
+            done_sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..threads {
+        done_receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+}
+
+#[test]
+fn thread_pool_current_load() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    assert_eq!(pool.current_load(), 0.0);
+
+    let barrier = Arc::new(Barrier::new(NUM_THREADS / 2 + 1));
+    for _ in 0..NUM_THREADS / 2 {
+        let barrier = barrier.clone();
+        pool.execute(move || {
+            let _ = barrier.wait();
+            let _ = barrier.wait();
+        });
+    }
+    let _ = barrier.wait();
+    assert_eq!(pool.active_count(), NUM_THREADS / 2);
+    assert_eq!(pool.current_load(), 0.5);
+    let _ = barrier.wait();
+    pool.join();
+    assert_eq!(pool.current_load(), 0.0);
+}
+
+/// Returns `true` if `cond` holds within 3 seconds.
+fn eventually(cond: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while Instant::now() < deadline {
+        if cond() {
+            return true;
+        }
+        sleep(Duration::from_millis(1));
+    }
+    false
+}
+
+#[test]
+fn thread_pool_autoscale() {
+    let pool = ThreadPool::new(1);
+    pool.autoscale(1, NUM_THREADS, 0.25..=0.75);
+
+    // Grows under sustained pressure.
+    let counter = Arc::new(AtomicUsize::new(0));
+    run_jobs(&pool, &counter);
+    assert!(eventually(|| pool.size() == NUM_THREADS));
+    pool.join();
+    assert_eq!(counter.load(Ordering::Relaxed), NUM_JOBS);
+
+    // Shrinks when idle.
+    assert!(eventually(|| pool.size() == 1));
+}
+
+#[test]
+fn thread_pool_execute_then() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let main = thread::current().id();
+    let results = Arc::new(Mutex::new(Vec::new()));
+    for i in 0..NUM_JOBS {
+        let results = results.clone();
+        pool.execute_then(
+            move || {
+                assert_ne!(thread::current().id(), main);
+                sleep(Duration::from_millis(1));
+                i * 2
+            },
+            move |x| {
+                assert_ne!(thread::current().id(), main);
+                results.lock().unwrap().push(x);
+            },
+        );
+    }
+    pool.join();
+    let mut results = results.lock().unwrap().clone();
+    results.sort_unstable();
+    assert_eq!(results, (0..NUM_JOBS).map(|i| i * 2).collect::<Vec<_>>());
+}
+
+#[test]
+fn thread_pool_execute_then_logged() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let pool = {
+        let events = events.clone();
+        ThreadPoolBuilder::new(NUM_THREADS)
+            .logger(Box::new(move |event| events.lock().unwrap().push(event)))
+            .build()
+    };
+    pool.execute_then(|| 1, |_| {});
+    pool.join();
+    // The second stage is submitted while the first one is still counted.
+    let submitted = events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|event| match event {
+            PoolEvent::JobSubmitted { job_count } => Some(*job_count),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(submitted, [1, 2]);
+}
+
+#[test]
+fn thread_pool_execute_then_panic() {
+    let pool = ThreadPoolBuilder::new(NUM_THREADS)
+        .panic_handler(Box::new(|_| {}))
+        .build();
+    let ran = Arc::new(AtomicUsize::new(0));
+    {
+        let ran = ran.clone();
+        pool.execute_then(
+            || -> usize { panic!("first stage panicked") },
+            move |_| {
+                let _ = ran.fetch_add(1, Ordering::Relaxed);
+            },
+        );
+    }
+    // The second stage is no longer pending.
+    assert_eq!(pool.join_timeout(Duration::from_secs(3)), 0);
+    assert_eq!(ran.load(Ordering::Relaxed), 0);
+    assert_eq!(pool.take_panics(), ["first stage panicked"]);
+}
+
+#[test]
+fn thread_pool_logger() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let pool = {
+        let events = events.clone();
+        ThreadPoolBuilder::new(NUM_THREADS)
+            .logger(Box::new(move |event| events.lock().unwrap().push(event)))
+            .build()
+    };
+    pool.execute(|| {});
+    pool.join();
+    {
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], PoolEvent::JobSubmitted { job_count: 1 });
+        let PoolEvent::JobStarted { worker } = events[1] else {
+            panic!("unexpected event {:?}", events[1]);
+        };
+        assert_eq!(events[2], PoolEvent::JobFinished { worker });
+        assert_eq!(events.len(), 3);
+    }
+
+    drop(pool);
+    let events = events.lock().unwrap();
+    for worker in 0..NUM_THREADS {
+        assert!(events.contains(&PoolEvent::WorkerTerminating { worker }));
+        assert!(events.contains(&PoolEvent::WorkerJoined { worker }));
+    }
+    assert_eq!(events.len(), 3 + 2 * NUM_THREADS);
+}
+
+#[test]
+fn thread_pool_wait_all() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let (release, blocked) = bounded::<()>(0);
+    let slow = (0..2)
+        .map(|_| {
+            let blocked = blocked.clone();
+            pool.execute_tracked(move || blocked.recv().unwrap())
+        })
+        .collect::<Vec<_>>();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let fast = (0..NUM_JOBS)
+        .map(|_| {
+            let counter = counter.clone();
+            pool.execute_tracked(move || {
+                let _ = counter.fetch_add(1, Ordering::Relaxed);
+            })
+        })
+        .collect::<Vec<_>>();
+
+    pool.wait_all(&fast);
+    assert_eq!(counter.load(Ordering::Relaxed), NUM_JOBS);
+    assert!(fast.iter().all(|token| token.is_done()));
+    assert!(slow.iter().all(|token| !token.is_done()));
+
+    for _ in &slow {
+        release.send(()).unwrap();
+    }
+    pool.wait_all(&slow);
+    assert!(slow.iter().all(|token| token.is_done()));
+}
+
+#[test]
+fn thread_pool_tracked_panic() {
+    let pool = ThreadPoolBuilder::new(NUM_THREADS)
+        .panic_handler(Box::new(|_| {}))
+        .build();
+    let (release, blocked) = bounded::<()>(0);
+    let slow = pool.execute_tracked(move || blocked.recv().unwrap());
+    let panicking = pool.execute_tracked(|| panic!("tracked job panicked"));
+    panicking.wait();
+    assert!(panicking.is_done());
+    assert_eq!(pool.join_any(&[slow.clone(), panicking.clone()]), 1);
+    pool.wait_all(&[panicking]);
+    assert!(!slow.is_done());
+    release.send(()).unwrap();
+    slow.wait();
+}
+
+#[test]
+fn thread_pool_broadcast() {
+    thread_local! {
+        static RUNS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    let pool = ThreadPool::new(NUM_THREADS);
+    let main = thread::current().id();
+    for round in 1..=3 {
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        {
+            let runs = runs.clone();
+            pool.broadcast(move || {
+                assert_ne!(thread::current().id(), main);
+                RUNS.with(|r| r.set(r.get() + 1));
+                runs.lock()
+                    .unwrap()
+                    .push((thread::current().id(), RUNS.with(Cell::get)));
+            });
+        }
+        let runs = runs.lock().unwrap();
+        assert_eq!(runs.len(), NUM_THREADS);
+        assert!(runs.iter().all(|&(_, r)| r == round));
+        let ids = runs.iter().map(|&(id, _)| id).collect::<HashSet<_>>();
+        assert_eq!(ids.len(), NUM_THREADS, "a worker ran the job twice");
+    }
+}
+
+#[test]
+fn thread_pool_broadcast_concurrent() {
+    let pool = Arc::new(
+        ThreadPoolBuilder::new(NUM_THREADS)
+            .panic_handler(Box::new(|_| {}))
+            .build(),
+    );
+    let runs = Arc::new(AtomicUsize::new(0));
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let _ = s.spawn(|| {
+                for _ in 0..10 {
+                    let runs = runs.clone();
+                    pool.broadcast(move || {
+                        let _ = runs.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            });
+        }
+    });
+    assert_eq!(runs.load(Ordering::Relaxed), 4 * 10 * NUM_THREADS);
+
+    // Calls from a worker are rejected instead of blocking forever.
+    let (tx, rx) = bounded(1);
+    {
+        let pool_ref = pool.clone();
+        pool.execute(move || {
+            let result = catch_unwind(AssertUnwindSafe(|| pool_ref.broadcast(|| {})));
+            tx.send(result.is_err()).unwrap();
+        });
+    }
+    assert!(rx.recv_timeout(Duration::from_secs(3)).unwrap());
+    pool.join();
+
+    // A paused pool runs the copies once resumed.
+    pool.pause();
+    thread::scope(|s| {
+        let handle = s.spawn(|| pool.broadcast(|| {}));
+        sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+        pool.resume();
+        handle.join().unwrap();
+    });
+}
+
+#[test]
+fn thread_pool_completed_jobs() {
+    let panics = Arc::new(AtomicUsize::new(0));
+    let pool = {
+        let panics = panics.clone();
+        ThreadPoolBuilder::new(NUM_THREADS)
+            .panic_handler(Box::new(move |_| {
+                let _ = panics.fetch_add(1, Ordering::Relaxed);
+            }))
+            .build()
+    };
+    for i in 0..NUM_JOBS {
+        pool.execute(move || {
+            if i % 100 == 0 {
+                panic!("job {i} panicked");
+            }
+        });
+    }
+    pool.join();
+    assert_eq!(pool.completed_jobs(), NUM_JOBS as u64);
+    assert_eq!(panics.load(Ordering::Relaxed), NUM_JOBS.div_ceil(100));
+
+    pool.reset_counters();
+    assert_eq!(pool.completed_jobs(), 0);
+    assert_eq!(pool.metrics().steals(), 0);
+    for _ in 0..10 {
+        pool.execute(|| {});
+    }
+    pool.join();
+    assert_eq!(pool.completed_jobs(), 10);
+}
+
+#[test]
+fn thread_pool_execute_recurring() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let runs = Arc::new(AtomicUsize::new(0));
+    {
+        let runs = runs.clone();
+        let mut remaining = 5;
+        pool.execute_recurring(move || {
+            let _ = runs.fetch_add(1, Ordering::Relaxed);
+            remaining -= 1;
+            remaining > 0
+        });
+    }
+    pool.join();
+    assert_eq!(runs.load(Ordering::Relaxed), 5);
+    sleep(Duration::from_millis(50));
+    assert_eq!(runs.load(Ordering::Relaxed), 5);
+    assert_eq!(pool.completed_jobs(), 5);
+}
+
+#[test]
+fn thread_pool_take_panics() {
+    let pool = ThreadPoolBuilder::new(NUM_THREADS)
+        .panic_handler(Box::new(|_| {}))
+        .build();
+    pool.execute(|| panic!("static message"));
+    pool.join();
+    pool.execute(|| panic!("job {} panicked", 1));
+    pool.execute(|| std::panic::panic_any(42));
+    pool.execute(|| {});
+    pool.join();
+    let mut panics = pool.take_panics();
+    assert_eq!(panics.remove(0), "static message");
+    panics.sort();
+    assert_eq!(panics, ["Box<dyn Any>", "job 1 panicked"]);
+    assert!(pool.take_panics().is_empty());
+}
+
+#[test]
+fn thread_pool_execute_on() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    for _ in 0..NUM_JOBS {
+        let ids = ids.clone();
+        pool.execute_on(0, move || {
+            ids.lock().unwrap().push(thread::current().id());
+        })
+        .unwrap();
+    }
+    pool.join();
+    let ids = ids.lock().unwrap();
+    assert_eq!(ids.len(), NUM_JOBS);
+    assert!(ids.iter().all(|id| *id == ids[0]));
+    assert_eq!(pool.execute_on(NUM_THREADS, || {}), Err(NoSuchWorker));
+}
+
+#[test]
+fn thread_pool_execute_to() {
+    const JOBS: usize = 50;
+
+    let pool = ThreadPool::new(NUM_THREADS);
+    let (tx, rx) = unbounded();
+    for i in 0..JOBS {
+        pool.execute_to(tx.clone(), move || {
+            sleep(Duration::from_millis((JOBS - i) as u64 % 7));
+            i * i
+        });
+    }
+    drop(tx);
+    let mut results = rx.iter().collect::<Vec<_>>();
+    results.sort_unstable();
+    assert_eq!(results, (0..JOBS).map(|i| i * i).collect::<Vec<_>>());
+}
+
+#[test]
+fn thread_pool_execute_ordered() {
+    const JOBS: usize = 20;
+
+    let pool = ThreadPool::new(NUM_THREADS);
+    let finished = Arc::new(Mutex::new(Vec::new()));
+    let finished_clone = finished.clone();
+    let results = pool.execute_ordered(0..JOBS, move |i| {
+        // Later inputs finish first.
+        sleep(Duration::from_millis(5 * (JOBS - i) as u64));
+        finished_clone.lock().unwrap().push(i);
+        i * i
+    });
+    assert!(results.eq((0..JOBS).map(|i| i * i)));
+    let finished = finished.lock().unwrap();
+    assert_ne!(*finished, (0..JOBS).collect::<Vec<_>>());
+}
+
+#[test]
+fn thread_pool_join_any() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let slow = pool.execute_tracked(|| sleep(Duration::from_millis(300)));
+    let fast = pool.execute_tracked(|| sleep(Duration::from_millis(10)));
+    let start = Instant::now();
+    assert_eq!(pool.join_any(&[slow.clone(), fast.clone()]), 1);
+    assert!(start.elapsed() < Duration::from_millis(300));
+    assert!(!slow.is_done());
+
+    // A job that is already done is returned without waiting.
+    assert_eq!(pool.join_any(&[slow.clone(), fast]), 1);
+    pool.join();
+    assert_eq!(pool.join_any(&[slow]), 0);
+}
+
+#[test]
+fn thread_pool_try_scope() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let values = pool.try_scope(|scope| {
+        for i in 0..10 {
+            scope.spawn(move |_| Ok::<_, String>(i * i));
+        }
+    });
+    assert_eq!(values, Ok((0..10).map(|i| i * i).collect()));
+
+    // The siblings run until they observe the cancellation.
+    let observed = Arc::new(AtomicUsize::new(0));
+    let result = pool.try_scope(|scope| {
+        for _ in 0..NUM_THREADS - 1 {
+            let observed = observed.clone();
+            scope.spawn(move |token| {
+                let start = Instant::now();
+                while !token.is_cancelled() {
+                    assert!(start.elapsed() < Duration::from_secs(5), "not cancelled");
+                    sleep(Duration::from_millis(1));
+                }
+                let _ = observed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            });
+        }
+        scope.spawn(|_| {
+            sleep(Duration::from_millis(20));
+            Err("failed")
+        });
+    });
+    assert_eq!(result, Err("failed"));
+    assert_eq!(observed.load(Ordering::Relaxed), NUM_THREADS - 1);
+}
+
+#[test]
+fn thread_pool_pause_resume() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let counter = Arc::new(AtomicUsize::new(0));
+    pool.pause();
+    for _ in 0..NUM_JOBS {
+        let counter = counter.clone();
+        pool.execute(move || {
+            let _ = counter.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    sleep(Duration::from_millis(100));
+    assert_eq!(counter.load(Ordering::Relaxed), 0);
+
+    pool.resume();
+    pool.join();
+    assert_eq!(counter.load(Ordering::Relaxed), NUM_JOBS);
+
+    // Dropping a paused pool runs the pending jobs.
+    pool.pause();
+    let counter_clone = counter.clone();
+    pool.execute(move || {
+        let _ = counter_clone.fetch_add(1, Ordering::Relaxed);
+    });
+    drop(pool);
+    assert_eq!(counter.load(Ordering::Relaxed), NUM_JOBS + 1);
+}
+
+#[test]
+fn thread_pool_blocking() {
+    const BLOCKING_THREADS: usize = 4;
+
+    let pool = ThreadPoolBuilder::new(2)
+        .blocking_threads(BLOCKING_THREADS)
+        .build();
+    let (release_sender, release_receiver) = unbounded::<()>();
+    let released = Arc::new(AtomicUsize::new(0));
+    for _ in 0..20 {
+        let release_receiver = release_receiver.clone();
+        let released = released.clone();
+        pool.blocking(move || {
+            let _ = release_receiver.recv();
+            let _ = released.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    sleep(Duration::from_millis(100));
+    assert_eq!(pool.blocking_pool().size(), BLOCKING_THREADS);
+    assert_eq!(pool.blocking_pool().active_count(), BLOCKING_THREADS);
+
+    // CPU-bound jobs are not stuck behind the blocking ones.
+    let tokens = (0..NUM_THREADS)
+        .map(|_| pool.execute_tracked(|| {}))
+        .collect::<Vec<_>>();
+    pool.wait_all(&tokens);
+    assert_eq!(pool.completed_jobs(), NUM_THREADS as u64);
+    assert_eq!(released.load(Ordering::Relaxed), 0);
+
+    drop(release_sender);
+    pool.join();
+    assert_eq!(released.load(Ordering::Relaxed), 20);
+    assert_eq!(pool.blocking_pool().completed_jobs(), 20);
+}
+
+#[test]
+fn thread_pool_blocking_spawn_and_exit() {
+    const KEEP_ALIVE: Duration = Duration::from_millis(50);
+
+    let pool = ThreadPoolBuilder::new(2)
+        .blocking_threads(4)
+        .blocking_keep_alive(KEEP_ALIVE)
+        .build();
+    assert_eq!(pool.blocking_pool().size(), 0);
+
+    // A thread is spawned at once for each job that finds no idle thread.
+    let (release_sender, release_receiver) = unbounded::<()>();
+    for _ in 0..3 {
+        let release_receiver = release_receiver.clone();
+        pool.blocking(move || {
+            let _ = release_receiver.recv();
+        });
+    }
+    assert_eq!(pool.blocking_pool().size(), 3);
+    drop(release_sender);
+    pool.join();
+
+    // Idle threads are reused.
+    pool.blocking(|| {});
+    pool.join();
+    assert_eq!(pool.blocking_pool().size(), 3);
+
+    // Idle threads exit after the keep-alive.
+    let deadline = Instant::now() + 20 * KEEP_ALIVE;
+    while pool.blocking_pool().size() > 0 {
+        assert!(Instant::now() < deadline);
+        sleep(KEEP_ALIVE);
+    }
+    pool.blocking(|| {});
+    assert_eq!(pool.blocking_pool().size(), 1);
+    pool.join();
+    assert_eq!(pool.blocking_pool().completed_jobs(), 5);
+}
+
+#[test]
+fn thread_pool_label_metrics() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let (release_sender, release_receiver) = unbounded::<()>();
+    for _ in 0..2 {
+        let release_receiver = release_receiver.clone();
+        pool.execute_labeled("upload", move || {
+            let _ = release_receiver.recv();
+        });
+    }
+    for _ in 0..10 {
+        pool.execute_labeled("render", || {});
+    }
+    pool.execute(|| {});
+    sleep(Duration::from_millis(100));
+    let metrics = pool.label_metrics();
+    assert_eq!(metrics.len(), 2);
+    assert_eq!(
+        metrics["upload"],
+        LabelStats {
+            active: 2,
+            completed: 0
+        }
+    );
+    assert_eq!(
+        metrics["render"],
+        LabelStats {
+            active: 0,
+            completed: 10
+        }
+    );
+
+    drop(release_sender);
+    pool.join();
+    assert_eq!(
+        pool.label_metrics()["upload"],
+        LabelStats {
+            active: 0,
+            completed: 2
+        }
+    );
+}
+
+#[test]
+fn thread_pool_execute_queued() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    pool.pause();
+    let depths = (0..100)
+        .map(|_| pool.execute_queued(|| {}))
+        .collect::<Vec<_>>();
+    assert_eq!(depths, (1..=100).collect::<Vec<_>>());
+    pool.resume();
+    pool.join();
+    assert_eq!(pool.execute_queued(|| {}), 1);
+}
+
+#[test]
+fn thread_pool_on_idle() {
+    let idle = Arc::new(AtomicUsize::new(0));
+    let idle_clone = idle.clone();
+    let pool = ThreadPoolBuilder::new(NUM_THREADS)
+        .on_idle(Box::new(move || {
+            let _ = idle_clone.fetch_add(1, Ordering::Relaxed);
+        }))
+        .build();
+    let barrier = Arc::new(Barrier::new(NUM_THREADS + 1));
+    for _ in 0..NUM_THREADS {
+        let barrier = barrier.clone();
+        pool.execute(move || {
+            let _ = barrier.wait();
+        });
+    }
+    for _ in 0..NUM_JOBS {
+        pool.execute(|| {});
+    }
+    let _ = barrier.wait();
+    pool.join();
+    // The hook runs right after `join` is woken up.
+    sleep(Duration::from_millis(50));
+    assert_eq!(idle.load(Ordering::Relaxed), 1);
+
+    // Each burst makes one transition.
+    pool.execute(|| sleep(Duration::from_millis(10)));
+    pool.join();
+    sleep(Duration::from_millis(50));
+    assert_eq!(idle.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn thread_pool_join_timeout() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let (finish_sender, finish_receiver) = bounded::<()>(0);
+    pool.execute(move || finish_receiver.recv().unwrap());
+    pool.execute(|| {});
+    let now = Instant::now();
+    assert_eq!(pool.join_timeout(Duration::from_millis(100)), 1);
+    assert!(now.elapsed() >= Duration::from_millis(100));
+
+    finish_sender.send(()).unwrap();
+    assert_eq!(pool.join_timeout(Duration::from_secs(10)), 0);
+}
+
+#[test]
+fn thread_pool_map_reduce() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let expected = (0..1000u64).map(|i| i * i).sum::<u64>();
+    assert_eq!(
+        pool.map_reduce(0..1000u64, |i| i * i, 0, |a, b| a + b),
+        expected
+    );
+    assert_eq!(pool.map_reduce(0..0u64, |i| i * i, 0, |a, b| a + b), 0);
+
+    // The partial results are combined in order, so an associative but not commutative `reduce`
+    // gives the sequential result.
+    let concatenated = pool.map_reduce(0..100, |i| i.to_string(), String::new(), |a, b| a + &b);
+    assert_eq!(
+        concatenated,
+        (0..100).map(|i| i.to_string()).collect::<String>()
+    );
+}
+
+#[test]
+fn thread_pool_active_worker_ids() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    assert!(pool.active_worker_ids().is_empty());
+    let (finish_sender, finish_receiver) = unbounded::<()>();
+    for worker in [1, 3] {
+        let finish_receiver = finish_receiver.clone();
+        pool.execute_on(worker, move || finish_receiver.recv().unwrap())
+            .unwrap();
+    }
+    while pool.active_count() < 2 {
+        thread::yield_now();
+    }
+    assert_eq!(pool.active_worker_ids(), [1, 3]);
+
+    finish_sender.send(()).unwrap();
+    finish_sender.send(()).unwrap();
+    pool.join();
+    assert!(pool.active_worker_ids().is_empty());
+}
+
+#[test]
+fn thread_pool_worker_ids_across_resize() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let pool = {
+        let events = events.clone();
+        ThreadPoolBuilder::new(4)
+            .logger(Box::new(move |event| events.lock().unwrap().push(event)))
+            .build()
+    };
+    assert_eq!(pool.worker_ids(), [0, 1, 2, 3]);
+    pool.set_size(2);
+    let terminated = |worker| {
+        events
+            .lock()
+            .unwrap()
+            .contains(&PoolEvent::WorkerTerminating { worker })
+    };
+    while !terminated(2) || !terminated(3) {
+        thread::yield_now();
+    }
+    // Give the threads time to exit after logging.
+    sleep(Duration::from_millis(100));
+    pool.set_size(4);
+    // The ids of the retired workers are not reused, and the exited ones are joined.
+    assert_eq!(pool.worker_ids(), [0, 1, 4, 5]);
+    for worker in [2, 3] {
+        assert!(events
+            .lock()
+            .unwrap()
+            .contains(&PoolEvent::WorkerJoined { worker }));
+    }
+    assert_eq!(pool.execute_on(3, || {}), Err(NoSuchWorker));
+
+    let (finish_sender, finish_receiver) = unbounded::<()>();
+    pool.execute_on(4, move || finish_receiver.recv().unwrap())
+        .unwrap();
+    while pool.active_count() < 1 {
+        thread::yield_now();
+    }
+    assert_eq!(pool.active_worker_ids(), [4]);
+    finish_sender.send(()).unwrap();
+    pool.join();
+    assert!(events
+        .lock()
+        .unwrap()
+        .contains(&PoolEvent::JobStarted { worker: 4 }));
+}
+
+#[test]
+fn thread_pool_logger_uses_pool() {
+    let pool = Arc::new(ThreadPool::new(NUM_THREADS));
+    let unfinished = Arc::new(Mutex::new(Vec::new()));
+    pool.set_logger({
+        let pool = Arc::downgrade(&pool);
+        let unfinished = unfinished.clone();
+        Box::new(move |event| {
+            if let (PoolEvent::JobSubmitted { .. }, Some(pool)) = (event, pool.upgrade()) {
+                // These take the locks of the job count and the workers.
+                let _ = pool.active_worker_ids();
+                unfinished
+                    .lock()
+                    .unwrap()
+                    .push(pool.join_timeout(Duration::ZERO));
+            }
+        })
+    });
+    pool.execute(|| {});
+    assert!(pool.execute_on(0, || {}).is_ok());
+    pool.broadcast(|| {});
+    pool.join();
+    assert_eq!(unfinished.lock().unwrap().len(), 2 + NUM_THREADS);
+}
+
+#[test]
+fn thread_pool_set_callbacks() {
+    let old_events = Arc::new(Mutex::new(Vec::new()));
+    let pool = {
+        let old_events = old_events.clone();
+        ThreadPoolBuilder::new(NUM_THREADS)
+            .logger(Box::new(move |event| {
+                old_events.lock().unwrap().push(event)
+            }))
+            .build()
+    };
+    pool.execute(|| {});
+    pool.join();
+    assert_eq!(old_events.lock().unwrap().len(), 3);
+
+    let new_events = Arc::new(Mutex::new(Vec::new()));
+    pool.set_logger({
+        let new_events = new_events.clone();
+        Box::new(move |event| new_events.lock().unwrap().push(event))
+    });
+    pool.execute(|| {});
+    pool.join();
+    assert_eq!(old_events.lock().unwrap().len(), 3);
+    assert_eq!(
+        new_events.lock().unwrap()[0],
+        PoolEvent::JobSubmitted { job_count: 1 }
+    );
+    assert_eq!(new_events.lock().unwrap().len(), 3);
+
+    // A panic handler can be set on a pool that had none.
+    let panics = Arc::new(AtomicUsize::new(0));
+    pool.set_panic_handler({
+        let panics = panics.clone();
+        Box::new(move |_| {
+            let _ = panics.fetch_add(1, Ordering::SeqCst);
+        })
+    });
+    pool.execute(|| panic!("job panicked"));
+    pool.join();
+    assert_eq!(panics.load(Ordering::SeqCst), 1);
+    assert_eq!(pool.take_panics(), ["job panicked"]);
+}
+
+#[test]
+fn thread_pool_notify_when_idle() {
+    let pool = ThreadPool::new(NUM_THREADS);
+    let (tx, rx) = unbounded();
+    pool.notify_when_idle(tx.clone());
+    pool.notify_when_idle(tx);
+
+    let (finish_sender, finish_receiver) = bounded::<()>(0);
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..NUM_THREADS {
+        let (counter, finish_receiver) = (counter.clone(), finish_receiver.clone());
+        pool.execute(move || {
+            finish_receiver.recv().unwrap();
+            let _ = counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    sleep(Duration::from_millis(100));
+    assert!(rx.try_recv().is_err());
+
+    for _ in 0..NUM_THREADS {
+        finish_sender.send(()).unwrap();
+    }
+    rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(counter.load(Ordering::SeqCst), NUM_THREADS);
+
+    // The senders are dropped after the notification.
+    assert!(rx.recv().is_err());
+}
+
+#[cfg(all(target_os = "linux", feature = "affinity"))]
+#[test]
+fn thread_pool_pin_to_cores() {
+    let cores = core_affinity::get_core_ids().unwrap();
+    let targets = vec![cores[0].id, cores[cores.len() - 1].id];
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let pool = {
+        let events = events.clone();
+        ThreadPoolBuilder::new(NUM_THREADS)
+            .pin_to_cores(targets.clone())
+            .logger(Box::new(move |event| events.lock().unwrap().push(event)))
+            .build()
+    };
+    let (tx, rx) = unbounded();
+    for worker in 0..NUM_THREADS {
+        let tx = tx.clone();
+        pool.execute_on(worker, move || {
+            // The affinity mask of a pinned thread only has its core.
+            tx.send((worker, core_affinity::get_core_ids().unwrap()))
+                .unwrap();
+        })
+        .unwrap();
+    }
+    drop(tx);
+    for (worker, cores) in rx {
+        assert_eq!(
+            cores,
+            [core_affinity::CoreId {
+                id: targets[worker % 2]
+            }]
+        );
+    }
+    assert!(!events
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|event| matches!(event, PoolEvent::PinningFailed { .. })));
+
+    // An unavailable core is reported, and the worker still runs jobs.
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let pool = {
+        let events = events.clone();
+        ThreadPoolBuilder::new(1)
+            .pin_to_cores(vec![usize::MAX])
+            .logger(Box::new(move |event| events.lock().unwrap().push(event)))
+            .build()
+    };
+    let (tx, rx) = bounded(1);
+    pool.execute(move || tx.send(()).unwrap());
+    rx.recv().unwrap();
+    assert!(events.lock().unwrap().contains(&PoolEvent::PinningFailed {
+        worker: 0,
+        core: usize::MAX,
+    }));
+}
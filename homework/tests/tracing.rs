@@ -0,0 +1,128 @@
+#![cfg(feature = "tracing")]
+
+use cs431_homework::hello_server::Cache;
+use cs431_homework::{ConcurrentSet, FineGrainedListSet, PoisonPolicy};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+/// The `op` and `outcome` fields of an event.
+type Recorded = (String, String);
+
+/// Records the events of the homework crate.
+#[derive(Debug, Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<Recorded>>>);
+
+#[derive(Debug, Default)]
+struct FieldVisitor {
+    op: String,
+    outcome: String,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{value}"))
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{value:?}");
+        match field.name() {
+            "op" => self.op = value,
+            "outcome" => self.outcome = value,
+            _ => {}
+        }
+    }
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target().starts_with("cs431_homework")
+    }
+
+    fn new_span(&self, _: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        if !visitor.op.is_empty() {
+            self.0.lock().unwrap().push((visitor.op, visitor.outcome));
+        }
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+fn recorded(ops: &[(&str, &str)]) -> Vec<Recorded> {
+    ops.iter()
+        .map(|&(op, outcome)| (op.to_string(), outcome.to_string()))
+        .collect()
+}
+
+#[test]
+fn tracing_list_set() {
+    let recorder = Recorder::default();
+    tracing::subscriber::with_default(recorder.clone(), || {
+        let set = FineGrainedListSet::new();
+        assert!(set.insert(1));
+        assert!(!set.insert(1));
+        assert!(set.contains(&1));
+        assert!(set.remove(&1));
+        assert!(!set.contains(&1));
+    });
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        recorded(&[
+            ("insert", "true"),
+            ("insert", "false"),
+            ("contains", "true"),
+            ("remove", "true"),
+            ("contains", "false"),
+        ])
+    );
+}
+
+#[test]
+fn tracing_cache() {
+    let recorder = Recorder::default();
+    tracing::subscriber::with_default(recorder.clone(), || {
+        let cache = Cache::default();
+        assert_eq!(cache.get_or_insert_with(1, |k| k), 1);
+        assert_eq!(cache.get_or_insert_with(1, |_| panic!()), 1);
+    });
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        recorded(&[
+            ("get_or_insert_with", "miss"),
+            ("get_or_insert_with", "hit"),
+        ])
+    );
+}
+
+#[test]
+fn tracing_keys_without_debug() {
+    /// A key that does not implement `Debug`.
+    #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+    struct Opaque(u8);
+
+    let recorder = Recorder::default();
+    tracing::subscriber::with_default(recorder.clone(), || {
+        let set = FineGrainedListSet::new();
+        assert!(set.insert(Opaque(1)));
+        let cache = Cache::with_poison_policy(PoisonPolicy::default());
+        assert_eq!(cache.get_or_insert_with(Opaque(1), |_| 1), 1);
+    });
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        recorded(&[("insert", "true"), ("get_or_insert_with", "miss")])
+    );
+}